@@ -0,0 +1,139 @@
+#![deny(warnings)]
+
+extern crate redoxfs;
+
+use std::{env, process};
+use std::fs::File;
+use std::io::Read;
+
+use redoxfs::{Disk, DiskFile, FileSystem, mounted, open_crypt, open_disk, open_mirror, read_passphrase};
+
+fn usage() {
+    println!("redoxfs-label [--set LABEL] [--partition N] [--key-file FILE] [disk...]");
+    println!("    shows the label of an unmounted filesystem");
+    println!("    disk...             every disk of a mirror, created by redoxfs-mkfs with several disks");
+    println!("    --set LABEL         change the label to LABEL, at most 64 bytes");
+    println!("    --partition N       use partition N of the disk instead of the first RedoxFS partition");
+    println!("    --key-file FILE     unlock an encrypted filesystem with the contents of FILE, instead of asking");
+}
+
+fn main() {
+    let mut label = None;
+    let mut partition = None;
+    let mut key = None;
+    let mut paths = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--set" {
+            match args.next() {
+                Some(value) => label = Some(value),
+                None => {
+                    println!("redoxfs-label: no label provided");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else if arg == "--partition" {
+            match args.next().and_then(|number| number.parse::<usize>().ok()) {
+                Some(number) => partition = Some(number),
+                None => {
+                    println!("redoxfs-label: invalid partition number");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else if arg == "--key-file" {
+            match args.next() {
+                Some(path) => {
+                    let mut data = Vec::new();
+                    if let Err(err) = File::open(&path).and_then(|mut file| file.read_to_end(&mut data)) {
+                        println!("redoxfs-label: failed to read key file {}: {}", path, err);
+                        process::exit(1);
+                    }
+                    key = Some(data);
+                },
+                None => {
+                    println!("redoxfs-label: no key file provided");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else {
+            paths.push(arg);
+        }
+    }
+
+    if paths.is_empty() {
+        println!("redoxfs-label: no disk image provided");
+        usage();
+        process::exit(1);
+    }
+    let path = paths.join(", ");
+
+    if label.is_some() {
+        for path in paths.iter() {
+            if mounted(path) {
+                println!("redoxfs-label: {} is mounted, refusing to change it", path);
+                process::exit(1);
+            }
+        }
+    }
+
+    // Every disk of a mirror is opened, so the new label is written to all of them
+    let images: Result<Vec<Box<dyn Disk + Send + Sync>>, _> = paths.iter().map(|path| {
+        let image = if label.is_some() {
+            DiskFile::open(path)
+        } else {
+            DiskFile::open_read_only(path)
+        };
+
+        image.and_then(|image| open_disk(image, partition))
+    }).collect();
+
+    let image = images.and_then(open_mirror).and_then(|image| open_crypt(image, || match key {
+        Some(ref key) => key.clone(),
+        None => match read_passphrase(&format!("redoxfs-label: passphrase for {}: ", path)) {
+            Ok(passphrase) => passphrase,
+            Err(err) => {
+                println!("redoxfs-label: failed to read passphrase: {}", err);
+                process::exit(1);
+            }
+        }
+    }));
+
+    let filesystem = match image {
+        Ok(image) => if label.is_some() {
+            FileSystem::open(image)
+        } else {
+            FileSystem::open_read_only(image)
+        },
+        Err(err) => {
+            println!("redoxfs-label: failed to open image {}: {}", path, err);
+            process::exit(1);
+        }
+    };
+
+    match filesystem {
+        Ok(mut filesystem) => match label {
+            Some(label) => match filesystem.set_label(&label).and_then(|_| filesystem.sync()) {
+                Ok(()) => println!("redoxfs-label: changed the label of {} to {}", path, label),
+                Err(err) => {
+                    println!("redoxfs-label: failed to change the label of {}: {}", path, err);
+                    process::exit(1);
+                }
+            },
+            None => match filesystem.header().1.label() {
+                Ok(label) => println!("{}", label),
+                Err(err) => {
+                    println!("redoxfs-label: label of {} is not valid UTF-8: {}", path, err);
+                    process::exit(1);
+                }
+            }
+        },
+        Err(err) => {
+            println!("redoxfs-label: failed to open filesystem {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+}
@@ -3,33 +3,202 @@
 extern crate redoxfs;
 
 use std::{env, process, time};
+use std::fs::File;
+use std::io::Read;
 
-use redoxfs::{FileSystem, DiskFile};
+use redoxfs::{Disk, DiskChecksum, DiskCrypt, DiskFile, DiskMirror, DiskQcow2, FileSystem, Header, format_uuid, mounted, parse_uuid, read_passphrase};
+
+/// Read the passphrase from a key file, or ask for it twice
+fn passphrase(key_file: &Option<String>) -> Vec<u8> {
+    if let Some(ref key_file) = *key_file {
+        let mut key = Vec::new();
+        if let Err(err) = File::open(key_file).and_then(|mut file| file.read_to_end(&mut key)) {
+            println!("redoxfs-mkfs: failed to read key file {}: {}", key_file, err);
+            process::exit(1);
+        }
+        return key;
+    }
+
+    match (read_passphrase("redoxfs-mkfs: passphrase: "), read_passphrase("redoxfs-mkfs: repeat passphrase: ")) {
+        (Ok(first), Ok(second)) => if first == second {
+            first
+        } else {
+            println!("redoxfs-mkfs: passphrases do not match");
+            process::exit(1);
+        },
+        (Err(err), _) | (_, Err(err)) => {
+            println!("redoxfs-mkfs: failed to read passphrase: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+fn usage() {
+    println!("redoxfs-mkfs [--block-size SIZE] [--uuid UUID] [--label LABEL] [--checksum] [--copy-on-write] [--encrypt] [--key-file FILE] [disk...]");
+    println!("    disk...             several disks are mirrored, each keeping a copy of the filesystem");
+    println!("    --block-size SIZE   use blocks of SIZE bytes, a power of two from 512 to 65536 (default 512),");
+    println!("                        versions before block sizes were supported cannot read other sizes");
+    println!("    --uuid UUID         identify the filesystem with UUID instead of a random one");
+    println!("    --label LABEL       name the filesystem LABEL, at most 64 bytes");
+    println!("    --checksum          store a checksum for every block, to detect corruption");
+    println!("    --copy-on-write     write changed metadata to new blocks instead of using a journal");
+    println!("    --encrypt           encrypt the filesystem, asking for a passphrase");
+    println!("    --key-file FILE     encrypt the filesystem, using the contents of FILE as the passphrase");
+}
 
 fn main() {
-    let mut args = env::args();
-    if let Some(path) = args.nth(1) {
-        let ctime = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap();
-
-        //Open an existing image
-        match DiskFile::open(&path) {
-            Ok(disk) => match FileSystem::create(disk, ctime.as_secs(), ctime.subsec_nanos()) {
-                Ok(filesystem) => {
-                    println!("redoxfs-mkfs: created filesystem on {}, size {} MB", path, filesystem.header.1.size/1024/1024);
+    let mut block_size = 512;
+    let mut uuid = None;
+    let mut label = None;
+    let mut checksum = false;
+    let mut copy_on_write = false;
+    let mut encrypt = false;
+    let mut key_file = None;
+    let mut paths = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--block-size" {
+            match args.next().and_then(|size| size.parse::<u64>().ok()) {
+                Some(size) if Header::valid_block_size(size) => block_size = size,
+                _ => {
+                    println!("redoxfs-mkfs: block size must be a power of two from 512 to 65536");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else if arg == "--uuid" {
+            match args.next().and_then(|uuid| parse_uuid(&uuid)) {
+                Some(value) => uuid = Some(value),
+                None => {
+                    println!("redoxfs-mkfs: invalid UUID, expected 8-4-4-4-12 hex digits");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else if arg == "--label" {
+            match args.next() {
+                Some(value) => if value.len() <= 64 {
+                    label = Some(value);
+                } else {
+                    println!("redoxfs-mkfs: label must be at most 64 bytes");
+                    process::exit(1);
+                },
+                None => {
+                    println!("redoxfs-mkfs: no label provided");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else if arg == "--checksum" {
+            checksum = true;
+        } else if arg == "--copy-on-write" {
+            copy_on_write = true;
+        } else if arg == "--encrypt" {
+            encrypt = true;
+        } else if arg == "--key-file" {
+            match args.next() {
+                Some(path) => {
+                    encrypt = true;
+                    key_file = Some(path);
                 },
-                Err(err) => {
-                    println!("redoxfs-mkfs: failed to create filesystem on {}: {}", path, err);
+                None => {
+                    println!("redoxfs-mkfs: no key file provided");
+                    usage();
                     process::exit(1);
                 }
-            },
+            }
+        } else {
+            paths.push(arg);
+        }
+    }
+
+    if paths.is_empty() {
+        println!("redoxfs-mkfs: no disk image provided");
+        usage();
+        process::exit(1);
+    }
+
+    let ctime = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap();
+    let path = paths.join(", ");
+
+    //Open existing images
+    let mut disks = Vec::new();
+    for path in paths.iter() {
+        if mounted(path) {
+            println!("redoxfs-mkfs: {} is mounted, refusing to format it", path);
+            process::exit(1);
+        }
+
+        let disk = match DiskFile::open(path) {
+            Ok(disk) => disk,
             Err(err) => {
                 println!("redoxfs-mkfs: failed to open image {}: {}", path, err);
                 process::exit(1);
             }
+        };
+
+        // Larger blocks are only used when asked for, older versions misread them
+        let sector_size = disk.sector_size();
+        if block_size < sector_size {
+            println!("redoxfs-mkfs: {} has {} byte sectors, writes will be slower, see --block-size", path, sector_size);
+        }
+
+        let disk = match DiskQcow2::probe(&disk) {
+            Ok(true) => DiskQcow2::open(disk).map(|disk| Box::new(disk) as Box<dyn Disk>),
+            Ok(false) => Ok(Box::new(disk) as Box<dyn Disk>),
+            Err(err) => Err(err)
+        };
+
+        // Every mirrored disk has its own checksums, so a bad block can be repaired from another
+        let disk = if checksum {
+            disk.and_then(DiskChecksum::create).map(|disk| Box::new(disk) as Box<dyn Disk>)
+        } else {
+            disk
+        };
+
+        match disk {
+            Ok(disk) => disks.push(disk),
+            Err(err) => {
+                println!("redoxfs-mkfs: failed to create filesystem on {}: {}", path, err);
+                process::exit(1);
+            }
         }
+    }
+
+    let disk = if disks.len() == 1 {
+        Ok(disks.remove(0))
     } else {
-        println!("redoxfs-mkfs: no disk image provided");
-        println!("redoxfs-mkfs [disk]");
-        process::exit(1);
+        DiskMirror::create(disks).map(|disk| Box::new(disk) as Box<dyn Disk>)
+    };
+
+    let disk = if encrypt {
+        let passphrase = passphrase(&key_file);
+        disk.and_then(|disk| DiskCrypt::create(disk, &passphrase)).map(|disk| Box::new(disk) as Box<dyn Disk>)
+    } else {
+        disk
+    };
+
+    let filesystem = disk.and_then(|disk| FileSystem::create_with_block_size(disk, block_size, ctime.as_secs(), ctime.subsec_nanos()));
+    match filesystem.and_then(|mut filesystem| match uuid {
+        Some(uuid) => filesystem.set_uuid(uuid).map(|_| filesystem),
+        None => Ok(filesystem)
+    }).and_then(|mut filesystem| match label {
+        Some(ref label) => filesystem.set_label(label).map(|_| filesystem),
+        None => Ok(filesystem)
+    }).and_then(|mut filesystem| if copy_on_write {
+        filesystem.set_copy_on_write().map(|_| filesystem)
+    } else {
+        Ok(filesystem)
+    }) {
+        Ok(filesystem) => {
+            println!("redoxfs-mkfs: created {}filesystem on {}, size {} MB, {} byte blocks, UUID {}",
+                     if encrypt { "encrypted " } else { "" }, path, filesystem.header().1.size/1024/1024,
+                     filesystem.block_size(), format_uuid(&filesystem.header().1.uuid));
+        },
+        Err(err) => {
+            println!("redoxfs-mkfs: failed to create filesystem on {}: {}", path, err);
+            process::exit(1);
+        }
     }
 }
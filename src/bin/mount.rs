@@ -12,9 +12,11 @@ extern crate redoxfs;
 use std::env;
 use std::fs::File;
 use std::os::unix::io::FromRawFd;
+use std::path::Path;
 use std::process;
+use std::sync::Arc;
 
-use redoxfs::{DiskCache, DiskFile, mount};
+use redoxfs::{Disk, DiskCache, DiskFile, DiskOverlay, DiskTrace, Header, TRACE_DATA, TRACE_HASH, mount, open_crypt, open_disk, open_mirror, parse_uuid, read_passphrase};
 
 #[cfg(unix)]
 fn fork() -> isize {
@@ -36,13 +38,190 @@ fn pipe(pipes: &mut [usize; 2]) -> isize {
     syscall::Error::mux(syscall::pipe2(pipes, 0)) as isize
 }
 
+/// Devices that may hold a filesystem, searched when mounting by UUID
+#[cfg(target_os = "linux")]
+fn devices() -> Vec<String> {
+    use std::io::Read;
+
+    let mut partitions = String::new();
+    if let Ok(mut file) = File::open("/proc/partitions") {
+        let _ = file.read_to_string(&mut partitions);
+    }
+
+    // Skip the heading, the name is the fourth column
+    partitions.lines().skip(2).filter_map(|line| line.split_whitespace().nth(3)).map(|name| format!("/dev/{}", name)).collect()
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn devices() -> Vec<String> {
+    use std::fs;
+
+    fs::read_dir("/dev").map(|entries| entries.filter_map(|entry| entry.ok()).filter_map(|entry| {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if name.starts_with("disk") { Some(format!("/dev/{}", name)) } else { None }
+    }).collect()).unwrap_or(Vec::new())
+}
+
+#[cfg(target_os = "redox")]
+fn devices() -> Vec<String> {
+    use std::fs;
+
+    fs::read_dir("disk:").map(|entries| entries.filter_map(|entry| entry.ok()).map(|entry| {
+        format!("disk:{}", entry.file_name().to_string_lossy())
+    }).collect()).unwrap_or(Vec::new())
+}
+
+/// Find the device holding a filesystem whose header matches, encrypted filesystems are not searched
+fn find_device<F: Fn(&Header) -> bool>(matches: F) -> Option<String> {
+    devices().into_iter().find(|path| {
+        let disk = DiskFile::open_read_only(path).and_then(|disk| open_disk(disk, None)).and_then(|disk| open_mirror(vec![disk]));
+
+        let mut header = Header::default();
+        disk.and_then(|disk| disk.read_at(0, &mut header)).is_ok() && header.valid() && matches(&header)
+    })
+}
+
 fn usage() {
-    println!("redoxfs [disk] [mountpoint]");
+    println!("redoxfs [-o OPTIONS] [--cache-size SIZE] [--partition N] [--overlay FILE] [--key-file FILE] [disk...] [mountpoint]");
+    println!("    disk...             several disks are mirrored, created by redoxfs-mkfs with several disks,");
+    println!("                        redoxfs-resync rebuilds a replaced or out of date one,");
+    println!("                        UUID=UUID or LABEL=LABEL finds the disk holding the filesystem with that UUID or label");
+    println!("    -o OPTIONS          comma separated mount options: ro, rw, writeback to keep written blocks in");
+    println!("                        memory until the filesystem syncs them");
+    println!("    --cache-size SIZE   memory used to cache disk blocks, with an optional K, M, or G suffix (default 32M)");
+    println!("    --partition N       mount partition N of the disk instead of the first RedoxFS partition");
+    println!("    --overlay FILE      leave the disk unchanged and keep changed blocks in FILE, created if missing,");
+    println!("                        redoxfs-overlay commits or discards them");
+    println!("    --key-file FILE     unlock an encrypted filesystem with the contents of FILE, instead of asking");
+    println!("    --trace FILE        record every disk operation to FILE, for use with redoxfs-replay");
+    println!("    --trace-data        include written data in the trace");
+}
+
+fn parse_size(arg: &str) -> Option<u64> {
+    let (number, multiplier) = if let Some(number) = arg.strip_suffix('K') {
+        (number, 1024)
+    } else if let Some(number) = arg.strip_suffix('M') {
+        (number, 1024 * 1024)
+    } else if let Some(number) = arg.strip_suffix('G') {
+        (number, 1024 * 1024 * 1024)
+    } else {
+        (arg, 1)
+    };
+
+    number.parse::<u64>().ok().and_then(|number| number.checked_mul(multiplier))
 }
 
 fn main() {
     use std::io::{Read, Write};
 
+    let mut cache_size = 32 * 1024 * 1024;
+    let mut partition = None;
+    let mut read_only = false;
+    let mut write_back = false;
+    let mut overlay = None;
+    let mut key = None;
+    let mut trace = None;
+    let mut trace_flags = TRACE_HASH;
+    let mut paths = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "-o" {
+            let options = args.next().unwrap_or(String::new());
+            for option in options.split(',') {
+                match option {
+                    "ro" => read_only = true,
+                    "rw" => read_only = false,
+                    "writeback" => write_back = true,
+                    _ => {
+                        println!("redoxfs: unknown mount option '{}'", option);
+                        usage();
+                        process::exit(1);
+                    }
+                }
+            }
+        } else if arg == "--cache-size" {
+            match args.next().and_then(|size| parse_size(&size)) {
+                Some(size) => cache_size = size,
+                None => {
+                    println!("redoxfs: invalid cache size");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else if arg == "--partition" {
+            match args.next().and_then(|number| number.parse::<usize>().ok()) {
+                Some(number) => partition = Some(number),
+                None => {
+                    println!("redoxfs: invalid partition number");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else if arg == "--overlay" {
+            match args.next() {
+                Some(path) => overlay = Some(path),
+                None => {
+                    println!("redoxfs: no overlay file provided");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else if arg == "--key-file" {
+            match args.next() {
+                Some(path) => {
+                    let mut data = Vec::new();
+                    if let Err(err) = File::open(&path).and_then(|mut file| file.read_to_end(&mut data)) {
+                        println!("redoxfs: failed to read key file {}: {}", path, err);
+                        process::exit(1);
+                    }
+                    key = Some(data);
+                },
+                None => {
+                    println!("redoxfs: no key file provided");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else if arg == "--trace" {
+            match args.next() {
+                Some(path) => trace = Some(path),
+                None => {
+                    println!("redoxfs: no trace file provided");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else if arg == "--trace-data" {
+            trace_flags |= TRACE_DATA;
+        } else {
+            paths.push(arg);
+        }
+    }
+
+    // The last path is the mountpoint, and is never looked up
+    let disks = if paths.len() > 1 { paths.len() - 1 } else { paths.len() };
+    for path in paths[..disks].iter_mut() {
+        let device = if let Some(uuid) = path.strip_prefix("UUID=") {
+            parse_uuid(uuid).and_then(|uuid| find_device(|header| header.uuid == uuid))
+        } else if let Some(label) = path.strip_prefix("LABEL=") {
+            find_device(|header| header.label().ok() == Some(label))
+        } else {
+            continue;
+        };
+
+        match device {
+            Some(device) => {
+                println!("redoxfs: found {} on {}", path, device);
+                *path = device;
+            },
+            None => {
+                println!("redoxfs: no filesystem with {}", path);
+                process::exit(1);
+            }
+        }
+    }
+
     let mut pipes = [0; 2];
     if pipe(&mut pipes) == 0 {
         let mut read = unsafe { File::from_raw_fd(pipes[0]) };
@@ -52,31 +231,85 @@ fn main() {
         if pid == 0 {
             drop(read);
 
-            if let Some(path) = env::args().nth(1) {
-                //Open an existing image
-                match DiskFile::open(&path).map(|image| DiskCache::new(image)) {
-                    Ok(disk) => match redoxfs::FileSystem::open(disk) {
-                        Ok(filesystem) => {
-                            println!("redoxfs: opened filesystem {}", path);
-
-                            if let Some(mountpoint) = env::args().nth(2) {
-                                match mount(filesystem, &mountpoint, || {
-                                    println!("redoxfs: mounted filesystem on {}:", mountpoint);
-                                    let _ = write.write(&[0]);
-                                }) {
-                                    Ok(()) => {
-                                        process::exit(0);
-                                    },
-                                    Err(err) => {
-                                        println!("redoxfs: failed to mount {} to {}: {}", path, mountpoint, err);
+            let mountpoint = if paths.len() > 1 { paths.pop() } else { None };
+            if ! paths.is_empty() {
+                let path = paths.join(", ");
+
+                //Open existing images
+                let images: Result<Vec<Box<dyn Disk + Send + Sync>>, _> = paths.iter().map(|path| {
+                    let image = if read_only || overlay.is_some() {
+                        DiskFile::open_read_only(path)
+                    } else {
+                        DiskFile::open(path)
+                    };
+
+                    image.and_then(|image| open_disk(image, partition))
+                }).collect();
+
+                let image = images.and_then(open_mirror);
+
+                let image = match overlay {
+                    Some(ref overlay) => image.and_then(|base| if Path::new(overlay).exists() {
+                        DiskFile::open(overlay).and_then(|delta| DiskOverlay::open(base, delta))
+                    } else {
+                        DiskFile::create(overlay, 0).and_then(|delta| DiskOverlay::create(base, delta))
+                    }).map(|image| Box::new(image) as Box<dyn Disk + Send + Sync>),
+                    None => image
+                };
+
+                let image = image.and_then(|image| open_crypt(image, || match key {
+                    Some(ref key) => key.clone(),
+                    None => match read_passphrase(&format!("redoxfs: passphrase for {}: ", path)) {
+                        Ok(passphrase) => passphrase,
+                        Err(err) => {
+                            println!("redoxfs: failed to read passphrase: {}", err);
+                            // The parent waits for a status, it would report success without one
+                            let _ = write.write(&[1]);
+                            process::exit(1);
+                        }
+                    }
+                }));
+
+                match image.and_then(|image| match trace {
+                    Some(ref trace) => DiskTrace::create(image, trace, trace_flags).map(|image| Box::new(image) as Box<dyn Disk + Send + Sync>),
+                    None => Ok(image)
+                }).map(|image| Arc::new(DiskCache::with_options(image, cache_size, write_back))) {
+                    Ok(cache) => {
+                        let filesystem = if read_only {
+                            redoxfs::FileSystem::open_read_only(cache.clone())
+                        } else {
+                            redoxfs::FileSystem::open(cache.clone())
+                        };
+
+                        match filesystem {
+                            Ok(filesystem) => {
+                                println!("redoxfs: opened filesystem {}", path);
+
+                                if let Some(mountpoint) = mountpoint {
+                                    match mount(filesystem, &mountpoint, || {
+                                        println!("redoxfs: mounted filesystem on {}:", mountpoint);
+                                        let _ = write.write(&[0]);
+                                    }) {
+                                        Ok(()) => {
+                                            let stats = cache.stats();
+                                            println!("redoxfs: unmounted filesystem from {}, cache hits {}, misses {}, evictions {}, {} bytes read from disk",
+                                                     mountpoint, stats.hits, stats.misses, stats.evictions, stats.bytes_read);
+
+                                            // The last reference flushes the cache, which exit would skip
+                                            drop(cache);
+                                            process::exit(0);
+                                        },
+                                        Err(err) => {
+                                            println!("redoxfs: failed to mount {} to {}: {}", path, mountpoint, err);
+                                        }
                                     }
+                                } else {
+                                    println!("redoxfs: no mount point provided");
+                                    usage();
                                 }
-                            } else {
-                                println!("redoxfs: no mount point provided");
-                                usage();
-                            }
-                        },
-                        Err(err) => println!("redoxfs: failed to open filesystem {}: {}", path, err)
+                            },
+                            Err(err) => println!("redoxfs: failed to open filesystem {}: {}", path, err)
+                        }
                     },
                     Err(err) => println!("redoxfs: failed to open image {}: {}", path, err)
                 }
@@ -91,7 +324,7 @@ fn main() {
             drop(write);
 
             let mut res = [0];
-            read.read(&mut res).unwrap();
+            read.read_exact(&mut res).unwrap();
 
             process::exit(res[0] as i32);
         } else {
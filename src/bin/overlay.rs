@@ -0,0 +1,115 @@
+#![deny(warnings)]
+
+extern crate redoxfs;
+
+use std::{env, process};
+
+use redoxfs::{Disk, DiskFile, DiskOverlay, mounted, open_disk, open_mirror};
+
+fn usage() {
+    println!("redoxfs-overlay [--partition N] [command] [disk...] [overlay]");
+    println!("    status              show how many blocks the overlay has changed");
+    println!("    commit              write the changed blocks to the disk, then empty the overlay");
+    println!("    discard             empty the overlay, dropping every change");
+    println!("    disk...             every disk of a mirror, created by redoxfs-mkfs with several disks");
+    println!("    --partition N       use partition N of the disk instead of the first RedoxFS partition");
+    println!("    the filesystem must not be mounted with the overlay while it is committed or discarded");
+}
+
+fn main() {
+    let mut partition = None;
+    let mut paths = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--partition" {
+            match args.next().and_then(|number| number.parse::<usize>().ok()) {
+                Some(number) => partition = Some(number),
+                None => {
+                    println!("redoxfs-overlay: invalid partition number");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else {
+            paths.push(arg);
+        }
+    }
+
+    if paths.len() < 3 {
+        println!("redoxfs-overlay: no command, disk or overlay provided");
+        usage();
+        process::exit(1);
+    }
+    let command = paths.remove(0);
+    let overlay_path = paths.pop().unwrap();
+    let disk_path = paths.join(", ");
+
+    if command != "status" && command != "commit" && command != "discard" {
+        println!("redoxfs-overlay: unknown command '{}'", command);
+        usage();
+        process::exit(1);
+    }
+
+    // Only a commit changes the disk
+    if command == "commit" {
+        for path in paths.iter() {
+            if mounted(path) {
+                println!("redoxfs-overlay: {} is mounted, refusing to change it", path);
+                process::exit(1);
+            }
+        }
+    }
+
+    // The base is opened like redoxfs does under the overlay, so the delta has the same size
+    let disks: Result<Vec<Box<dyn Disk + Send + Sync>>, _> = paths.iter().map(|path| {
+        let disk = if command == "commit" {
+            DiskFile::open(path)
+        } else {
+            DiskFile::open_read_only(path)
+        };
+
+        disk.and_then(|disk| open_disk(disk, partition))
+    }).collect();
+
+    let disk = disks.and_then(open_mirror);
+
+    let disk = match disk {
+        Ok(disk) => disk,
+        Err(err) => {
+            println!("redoxfs-overlay: failed to open image {}: {}", disk_path, err);
+            process::exit(1);
+        }
+    };
+
+    let mut overlay = match DiskFile::open(&overlay_path).and_then(|delta| DiskOverlay::open(disk, delta)) {
+        Ok(overlay) => overlay,
+        Err(err) => {
+            println!("redoxfs-overlay: failed to open overlay {}: {}", overlay_path, err);
+            process::exit(1);
+        }
+    };
+
+    let changed = overlay.changed_blocks();
+    let result = if command == "commit" {
+        overlay.commit()
+    } else if command == "discard" {
+        overlay.discard()
+    } else {
+        Ok(())
+    };
+
+    match result {
+        Ok(()) => if command == "commit" {
+            println!("redoxfs-overlay: wrote {} changed blocks to {}", changed, disk_path);
+        } else if command == "discard" {
+            println!("redoxfs-overlay: dropped {} changed blocks from {}", changed, overlay_path);
+        } else {
+            println!("redoxfs-overlay: {} has {} changed blocks", overlay_path, changed);
+        },
+        Err(err) => {
+            println!("redoxfs-overlay: failed to {} {}: {}", command, overlay_path, err);
+            process::exit(1);
+        }
+    }
+}
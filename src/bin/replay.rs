@@ -0,0 +1,62 @@
+#![deny(warnings)]
+
+extern crate redoxfs;
+
+use std::{env, process, time};
+
+use redoxfs::{DiskFile, TraceReader, TRACE_DATA, TRACE_HASH, replay};
+
+fn main() {
+    let mut args = env::args().skip(1);
+    let (trace_path, image_path) = match (args.next(), args.next()) {
+        (Some(trace_path), Some(image_path)) => (trace_path, image_path),
+        _ => {
+            println!("redoxfs-replay: no trace or image provided");
+            println!("redoxfs-replay [trace] [image]");
+            process::exit(1);
+        }
+    };
+
+    let mut trace = match TraceReader::open(&trace_path) {
+        Ok(trace) => trace,
+        Err(err) => {
+            println!("redoxfs-replay: failed to open trace {}: {}", trace_path, err);
+            process::exit(1);
+        }
+    };
+
+    let disk = match DiskFile::create(&image_path, trace.size) {
+        Ok(disk) => disk,
+        Err(err) => {
+            println!("redoxfs-replay: failed to create image {}: {}", image_path, err);
+            process::exit(1);
+        }
+    };
+
+    let start = time::Instant::now();
+    match replay(&mut trace, &disk) {
+        Ok(stats) => {
+            let elapsed = start.elapsed();
+            println!("redoxfs-replay: replayed {} reads ({} bytes), {} writes ({} bytes), {} syncs",
+                     stats.reads, stats.bytes_read, stats.writes, stats.bytes_written, stats.syncs);
+            println!("redoxfs-replay: took {}.{:09} s, traced {}.{:09} s",
+                     elapsed.as_secs(), elapsed.subsec_nanos(),
+                     stats.trace_time / 1000000000, stats.trace_time % 1000000000);
+
+            if trace.flags & (TRACE_HASH | TRACE_DATA) == TRACE_HASH | TRACE_DATA {
+                if stats.mismatches.is_empty() {
+                    println!("redoxfs-replay: all reads matched the trace");
+                } else {
+                    for block in stats.mismatches.iter() {
+                        println!("redoxfs-replay: read at block {} did not match the trace", block);
+                    }
+                    process::exit(1);
+                }
+            }
+        },
+        Err(err) => {
+            println!("redoxfs-replay: failed to replay {}: {}", trace_path, err);
+            process::exit(1);
+        }
+    }
+}
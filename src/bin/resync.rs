@@ -0,0 +1,118 @@
+#![deny(warnings)]
+
+extern crate redoxfs;
+
+use std::{env, process};
+use std::path::Path;
+
+use redoxfs::{Disk, DiskChecksum, DiskFile, DiskMirror, mounted, open_disk, open_partition};
+
+fn usage() {
+    println!("redoxfs-resync [--partition N] [disk...] [new disk]");
+    println!("    copies the mirrored disks to a new, replaced or out of date disk, which is created if missing,");
+    println!("    with checksums if the other disks have them");
+    println!("    --partition N       use partition N of every disk instead of the first RedoxFS partition");
+}
+
+fn main() {
+    let mut partition = None;
+    let mut paths = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--partition" {
+            match args.next().and_then(|number| number.parse::<usize>().ok()) {
+                Some(number) => partition = Some(number),
+                None => {
+                    println!("redoxfs-resync: invalid partition number");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else {
+            paths.push(arg);
+        }
+    }
+
+    let target = match paths.pop() {
+        Some(target) => target,
+        None => {
+            println!("redoxfs-resync: no disks provided");
+            usage();
+            process::exit(1);
+        }
+    };
+
+    if paths.is_empty() {
+        println!("redoxfs-resync: no disk to copy from provided");
+        usage();
+        process::exit(1);
+    }
+
+    // The other disks are written too, to record that the new one is out of date until it is rebuilt
+    for path in paths.iter().chain(Some(&target)) {
+        if mounted(path) {
+            println!("redoxfs-resync: {} is mounted, refusing to change it", path);
+            process::exit(1);
+        }
+    }
+
+    // A new disk gets the size of the first one, and checksums if it has them
+    let first = DiskFile::open_read_only(&paths[0]).and_then(|disk| {
+        let size = disk.size()?;
+        Ok((size, DiskChecksum::probe(&open_partition(disk, partition)?)?))
+    });
+    let (size, checksum) = match first {
+        Ok(first) => first,
+        Err(err) => {
+            println!("redoxfs-resync: failed to open {}: {}", paths[0], err);
+            process::exit(1);
+        }
+    };
+
+    let mut members = Vec::new();
+    for path in paths.iter() {
+        let disk = DiskFile::open(path).and_then(|disk| open_disk(disk, partition));
+
+        match disk {
+            Ok(disk) => members.push(disk),
+            Err(err) => {
+                println!("redoxfs-resync: failed to open {}: {}", path, err);
+                process::exit(1);
+            }
+        }
+    }
+
+    let disk = if Path::new(&target).exists() {
+        DiskFile::open(&target)
+    } else {
+        DiskFile::create(&target, size)
+    };
+
+    let disk = disk.and_then(|disk| open_partition(disk, partition)).and_then(|disk| match DiskChecksum::probe(&disk) {
+        Ok(true) => DiskChecksum::open(disk).map(|disk| Box::new(disk) as Box<dyn Disk + Send + Sync>),
+        Ok(false) => if checksum {
+            DiskChecksum::create(disk).map(|disk| Box::new(disk) as Box<dyn Disk + Send + Sync>)
+        } else {
+            Ok(Box::new(disk) as Box<dyn Disk + Send + Sync>)
+        },
+        Err(err) => Err(err)
+    });
+
+    match disk {
+        Ok(disk) => members.push(disk),
+        Err(err) => {
+            println!("redoxfs-resync: failed to open {}: {}", target, err);
+            process::exit(1);
+        }
+    }
+
+    let member = members.len() - 1;
+    match DiskMirror::open(members).and_then(|mut mirror| mirror.resync(member)) {
+        Ok(()) => println!("redoxfs-resync: rebuilt {}", target),
+        Err(err) => {
+            println!("redoxfs-resync: failed to rebuild {}: {}", target, err);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,105 @@
+#![deny(warnings)]
+
+extern crate redoxfs;
+
+use std::{env, process};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use redoxfs::{Disk, DiskFile, FileSystem, SparseReader, SparseStats, write_sparse};
+
+fn usage() {
+    println!("redoxfs-simg export [--block-size SIZE] [image] [simg]");
+    println!("redoxfs-simg import [simg] [image]");
+}
+
+fn print_stats(stats: &SparseStats, block_size: u32) {
+    println!("redoxfs-simg: {} chunks, {} raw, {} fill, and {} don't care blocks of {} bytes",
+             stats.chunks, stats.raw, stats.fill, stats.dont_care, block_size);
+}
+
+fn export(image: &str, simg: &str, block_size: u32) {
+    let fs = match DiskFile::open_read_only(image).and_then(FileSystem::open_read_only) {
+        Ok(fs) => fs,
+        Err(err) => {
+            println!("redoxfs-simg: failed to open filesystem {}: {}", image, err);
+            process::exit(1);
+        }
+    };
+
+    let mut writer = match File::create(simg) {
+        Ok(file) => BufWriter::new(file),
+        Err(err) => {
+            println!("redoxfs-simg: failed to create {}: {}", simg, err);
+            process::exit(1);
+        }
+    };
+
+    match write_sparse(&fs, &mut writer, block_size) {
+        Ok(stats) => if let Err(err) = writer.flush() {
+            println!("redoxfs-simg: failed to write {}: {}", simg, err);
+            process::exit(1);
+        } else {
+            print_stats(&stats, block_size);
+        },
+        Err(err) => {
+            println!("redoxfs-simg: failed to write {}: {}", simg, err);
+            process::exit(1);
+        }
+    }
+}
+
+fn import(simg: &str, image: &str) {
+    let mut reader = match SparseReader::open(simg) {
+        Ok(reader) => reader,
+        Err(err) => {
+            println!("redoxfs-simg: failed to open {}: {}", simg, err);
+            process::exit(1);
+        }
+    };
+
+    let disk = match DiskFile::create(image, reader.size()) {
+        Ok(disk) => disk,
+        Err(err) => {
+            println!("redoxfs-simg: failed to create image {}: {}", image, err);
+            process::exit(1);
+        }
+    };
+
+    match reader.expand(&disk).and_then(|stats| disk.sync().map(|_| stats)) {
+        Ok(stats) => print_stats(&stats, reader.block_size),
+        Err(err) => {
+            println!("redoxfs-simg: failed to expand {}: {}", simg, err);
+            process::exit(1);
+        }
+    }
+}
+
+fn main() {
+    let mut block_size = 4096;
+    let mut args = Vec::new();
+
+    let mut env_args = env::args().skip(1);
+    while let Some(arg) = env_args.next() {
+        if arg == "--block-size" {
+            match env_args.next().and_then(|size| size.parse::<u32>().ok()) {
+                Some(size) if size > 0 && size % 512 == 0 => block_size = size,
+                _ => {
+                    println!("redoxfs-simg: block size must be a multiple of 512");
+                    process::exit(1);
+                }
+            }
+        } else {
+            args.push(arg);
+        }
+    }
+
+    match (args.get(0).map(|arg| arg.as_str()), args.get(1), args.get(2)) {
+        (Some("export"), Some(image), Some(simg)) => export(image, simg, block_size),
+        (Some("import"), Some(simg), Some(image)) => import(simg, image),
+        _ => {
+            usage();
+            process::exit(1);
+        }
+    }
+}
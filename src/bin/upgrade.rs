@@ -0,0 +1,210 @@
+#![deny(warnings)]
+
+extern crate redoxfs;
+
+use std::{env, process, time};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use redoxfs::{Disk, DiskFile, mounted, open_crypt, open_disk, open_mirror, plan_upgrade, read_passphrase, restore_backup};
+
+fn usage() {
+    println!("redoxfs-upgrade [--dry-run] [--backup FILE] [--restore FILE] [--partition N] [--key-file FILE] [disk...]");
+    println!("    rewrites the metadata of an unmounted filesystem from an older version to the current one");
+    println!("    disk...             every disk of a mirror, created by redoxfs-mkfs with several disks");
+    println!("    --dry-run           show what would be changed, without changing anything");
+    println!("    --backup FILE       save the old contents of the changed blocks to FILE, default disk.upgrade-backup for the first disk,");
+    println!("                        required for block devices");
+    println!("    --restore FILE      write back the blocks saved in FILE by an earlier upgrade");
+    println!("    --partition N       use partition N of the disk instead of the first RedoxFS partition");
+    println!("    --key-file FILE     unlock an encrypted filesystem with the contents of FILE, instead of asking");
+}
+
+fn main() {
+    let mut dry_run = false;
+    let mut backup = None;
+    let mut restore = None;
+    let mut partition = None;
+    let mut key = None;
+    let mut paths = Vec::new();
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--dry-run" {
+            dry_run = true;
+        } else if arg == "--backup" || arg == "--restore" {
+            match args.next() {
+                Some(path) => if arg == "--backup" {
+                    backup = Some(path);
+                } else {
+                    restore = Some(path);
+                },
+                None => {
+                    println!("redoxfs-upgrade: no backup file provided");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else if arg == "--partition" {
+            match args.next().and_then(|number| number.parse::<usize>().ok()) {
+                Some(number) => partition = Some(number),
+                None => {
+                    println!("redoxfs-upgrade: invalid partition number");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else if arg == "--key-file" {
+            match args.next() {
+                Some(path) => {
+                    let mut data = Vec::new();
+                    if let Err(err) = File::open(&path).and_then(|mut file| file.read_to_end(&mut data)) {
+                        println!("redoxfs-upgrade: failed to read key file {}: {}", path, err);
+                        process::exit(1);
+                    }
+                    key = Some(data);
+                },
+                None => {
+                    println!("redoxfs-upgrade: no key file provided");
+                    usage();
+                    process::exit(1);
+                }
+            }
+        } else {
+            paths.push(arg);
+        }
+    }
+
+    if paths.is_empty() {
+        println!("redoxfs-upgrade: no disk image provided");
+        usage();
+        process::exit(1);
+    }
+    let path = paths.join(", ");
+
+    if ! dry_run {
+        for path in paths.iter() {
+            if mounted(path) {
+                println!("redoxfs-upgrade: {} is mounted, refusing to change it", path);
+                process::exit(1);
+            }
+        }
+    }
+
+    let images: Result<Vec<DiskFile>, _> = paths.iter().map(|path| if dry_run {
+        DiskFile::open_read_only(path)
+    } else {
+        DiskFile::open(path)
+    }).collect();
+
+    // The default backup would be written next to the device node, on a filesystem in memory
+    let block_device = images.as_ref().map_or(false, |images| images[0].is_block_device());
+    if ! dry_run && restore.is_none() && backup.is_none() && block_device {
+        println!("redoxfs-upgrade: {} is a block device, choose where to save the old blocks with --backup", paths[0]);
+        process::exit(1);
+    }
+
+    // Every disk of a mirror is opened, so the upgrade is written to all of them
+    let images: Result<Vec<Box<dyn Disk + Send + Sync>>, _> = images.and_then(|images| {
+        images.into_iter().map(|image| open_disk(image, partition)).collect()
+    });
+
+    let image = images.and_then(open_mirror).and_then(|image| open_crypt(image, || match key {
+        Some(ref key) => key.clone(),
+        None => match read_passphrase(&format!("redoxfs-upgrade: passphrase for {}: ", path)) {
+            Ok(passphrase) => passphrase,
+            Err(err) => {
+                println!("redoxfs-upgrade: failed to read passphrase: {}", err);
+                process::exit(1);
+            }
+        }
+    }));
+
+    let image = match image {
+        Ok(image) => image,
+        Err(err) => {
+            println!("redoxfs-upgrade: failed to open image {}: {}", path, err);
+            process::exit(1);
+        }
+    };
+
+    if let Some(restore) = restore {
+        if dry_run {
+            println!("redoxfs-upgrade: --restore cannot be used with --dry-run");
+            process::exit(1);
+        }
+
+        let mut file = match File::open(&restore) {
+            Ok(file) => file,
+            Err(err) => {
+                println!("redoxfs-upgrade: failed to open backup {}: {}", restore, err);
+                process::exit(1);
+            }
+        };
+
+        match restore_backup(&image, &mut file) {
+            Ok(count) => println!("redoxfs-upgrade: restored {} blocks of {} from {}", count, path, restore),
+            Err(err) => {
+                println!("redoxfs-upgrade: failed to restore {} from {}: {}", path, restore, err);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let ctime = time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap();
+    let upgrade = match plan_upgrade(&image, ctime.as_secs(), ctime.subsec_nanos()) {
+        Ok(upgrade) => upgrade,
+        Err(err) => {
+            println!("redoxfs-upgrade: failed to upgrade {}: {}", path, err);
+            process::exit(1);
+        }
+    };
+
+    if upgrade.blocks.is_empty() {
+        println!("redoxfs-upgrade: {} is already at version {}", path, upgrade.to);
+        return;
+    }
+
+    println!("redoxfs-upgrade: {} is at version {}, {} metadata blocks to rewrite for version {}",
+             path, upgrade.from, upgrade.blocks.len(), upgrade.to);
+    if dry_run {
+        for block in upgrade.blocks.iter() {
+            println!("    block {}", block.block);
+        }
+        return;
+    }
+
+    let backup = backup.unwrap_or(format!("{}.upgrade-backup", paths[0]));
+    if Path::new(&backup).exists() {
+        println!("redoxfs-upgrade: {} already exists, refusing to overwrite it", backup);
+        process::exit(1);
+    }
+
+    let mut file = match File::create(&backup) {
+        Ok(file) => file,
+        Err(err) => {
+            println!("redoxfs-upgrade: failed to create backup {}: {}", backup, err);
+            process::exit(1);
+        }
+    };
+
+    // The backup must be on disk before any block is changed, or a crash could lose both
+    if let Err(err) = upgrade.write_backup(&mut file) {
+        println!("redoxfs-upgrade: failed to write backup {}: {}", backup, err);
+        process::exit(1);
+    }
+    if let Err(err) = file.sync_all() {
+        println!("redoxfs-upgrade: failed to write backup {}: {}", backup, err);
+        process::exit(1);
+    }
+
+    match upgrade.apply(&image) {
+        Ok(()) => println!("redoxfs-upgrade: upgraded {} to version {}, old blocks saved in {}", path, upgrade.to, backup),
+        Err(err) => {
+            println!("redoxfs-upgrade: failed to upgrade {}: {}, restore it with --restore {}", path, err, backup);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::mem;
+
+use extent::Extent;
+
+fn read_le(buf: &[u8]) -> u64 {
+    buf.iter().rev().fold(0, |value, &b| value << 8 | b as u64)
+}
+
+fn write_le(buf: &mut [u8], value: u64) {
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = (value >> (i * 8)) as u8;
+    }
+}
+
+/// Index of the part of the map at `level` that holds the entry of `block`
+fn map_index(block: u64, level: u32, entries: u64) -> u64 {
+    (0..level + 1).fold(block, |index, _| index/entries)
+}
+
+/// Number of levels of the map of a file system with `blocks` blocks
+pub fn map_levels(blocks: u64, entries: u64) -> u32 {
+    let mut levels = 1;
+    let mut span = entries;
+    while span < blocks {
+        span = span.saturating_mul(entries);
+        levels += 1;
+    }
+    levels
+}
+
+/// The 64-bit entries of a block of the map
+pub fn decode_map(data: &[u8]) -> Vec<u64> {
+    data.chunks(8).map(read_le).collect()
+}
+
+/// A block of the list of pending extents: the next block, the number of extents, then the extents
+pub fn encode_pending(next: u64, extents: &[Extent], block_size: u64) -> Vec<u8> {
+    let mut data = vec![0; block_size as usize];
+    write_le(&mut data[0..8], next);
+    write_le(&mut data[8..16], extents.len() as u64);
+    for (i, extent) in extents.iter().enumerate() {
+        write_le(&mut data[16 + i * 16..24 + i * 16], extent.block);
+        write_le(&mut data[24 + i * 16..32 + i * 16], extent.length);
+    }
+    data
+}
+
+/// The next block and the extents of a block of the list of pending extents
+pub fn decode_pending(data: &[u8]) -> (u64, Vec<Extent>) {
+    let count = read_le(&data[8..16]) as usize;
+    let extents = data[16..].chunks(16).take(count).map(|entry| {
+        Extent::new(read_le(&entry[..8]), read_le(&entry[8..]))
+    }).collect();
+    (read_le(&data[..8]), extents)
+}
+
+/// Number of extents in a block of the list of pending extents
+pub fn pending_entries(block_size: u64) -> usize {
+    (block_size as usize - 16)/16
+}
+
+fn contains(extents: &[Extent], block: u64, block_size: u64) -> bool {
+    extents.iter().any(|extent| block >= extent.block && block < extent.block + (extent.length + block_size - 1)/block_size)
+}
+
+/// The changes of the running transaction
+#[derive(Default)]
+pub struct CowTransaction {
+    /// Extents allocated, which were free in the last commit and are written in place
+    pub allocated: Vec<Extent>,
+    /// Extents freed, with lengths in bytes
+    pub freed: Vec<Extent>,
+    /// New block of each moved node
+    pub moved: BTreeMap<u64, u64>,
+    /// New block of each changed part of the map
+    pub map_blocks: BTreeMap<(u32, u64), u64>,
+    /// New blocks of the list of pending extents
+    pub pending_blocks: Vec<u64>,
+}
+
+impl CowTransaction {
+    pub fn allocated(&self, block: u64, block_size: u64) -> bool {
+        contains(&self.allocated, block, block_size)
+    }
+
+    pub fn freed(&self, block: u64, block_size: u64) -> bool {
+        contains(&self.freed, block, block_size)
+    }
+}
+
+/// The state of a file system updated by copy-on-write
+///
+/// Nodes keep the number of the block where they were created, and are written to a free block
+/// when they change. The map records where each moved node is: it is a radix tree of blocks of
+/// 64-bit entries, holding the block of a node in the leaves, or 0 if it was not moved, and the
+/// blocks of the level below in the others. A commit writes the moved nodes and the changed
+/// parts of the map to free blocks, then the header. The blocks it stops using are freed by the
+/// next commit, so the blocks of the last commit are never overwritten.
+pub struct Cow {
+    pub generation: u64,
+    /// Block holding each moved node
+    pub map: BTreeMap<u64, u64>,
+    /// Block of each part of the map, by level and index
+    pub map_blocks: BTreeMap<(u32, u64), u64>,
+    /// Extents to free in the next commit, with lengths in bytes
+    pub pending: Vec<Extent>,
+    /// Blocks holding the list of pending extents
+    pub pending_blocks: Vec<u64>,
+    pub txn: CowTransaction,
+}
+
+impl Cow {
+    pub fn new(generation: u64) -> Cow {
+        Cow {
+            generation: generation,
+            map: BTreeMap::new(),
+            map_blocks: BTreeMap::new(),
+            pending: Vec::new(),
+            pending_blocks: Vec::new(),
+            txn: CowTransaction::default()
+        }
+    }
+
+    /// Block of the root of the map
+    pub fn map_root(&self, block_size: u64, blocks: u64) -> u64 {
+        let levels = map_levels(blocks, block_size/8);
+        self.map_blocks.get(&(levels - 1, 0)).cloned().unwrap_or(0)
+    }
+
+    /// First block of the list of pending extents
+    pub fn pending_root(&self) -> u64 {
+        self.pending_blocks.first().cloned().unwrap_or(0)
+    }
+
+    /// Moved nodes in the extents freed by the transaction, which leave the map
+    pub fn removed(&self, block_size: u64) -> Vec<u64> {
+        self.txn.freed.iter().flat_map(|extent| {
+            self.map.range(extent.block..extent.block + extent.length/block_size).map(|(&block, _)| block)
+        }).collect()
+    }
+
+    /// Parts of the map changed by the transaction, by level and index
+    pub fn dirty_map(&self, block_size: u64, levels: u32) -> BTreeSet<(u32, u64)> {
+        let entries = block_size/8;
+        let mut dirty = BTreeSet::new();
+        for block in self.txn.moved.keys().cloned().chain(self.removed(block_size)) {
+            for level in 0..levels {
+                dirty.insert((level, map_index(block, level, entries)));
+            }
+        }
+        dirty
+    }
+
+    /// Extents that the transaction stops using, to free in the next commit
+    pub fn next_pending(&self, block_size: u64, levels: u32) -> Vec<Extent> {
+        let mut pending = self.txn.freed.clone();
+        for block in self.txn.moved.keys().cloned().chain(self.removed(block_size)) {
+            if let Some(&old) = self.map.get(&block) {
+                pending.push(Extent::new(old, block_size));
+            }
+        }
+        for part in self.dirty_map(block_size, levels) {
+            if let Some(&old) = self.map_blocks.get(&part) {
+                pending.push(Extent::new(old, block_size));
+            }
+        }
+        for &old in self.pending_blocks.iter() {
+            pending.push(Extent::new(old, block_size));
+        }
+        pending
+    }
+
+    /// The map after the transaction, with the new blocks of its changed parts
+    pub fn next_map(&self, block_size: u64) -> (BTreeMap<u64, u64>, BTreeMap<(u32, u64), u64>) {
+        let mut map = self.map.clone();
+        for block in self.removed(block_size) {
+            map.remove(&block);
+        }
+        for (&block, &new) in self.txn.moved.iter() {
+            map.insert(block, new);
+        }
+
+        let mut map_blocks = self.map_blocks.clone();
+        for (&part, &new) in self.txn.map_blocks.iter() {
+            map_blocks.insert(part, new);
+        }
+        (map, map_blocks)
+    }
+
+    /// Contents of a part of the map
+    pub fn encode_map(map: &BTreeMap<u64, u64>, map_blocks: &BTreeMap<(u32, u64), u64>, part: (u32, u64), block_size: u64) -> Vec<u8> {
+        let entries = block_size/8;
+        let (level, index) = part;
+        let mut data = vec![0; block_size as usize];
+        for (j, entry) in data.chunks_mut(8).enumerate() {
+            let child = index * entries + j as u64;
+            let value = if level == 0 {
+                map.get(&child)
+            } else {
+                map_blocks.get(&(level - 1, child))
+            };
+            write_le(entry, value.cloned().unwrap_or(0));
+        }
+        data
+    }
+
+    /// Start using the state written by the transaction
+    pub fn finish(&mut self, map: BTreeMap<u64, u64>, map_blocks: BTreeMap<(u32, u64), u64>, pending: Vec<Extent>) {
+        let txn = mem::take(&mut self.txn);
+        self.generation += 1;
+        self.map = map;
+        self.map_blocks = map_blocks;
+        self.pending = pending;
+        self.pending_blocks = txn.pending_blocks;
+    }
+}
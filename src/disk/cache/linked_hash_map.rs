@@ -101,9 +101,7 @@ impl<K, V> LinkedHashMapEntry<K, V> {
 
 unsafe fn drop_empty_entry_box<K, V>(the_box: *mut LinkedHashMapEntry<K, V>) {
     // Prevent compiler from trying to drop the un-initialized key and values in the node.
-    let LinkedHashMapEntry { key, value, .. } = *Box::from_raw(the_box);
-    mem::forget(key);
-    mem::forget(value);
+    drop(Box::from_raw(the_box as *mut mem::MaybeUninit<LinkedHashMapEntry<K, V>>));
 }
 
 impl<K: Hash + Eq, V> LinkedHashMap<K, V> {
@@ -183,7 +181,7 @@ impl<K: Hash + Eq, V, S: BuildHasher> LinkedHashMap<K, V, S> {
         if self.head.is_null() {
             // allocate the guard node if not present
             unsafe {
-                self.head = Box::into_raw(Box::new(mem::uninitialized()));
+                self.head = Box::into_raw(Box::new(mem::MaybeUninit::<LinkedHashMapEntry<K, V>>::uninit())) as *mut LinkedHashMapEntry<K, V>;
                 (*self.head).next = self.head;
                 (*self.head).prev = self.head;
             }
@@ -299,7 +297,7 @@ impl<K: Hash + Eq, V, S: BuildHasher> LinkedHashMap<K, V, S> {
             self.detach(node_ptr);
             self.attach(node_ptr);
         }
-        return value;
+        value
     }
 
     /// Removes and returns the value corresponding to the key from the map.
@@ -364,7 +362,7 @@ impl<K: Hash + Eq, V, S: BuildHasher> LinkedHashMap<K, V, S> {
     /// ```
     #[inline]
     pub fn pop_front(&mut self) -> Option<(K, V)> {
-        if self.len() > 0 {
+        if ! self.is_empty() {
             let lru = unsafe { (*self.head).prev };
             self.detach(lru);
             return self.map
@@ -387,7 +385,7 @@ impl<K: Hash + Eq, V, S: BuildHasher> LinkedHashMap<K, V, S> {
     /// ```
     #[inline]
     pub fn front(&self) -> Option<(&K, &V)> {
-        if self.len() > 0 {
+        if ! self.is_empty() {
             let lru = unsafe { (*self.head).prev };
             return self.map.get(&KeyRef{k: unsafe { &(*lru).key }})
                 .map(|e| (&e.key, &e.value))
@@ -410,7 +408,7 @@ impl<K: Hash + Eq, V, S: BuildHasher> LinkedHashMap<K, V, S> {
     /// ```
     #[inline]
     pub fn pop_back(&mut self) -> Option<(K, V)> {
-        if self.len() > 0 {
+        if ! self.is_empty() {
             let mru = unsafe { (*self.head).next };
             self.detach(mru);
             return self.map
@@ -433,7 +431,7 @@ impl<K: Hash + Eq, V, S: BuildHasher> LinkedHashMap<K, V, S> {
     /// ```
     #[inline]
     pub fn back(&mut self) -> Option<(&K, &V)> {
-        if self.len() > 0 {
+        if ! self.is_empty() {
             let mru = unsafe { (*self.head).next };
             return self.map.get(&KeyRef{k: unsafe { &(*mru).key }})
                 .map(|e| (&e.key, &e.value))
@@ -670,10 +668,6 @@ impl<K: Hash + Eq, V: PartialEq, S: BuildHasher> PartialEq for LinkedHashMap<K,
     fn eq(&self, other: &Self) -> bool {
         self.len() == other.len() && self.iter().eq(other)
     }
-
-    fn ne(&self, other: &Self) -> bool {
-        self.len() != other.len() || self.iter().ne(other)
-    }
 }
 
 impl<K: Hash + Eq, V: Eq, S: BuildHasher> Eq for LinkedHashMap<K, V, S> {}
@@ -805,8 +799,7 @@ impl<'a, K, V> DoubleEndedIterator for Iter<'a, K, V> {
             self.remaining -= 1;
             unsafe {
                 self.tail = (*self.tail).next;
-                let r = Some((&(*self.tail).key, &(*self.tail).value));
-                r
+                Some((&(*self.tail).key, &(*self.tail).value))
             }
         }
     }
@@ -820,8 +813,7 @@ impl<'a, K, V> DoubleEndedIterator for IterMut<'a, K, V> {
             self.remaining -= 1;
             unsafe {
                 self.tail = (*self.tail).next;
-                let r = Some((&(*self.tail).key, &mut (*self.tail).value));
-                r
+                Some((&(*self.tail).key, &mut (*self.tail).value))
             }
         }
     }
@@ -836,6 +828,7 @@ impl<'a, K, V> ExactSizeIterator for IterMut<'a, K, V> {
 }
 
 /// An insertion-order iterator over a `LinkedHashMap`'s keys.
+#[allow(clippy::type_complexity)]
 pub struct Keys<'a, K: 'a, V: 'a> {
     inner: iter::Map<Iter<'a, K, V>, fn((&'a K, &'a V)) -> &'a K>
 }
@@ -847,12 +840,12 @@ impl<'a, K, V> Clone for Keys<'a, K, V> {
 impl<'a, K, V> Iterator for Keys<'a, K, V> {
     type Item = &'a K;
 
-    #[inline] fn next(&mut self) -> Option<(&'a K)> { self.inner.next() }
+    #[inline] fn next(&mut self) -> Option<&'a K> { self.inner.next() }
     #[inline] fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
 }
 
 impl<'a, K, V> DoubleEndedIterator for Keys<'a, K, V> {
-    #[inline] fn next_back(&mut self) -> Option<(&'a K)> { self.inner.next_back() }
+    #[inline] fn next_back(&mut self) -> Option<&'a K> { self.inner.next_back() }
 }
 
 impl<'a, K, V> ExactSizeIterator for Keys<'a, K, V> {
@@ -860,6 +853,7 @@ impl<'a, K, V> ExactSizeIterator for Keys<'a, K, V> {
 }
 
 /// An insertion-order iterator over a `LinkedHashMap`'s values.
+#[allow(clippy::type_complexity)]
 pub struct Values<'a, K: 'a, V: 'a> {
     inner: iter::Map<Iter<'a, K, V>, fn((&'a K, &'a V)) -> &'a V>
 }
@@ -871,12 +865,12 @@ impl<'a, K, V> Clone for Values<'a, K, V> {
 impl<'a, K, V> Iterator for Values<'a, K, V> {
     type Item = &'a V;
 
-    #[inline] fn next(&mut self) -> Option<(&'a V)> { self.inner.next() }
+    #[inline] fn next(&mut self) -> Option<&'a V> { self.inner.next() }
     #[inline] fn size_hint(&self) -> (usize, Option<usize>) { self.inner.size_hint() }
 }
 
 impl<'a, K, V> DoubleEndedIterator for Values<'a, K, V> {
-    #[inline] fn next_back(&mut self) -> Option<(&'a V)> { self.inner.next_back() }
+    #[inline] fn next_back(&mut self) -> Option<&'a V> { self.inner.next_back() }
 }
 
 impl<'a, K, V> ExactSizeIterator for Values<'a, K, V> {
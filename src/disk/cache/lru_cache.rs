@@ -142,6 +142,48 @@ impl<K, V, S> LruCache<K, V, S> where K: Eq + Hash, S: BuildHasher {
         self.map.get_refresh(k)
     }
 
+    /// Returns a mutable reference to the value corresponding to the given key in the cache, if
+    /// any, without marking it as used.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use lru_cache::LruCache;
+    ///
+    /// let mut cache = LruCache::new(2);
+    ///
+    /// cache.insert(1, "a");
+    /// cache.insert(2, "b");
+    /// assert_eq!(cache.peek_mut(&1), Some(&mut "a"));
+    ///
+    /// cache.insert(3, "c");
+    /// assert_eq!(cache.peek_mut(&1), None);
+    /// ```
+    pub fn peek_mut<Q: ?Sized>(&mut self, k: &Q) -> Option<&mut V>
+        where K: Borrow<Q>,
+              Q: Hash + Eq
+    {
+        self.map.get_mut(k)
+    }
+
+    /// Returns the least-recently-used key-value pair, which would be the next to be removed,
+    /// without marking it as used.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use lru_cache::LruCache;
+    ///
+    /// let mut cache = LruCache::new(2);
+    ///
+    /// cache.insert(1, "a");
+    /// cache.insert(2, "b");
+    /// assert_eq!(cache.peek_lru(), Some((&1, &"a")));
+    /// ```
+    pub fn peek_lru(&self) -> Option<(&K, &V)> {
+        self.map.front()
+    }
+
     /// Removes the given key from the cache and returns its corresponding value.
     ///
     /// # Examples
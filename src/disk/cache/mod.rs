@@ -1,4 +1,5 @@
 use std::{cmp, ptr};
+use std::sync::Mutex;
 use syscall::error::Result;
 
 use disk::Disk;
@@ -8,32 +9,237 @@ use self::lru_cache::LruCache;
 mod linked_hash_map;
 mod lru_cache;
 
+/// Memory used for cached blocks unless a size is given
+const DEFAULT_SIZE: u64 = 32 * 1024 * 1024;
+
 fn copy_memory(src: &[u8], dest: &mut [u8]) -> usize {
     let len = cmp::min(src.len(), dest.len());
     unsafe { ptr::copy(src.as_ptr(), dest.as_mut_ptr(), len) };
     len
 }
 
-pub struct DiskCache<T> {
+struct CacheBlock {
+    data: [u8; 512],
+    /// Modified in the cache but not yet written to the inner disk
+    dirty: bool,
+}
+
+/// Counters describing how well a `DiskCache` is performing
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DiskCacheStats {
+    /// Blocks read from the cache
+    pub hits: u64,
+    /// Blocks that had to be read from the inner disk
+    pub misses: u64,
+    /// Blocks removed from the cache to make room for others
+    pub evictions: u64,
+    /// Bytes read from the inner disk
+    pub bytes_read: u64,
+}
+
+struct CacheState {
+    cache: LruCache<u64, CacheBlock>,
+    stats: DiskCacheStats,
+    /// Writes so far, to notice writes racing with a read of the inner disk
+    writes: u64,
+}
+
+impl CacheState {
+    fn dirty(&mut self, block: u64) -> bool {
+        self.cache.peek_mut(&block).map_or(false, |entry| entry.dirty)
+    }
+
+    /// Write all dirty blocks back to the inner disk
+    fn flush<T: Disk>(&mut self, inner: &T) -> Result<()> {
+        let mut dirty: Vec<u64> = self.cache.iter()
+            .filter(|&(_, entry)| entry.dirty)
+            .map(|(&block, _)| block)
+            .collect();
+        dirty.sort();
+
+        for block in dirty {
+            // Blocks may already have been written as part of a neighboring run
+            if self.dirty(block) {
+                self.write_back_run(inner, block)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write a dirty block back, coalesced with any neighboring dirty blocks
+    fn write_back_run<T: Disk>(&mut self, inner: &T, block: u64) -> Result<()> {
+        let mut start = block;
+        while start > 0 && self.dirty(start - 1) {
+            start -= 1;
+        }
+
+        let mut end = block + 1;
+        while self.dirty(end) {
+            end += 1;
+        }
+
+        let mut buffer = vec![0; ((end - start) * 512) as usize];
+        for (i, block_i) in (start..end).enumerate() {
+            if let Some(entry) = self.cache.peek_mut(&block_i) {
+                copy_memory(&entry.data, &mut buffer[i * 512 .. (i + 1) * 512]);
+            }
+        }
+
+        inner.write_at(start, &buffer)?;
+
+        for block_i in start..end {
+            if let Some(entry) = self.cache.peek_mut(&block_i) {
+                entry.dirty = false;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn insert<T: Disk>(&mut self, inner: &T, block: u64, data: [u8; 512], dirty: bool) -> Result<()> {
+        if let Some(entry) = self.cache.get_mut(&block) {
+            entry.data = data;
+            entry.dirty |= dirty;
+            return Ok(());
+        }
+
+        // Make sure the block about to be evicted is not lost
+        if self.cache.len() >= self.cache.capacity() {
+            self.stats.evictions += 1;
+
+            let lru_dirty = match self.cache.peek_lru() {
+                Some((&lru_block, entry)) if entry.dirty => Some(lru_block),
+                _ => None
+            };
+
+            if let Some(lru_block) = lru_dirty {
+                self.write_back_run(inner, lru_block)?;
+            }
+        }
+
+        self.cache.insert(block, CacheBlock {
+            data: data,
+            dirty: dirty
+        });
+
+        Ok(())
+    }
+
+    /// Read one block, from the cache if possible
+    fn read_block<T: Disk>(&mut self, inner: &T, block: u64, data: &mut [u8; 512]) -> Result<()> {
+        if let Some(entry) = self.cache.get_mut(&block) {
+            self.stats.hits += 1;
+            copy_memory(&entry.data, data);
+            return Ok(());
+        }
+
+        self.stats.misses += 1;
+        inner.read_at(block, data)?;
+        self.stats.bytes_read += 512;
+        let copy = *data;
+        self.insert(inner, block, copy, false)
+    }
+}
+
+/// A cache of disk blocks in memory, shared by all users of the disk behind a lock
+///
+/// Unless written blocks are kept in memory, the lock is not held while reading missing blocks from
+/// the inner disk, so several threads can wait for the inner disk at once.
+pub struct DiskCache<T: Disk> {
     inner: T,
-    cache: LruCache<u64, [u8; 512]>,
+    state: Mutex<CacheState>,
+    write_back: bool,
 }
 
 impl<T: Disk> DiskCache<T> {
     pub fn new(inner: T) -> Self {
+        DiskCache::with_size(inner, DEFAULT_SIZE)
+    }
+
+    /// Create a cache that uses at most `size` bytes of memory for cached blocks
+    pub fn with_size(inner: T, size: u64) -> Self {
+        DiskCache::with_options(inner, size, false)
+    }
+
+    /// Create a cache that keeps written blocks in memory until they are evicted or flushed
+    pub fn with_write_back(inner: T) -> Self {
+        DiskCache::with_options(inner, DEFAULT_SIZE, true)
+    }
+
+    /// Create a cache of at most `size` bytes, which keeps written blocks in memory if `write_back`
+    pub fn with_options(inner: T, size: u64, write_back: bool) -> Self {
         DiskCache {
             inner: inner,
-            cache: LruCache::new(65536) // 32 MB cache
+            state: Mutex::new(CacheState {
+                cache: LruCache::new(cmp::max(1, size/512) as usize),
+                stats: DiskCacheStats::default(),
+                writes: 0,
+            }),
+            write_back: write_back,
         }
     }
+
+    pub fn stats(&self) -> DiskCacheStats {
+        self.state.lock().unwrap().stats
+    }
+
+    /// Write all dirty blocks back to the inner disk
+    pub fn flush(&self) -> Result<()> {
+        self.state.lock().unwrap().flush(&self.inner)
+    }
 }
 
 impl<T: Disk> Disk for DiskCache<T> {
-    fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+    fn read_at(&self, block: u64, buffer: &mut [u8]) -> Result<usize> {
         // println!("Cache read at {}", block);
 
+        let mut guard = self.state.lock().unwrap();
+        let writes = {
+            let state = &mut *guard;
+
+            let mut read = 0;
+            let mut failed = false;
+            for i in 0..(buffer.len() + 511)/512 {
+                let block_i = block + i as u64;
+
+                let buffer_i = i * 512;
+                let buffer_j = cmp::min(buffer_i + 512, buffer.len());
+                let buffer_slice = &mut buffer[buffer_i .. buffer_j];
+
+                if let Some(entry) = state.cache.get_mut(&block_i) {
+                    read += copy_memory(&entry.data, buffer_slice);
+                }else{
+                    failed = true;
+                    break;
+                }
+            }
+
+            if ! failed {
+                state.stats.hits += ((buffer.len() + 511)/512) as u64;
+                return Ok(read);
+            }
+
+            state.writes
+        };
+
+        // In write-back mode the inner disk may be older than the cache, and a dirty block written
+        // back by an eviction while the lock is released would be returned and cached stale
+        let mut guard = if self.write_back {
+            self.inner.read_at(block, buffer)?;
+            guard
+        } else {
+            drop(guard);
+            self.inner.read_at(block, buffer)?;
+            self.state.lock().unwrap()
+        };
+        let state = &mut *guard;
+        state.stats.bytes_read += buffer.len() as u64;
+
+        // Blocks read while another thread was writing may already be stale, so are not cached
+        let cacheable = state.writes == writes;
+
         let mut read = 0;
-        let mut failed = false;
         for i in 0..(buffer.len() + 511)/512 {
             let block_i = block + i as u64;
 
@@ -41,38 +247,37 @@ impl<T: Disk> Disk for DiskCache<T> {
             let buffer_j = cmp::min(buffer_i + 512, buffer.len());
             let buffer_slice = &mut buffer[buffer_i .. buffer_j];
 
-            if let Some(cache_buf) = self.cache.get_mut(&block_i) {
-                read += copy_memory(cache_buf, buffer_slice);
-            }else{
-                failed = true;
-                break;
+            // Cached blocks may be newer than the inner disk
+            if let Some(entry) = state.cache.get_mut(&block_i) {
+                state.stats.hits += 1;
+                read += copy_memory(&entry.data, buffer_slice);
+                continue;
             }
-        }
-
-        if failed {
-            self.inner.read_at(block, buffer)?;
-
-            read = 0;
-            for i in 0..(buffer.len() + 511)/512 {
-                let block_i = block + i as u64;
 
-                let buffer_i = i * 512;
-                let buffer_j = cmp::min(buffer_i + 512, buffer.len());
-                let buffer_slice = &buffer[buffer_i .. buffer_j];
+            state.stats.misses += 1;
+            read += buffer_slice.len();
 
-                let mut cache_buf = [0; 512];
-                read += copy_memory(buffer_slice, &mut cache_buf);
-                self.cache.insert(block_i, cache_buf);
+            // Only whole blocks can be cached
+            if cacheable && buffer_slice.len() == 512 {
+                let mut data = [0; 512];
+                copy_memory(buffer_slice, &mut data);
+                state.insert(&self.inner, block_i, data, false)?;
             }
         }
 
         Ok(read)
     }
 
-    fn write_at(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+    fn write_at(&self, block: u64, buffer: &[u8]) -> Result<usize> {
         // println!("Cache write at {}", block);
 
-        self.inner.write_at(block, buffer)?;
+        // Writes hold the lock throughout, so the cache and the inner disk agree
+        let mut state = self.state.lock().unwrap();
+        state.writes += 1;
+
+        if ! self.write_back {
+            self.inner.write_at(block, buffer)?;
+        }
 
         let mut written = 0;
         for i in 0..(buffer.len() + 511)/512 {
@@ -82,15 +287,87 @@ impl<T: Disk> Disk for DiskCache<T> {
             let buffer_j = cmp::min(buffer_i + 512, buffer.len());
             let buffer_slice = &buffer[buffer_i .. buffer_j];
 
-            let mut cache_buf = [0; 512];
-            written += copy_memory(buffer_slice, &mut cache_buf);
-            self.cache.insert(block_i, cache_buf);
+            let mut data = [0; 512];
+            if buffer_slice.len() < 512 {
+                // Partial block, keep the rest of the existing contents
+                state.read_block(&self.inner, block_i, &mut data)?;
+            }
+            written += copy_memory(buffer_slice, &mut data);
+
+            let dirty = self.write_back;
+            state.insert(&self.inner, block_i, data, dirty)?;
         }
 
         Ok(written)
     }
 
-    fn size(&mut self) -> Result<u64> {
+    fn size(&self) -> Result<u64> {
         self.inner.size()
     }
+
+    fn sync(&self) -> Result<()> {
+        self.flush()?;
+        self.inner.sync()
+    }
+}
+
+impl<T: Disk> Drop for DiskCache<T> {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            eprintln!("DiskCache: failed to flush: {}", err);
+        }
+    }
+}
+
+#[test]
+fn disk_cache_write_back_test() {
+    use disk::DiskMemory;
+
+    let mut cache = DiskCache::with_options(DiskMemory::new(16 * 512), 4 * 512, true);
+
+    cache.write_at(0, &[1; 512]).unwrap();
+    cache.write_at(1, &[2; 2 * 512]).unwrap();
+    assert_eq!(cache.inner.as_slice()[0], 0);
+
+    // Dirty blocks are served from the cache
+    let mut buf = [0; 3 * 512];
+    cache.read_at(0, &mut buf).unwrap();
+    assert_eq!(buf[0], 1);
+    assert_eq!(buf[512], 2);
+
+    // Evicting block 0 writes the whole dirty run
+    cache.write_at(8, &[3; 512]).unwrap();
+    cache.write_at(9, &[4; 512]).unwrap();
+    assert_eq!(cache.inner.as_slice()[0], 1);
+    assert_eq!(cache.inner.as_slice()[2 * 512], 2);
+    assert_eq!(cache.inner.as_slice()[8 * 512], 0);
+
+    cache.flush().unwrap();
+    assert_eq!(cache.inner.as_slice()[8 * 512], 3);
+    assert_eq!(cache.inner.as_slice()[9 * 512], 4);
+
+    let stats = cache.stats();
+    assert_eq!(stats.hits, 3);
+    assert_eq!(stats.misses, 0);
+    assert_eq!(stats.evictions, 1);
+    assert_eq!(stats.bytes_read, 0);
+}
+
+#[test]
+fn disk_cache_stats_test() {
+    use disk::DiskMemory;
+
+    let cache = DiskCache::with_size(DiskMemory::new(16 * 512), 2 * 512);
+
+    let mut buf = [0; 512];
+    cache.read_at(0, &mut buf).unwrap();
+    cache.read_at(0, &mut buf).unwrap();
+    cache.read_at(1, &mut buf).unwrap();
+    cache.read_at(2, &mut buf).unwrap();
+
+    let stats = cache.stats();
+    assert_eq!(stats.hits, 1);
+    assert_eq!(stats.misses, 3);
+    assert_eq!(stats.evictions, 1);
+    assert_eq!(stats.bytes_read, 3 * 512);
 }
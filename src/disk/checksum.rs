@@ -0,0 +1,322 @@
+use std::cmp;
+use std::sync::Mutex;
+use syscall::error::{Error, Result, EBADMSG, EINVAL, EIO, ENOSPC};
+
+use disk::Disk;
+
+const CHECKSUM_SIGNATURE: &'static [u8; 8] = b"RFSCHECK";
+const CHECKSUM_VERSION: u64 = 2;
+
+/// Checksums stored in one block of the checksum area
+const CHECKSUMS_PER_BLOCK: u64 = 512/8;
+
+/// Second half of the entry of a block that was not written since the layer was created
+const ENTRY_UNWRITTEN: &'static [u8; 4] = b"NONE";
+/// Second half of the entry of a block whose checksum is in the first half
+const ENTRY_WRITTEN: &'static [u8; 4] = b"CRC ";
+
+fn read_u64(buf: &[u8]) -> u64 {
+    buf[..8].iter().rev().fold(0, |value, &b| value << 8 | b as u64)
+}
+
+fn write_u64(buf: &mut [u8], value: u64) {
+    for (i, b) in buf[..8].iter_mut().enumerate() {
+        *b = (value >> (i * 8)) as u8;
+    }
+}
+
+/// CRC-32C (Castagnoli), as used by iSCSI, ext4 and btrfs
+pub struct Crc32c {
+    table: [u32; 256],
+}
+
+impl Crc32c {
+    pub fn new() -> Crc32c {
+        let mut table = [0; 256];
+        for (i, entry) in table.iter_mut().enumerate() {
+            let mut crc = i as u32;
+            for _ in 0..8 {
+                crc = if crc & 1 != 0 { crc >> 1 ^ 0x82f63b78 } else { crc >> 1 };
+            }
+            *entry = crc;
+        }
+        Crc32c {
+            table: table
+        }
+    }
+
+    pub fn checksum(&self, data: &[u8]) -> u32 {
+        !data.iter().fold(!0, |crc, &b| self.table[((crc ^ b as u32) & 0xFF) as usize] ^ crc >> 8)
+    }
+}
+
+/// A disk that stores a CRC-32C checksum for every block of another disk, and verifies it on read
+///
+/// The inner disk holds a header block, then the checksum area, then the data blocks. Each entry
+/// of the checksum area is the checksum followed by `ENTRY_WRITTEN`, or `ENTRY_UNWRITTEN` for
+/// blocks that were not written since the layer was created, which read as usual. Reading a
+/// block that does not match its checksum, or whose entry is neither, fails with EBADMSG.
+pub struct DiskChecksum<T: Disk> {
+    inner: T,
+    /// Number of data blocks
+    blocks: u64,
+    crc: Crc32c,
+    /// Held while updating the checksum area, where each block is shared by many data blocks
+    update: Mutex<()>,
+}
+
+impl<T: Disk> DiskChecksum<T> {
+    /// Check if a disk has a checksum header
+    pub fn probe(disk: &T) -> Result<bool> {
+        let mut block = [0; 512];
+        let count = disk.read_at(0, &mut block)?;
+        Ok(count >= 8 && &block[..8] == CHECKSUM_SIGNATURE)
+    }
+
+    /// Write a checksum header and an empty checksum area to `inner`
+    pub fn create(inner: T) -> Result<DiskChecksum<T>> {
+        let total = inner.size()?/512;
+        if total < 2 {
+            return Err(Error::new(ENOSPC));
+        }
+
+        // As many data blocks as fit next to their checksums
+        let mut blocks = (total - 1) * CHECKSUMS_PER_BLOCK/(CHECKSUMS_PER_BLOCK + 1);
+        while 1 + (blocks + CHECKSUMS_PER_BLOCK - 1)/CHECKSUMS_PER_BLOCK + blocks > total {
+            blocks -= 1;
+        }
+
+        let checksum_blocks = (blocks + CHECKSUMS_PER_BLOCK - 1)/CHECKSUMS_PER_BLOCK;
+        let mut unwritten = vec![0; 1024 * 1024];
+        for entry in unwritten.chunks_mut(8) {
+            entry[4..].copy_from_slice(ENTRY_UNWRITTEN);
+        }
+        let mut block = 0;
+        while block < checksum_blocks {
+            let count = cmp::min(checksum_blocks - block, unwritten.len() as u64/512);
+            if inner.write_at(1 + block, &unwritten[..count as usize * 512])? != count as usize * 512 {
+                return Err(Error::new(EIO));
+            }
+            block += count;
+        }
+
+        let mut header = [0; 512];
+        header[..8].copy_from_slice(CHECKSUM_SIGNATURE);
+        write_u64(&mut header[8..], CHECKSUM_VERSION);
+        write_u64(&mut header[16..], blocks);
+        if inner.write_at(0, &header)? != header.len() {
+            return Err(Error::new(EIO));
+        }
+
+        Ok(DiskChecksum {
+            inner: inner,
+            blocks: blocks,
+            crc: Crc32c::new(),
+            update: Mutex::new(())
+        })
+    }
+
+    pub fn open(inner: T) -> Result<DiskChecksum<T>> {
+        let mut header = [0; 512];
+        if inner.read_at(0, &mut header)? != header.len() {
+            return Err(Error::new(EIO));
+        }
+
+        if &header[..8] != CHECKSUM_SIGNATURE || read_u64(&header[8..]) != CHECKSUM_VERSION {
+            return Err(Error::new(EINVAL));
+        }
+
+        Ok(DiskChecksum {
+            inner: inner,
+            blocks: read_u64(&header[16..]),
+            crc: Crc32c::new(),
+            update: Mutex::new(())
+        })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// First block of the inner disk holding data
+    fn data_start(&self) -> u64 {
+        1 + (self.blocks + CHECKSUMS_PER_BLOCK - 1)/CHECKSUMS_PER_BLOCK
+    }
+
+    /// Read the blocks of the checksum area covering `count` blocks starting at `block`
+    fn read_checksums(&self, block: u64, count: u64) -> Result<(u64, Vec<u8>)> {
+        let first = block/CHECKSUMS_PER_BLOCK;
+        let last = (block + count - 1)/CHECKSUMS_PER_BLOCK;
+        let mut checksums = vec![0; ((last - first + 1) * 512) as usize];
+        if self.inner.read_at(1 + first, &mut checksums)? != checksums.len() {
+            return Err(Error::new(EIO));
+        }
+        Ok((first * CHECKSUMS_PER_BLOCK, checksums))
+    }
+
+    /// Check whole blocks read from `block`, returning the first that does not match its checksum
+    fn verify(&self, block: u64, data: &[u8]) -> Result<Option<u64>> {
+        let count = (data.len()/512) as u64;
+        if count == 0 {
+            return Ok(None);
+        }
+
+        let (start, checksums) = self.read_checksums(block, count)?;
+        for (i, sector) in data.chunks(512).enumerate() {
+            let offset = ((block + i as u64 - start) * 8) as usize;
+            let entry = &checksums[offset..offset + 8];
+            if &entry[4..] == ENTRY_UNWRITTEN {
+                continue;
+            }
+            let stored = entry[..4].iter().rev().fold(0, |value, &b| value << 8 | b as u32);
+            if &entry[4..] != ENTRY_WRITTEN || stored != self.crc.checksum(sector) {
+                return Ok(Some(block + i as u64));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Read every block, returning those that do not match their checksums
+    pub fn scrub(&self) -> Result<Vec<u64>> {
+        let mut bad = Vec::new();
+        let mut data = vec![0; (CHECKSUMS_PER_BLOCK * 512) as usize];
+        let mut block = 0;
+        while block < self.blocks {
+            let count = cmp::min(self.blocks - block, CHECKSUMS_PER_BLOCK);
+            let len = (count * 512) as usize;
+            let data_start = self.data_start();
+            if self.inner.read_at(data_start + block, &mut data[..len])? != len {
+                return Err(Error::new(EIO));
+            }
+
+            // Check block by block, to find every bad one
+            for i in 0..count {
+                let sector = (i * 512) as usize;
+                if self.verify(block + i, &data[sector..sector + 512])?.is_some() {
+                    bad.push(block + i);
+                }
+            }
+            block += count;
+        }
+        Ok(bad)
+    }
+
+    /// Length of an access at `block` after clipping it to the end of the data blocks
+    fn clip(&self, block: u64, len: usize) -> usize {
+        if block >= self.blocks {
+            0
+        } else {
+            cmp::min(len as u64, (self.blocks - block) * 512) as usize
+        }
+    }
+}
+
+impl<T: Disk> Disk for DiskChecksum<T> {
+    fn read_at(&self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let len = self.clip(block, buffer.len());
+        if len % 512 != 0 {
+            // Checksums cover whole blocks
+            let mut data = vec![0; (len + 511)/512 * 512];
+            self.read_at(block, &mut data)?;
+            buffer[..len].copy_from_slice(&data[..len]);
+            return Ok(len);
+        }
+
+        let data_start = self.data_start();
+        if self.inner.read_at(data_start + block, &mut buffer[..len])? != len {
+            return Err(Error::new(EIO));
+        }
+
+        if let Some(bad) = self.verify(block, &buffer[..len])? {
+            eprintln!("redoxfs: checksum mismatch in block {}", bad);
+            return Err(Error::new(EBADMSG));
+        }
+
+        Ok(len)
+    }
+
+    fn write_at(&self, block: u64, buffer: &[u8]) -> Result<usize> {
+        let len = self.clip(block, buffer.len());
+        if len == 0 {
+            return Ok(0);
+        }
+
+        if len % 512 != 0 {
+            // A partial last block is merged with its current contents
+            let mut data = vec![0; (len + 511)/512 * 512];
+            let last = data.len() - 512;
+            self.read_at(block + last as u64/512, &mut data[last..])?;
+            data[..len].copy_from_slice(&buffer[..len]);
+            self.write_at(block, &data)?;
+            return Ok(len);
+        }
+
+        let data_start = self.data_start();
+        if self.inner.write_at(data_start + block, &buffer[..len])? != len {
+            return Err(Error::new(EIO));
+        }
+
+        // The checksums are written after the data, so a crash in between shows up as a mismatch
+        let _update = self.update.lock().unwrap();
+        let count = (len/512) as u64;
+        let (start, mut checksums) = self.read_checksums(block, count)?;
+        for (i, sector) in buffer[..len].chunks(512).enumerate() {
+            let offset = ((block + i as u64 - start) * 8) as usize;
+            let checksum = self.crc.checksum(sector);
+            for (j, b) in checksums[offset..offset + 4].iter_mut().enumerate() {
+                *b = (checksum >> (j * 8)) as u8;
+            }
+            checksums[offset + 4..offset + 8].copy_from_slice(ENTRY_WRITTEN);
+        }
+        if self.inner.write_at(1 + start/CHECKSUMS_PER_BLOCK, &checksums)? != checksums.len() {
+            return Err(Error::new(EIO));
+        }
+
+        Ok(len)
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.blocks * 512)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.inner.sync()
+    }
+}
+
+#[test]
+fn crc32c_test() {
+    assert_eq!(Crc32c::new().checksum(b"123456789"), 0xe3069283);
+}
+
+#[test]
+fn checksum_test() {
+    use disk::DiskMemory;
+    use filesystem::FileSystem;
+    use node::Node;
+
+    let checksum = DiskChecksum::create(DiskMemory::new(1024 * 1024)).unwrap();
+    let fs = FileSystem::create(checksum, 0, 0).unwrap();
+    let root = fs.header().1.root;
+    let node = fs.create_node(Node::MODE_FILE | 0o644, "test", root, 0, 0).unwrap();
+    fs.write_node(node.0, 0, &[1; 1000], 0, 0).unwrap();
+    assert!(fs.disk.scrub().unwrap().is_empty());
+
+    // Flip a bit of the node on the inner disk
+    let data_start = fs.disk.data_start();
+    let disk = fs.disk.into_inner();
+    let mut block = [0; 512];
+    disk.read_at(data_start + node.0, &mut block).unwrap();
+    block[300] ^= 1;
+    disk.write_at(data_start + node.0, &block).unwrap();
+
+    let fs = FileSystem::open(DiskChecksum::open(disk).unwrap()).unwrap();
+    assert_eq!(fs.find_node("test", root).err().map(|err| err.errno), Some(EBADMSG));
+    assert_eq!(fs.disk.scrub().unwrap(), vec![node.0]);
+
+    // A zeroed block of the checksum area does not turn off verification
+    let disk = fs.disk.into_inner();
+    disk.write_at(1, &[0; 512]).unwrap();
+    let checksum = DiskChecksum::open(disk).unwrap();
+    assert_eq!(checksum.read_at(0, &mut block).err().map(|err| err.errno), Some(EBADMSG));
+}
@@ -0,0 +1,388 @@
+extern crate aes;
+extern crate aes_gcm;
+extern crate hmac;
+extern crate pbkdf2;
+extern crate sha2;
+extern crate xts_mode;
+extern crate zeroize;
+
+use std::cmp;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use syscall::error::{Error, Result, EINVAL, EIO, EKEYREJECTED, ENOSPC};
+
+use disk::Disk;
+use super::file::io_error;
+
+use self::aes::{Aes256, NewBlockCipher};
+use self::aes::cipher::generic_array::GenericArray;
+use self::aes_gcm::{Aes256Gcm, Nonce, Tag};
+use self::aes_gcm::aead::{AeadInPlace, NewAead};
+use self::hmac::Hmac;
+use self::sha2::Sha256;
+use self::xts_mode::{Xts128, get_tweak_default};
+use self::zeroize::{Zeroize, Zeroizing};
+
+const CRYPT_SIGNATURE: &'static [u8; 8] = b"RFSCRYPT";
+const CRYPT_VERSION: u64 = 1;
+
+/// Blocks taken by the unencrypted header, in front of the encrypted data
+const HEADER_BLOCKS: u64 = 8;
+const KEY_SLOTS: usize = 8;
+const KEY_SLOT_SIZE: usize = 128;
+
+/// Offsets in a key slot of the iterations, salt, nonce, encrypted master key and tag
+const SLOT_SALT: usize = 4;
+const SLOT_NONCE: usize = 36;
+const SLOT_MASTER: usize = 48;
+const SLOT_TAG: usize = 112;
+
+#[cfg(not(target_os = "redox"))]
+const RANDOM_PATH: &'static str = "/dev/urandom";
+#[cfg(target_os = "redox")]
+const RANDOM_PATH: &'static str = "rand:";
+
+/// Fill `buf` with random bytes from the operating system
+pub fn random(buf: &mut [u8]) -> Result<()> {
+    File::open(RANDOM_PATH).and_then(|mut file| file.read_exact(buf)).map_err(io_error)
+}
+
+fn read_u32(buf: &[u8]) -> u32 {
+    buf[..4].iter().rev().fold(0, |value, &b| value << 8 | b as u32)
+}
+
+fn write_u32(buf: &mut [u8], value: u32) {
+    for (i, b) in buf[..4].iter_mut().enumerate() {
+        *b = (value >> (i * 8)) as u8;
+    }
+}
+
+/// Read a passphrase from the terminal, without echoing it
+#[cfg(unix)]
+pub fn read_passphrase(prompt: &str) -> io::Result<Vec<u8>> {
+    use libc;
+    use std::mem;
+
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+
+    let mut termios: libc::termios = unsafe { mem::zeroed() };
+    let terminal = unsafe { libc::tcgetattr(0, &mut termios) } == 0;
+    if terminal {
+        let mut quiet = termios;
+        quiet.c_lflag &= !libc::ECHO;
+        unsafe { libc::tcsetattr(0, libc::TCSANOW, &quiet) };
+    }
+
+    let mut line = String::new();
+    let result = io::stdin().read_line(&mut line);
+
+    if terminal {
+        unsafe { libc::tcsetattr(0, libc::TCSANOW, &termios) };
+        eprintln!();
+    }
+
+    result?;
+    let passphrase = line.trim_end_matches(|c| c == '\n' || c == '\r').as_bytes().to_vec();
+    line.zeroize();
+    Ok(passphrase)
+}
+
+/// Read a passphrase from the terminal
+#[cfg(not(unix))]
+pub fn read_passphrase(prompt: &str) -> io::Result<Vec<u8>> {
+    eprint!("{}", prompt);
+    io::stderr().flush()?;
+
+    let mut line = String::new();
+    io::stdin().read_line(&mut line)?;
+    let passphrase = line.trim_end_matches(|c| c == '\n' || c == '\r').as_bytes().to_vec();
+    line.zeroize();
+    Ok(passphrase)
+}
+
+/// The XTS cipher of a 64 byte key, the first half encrypts the data and the second the tweaks
+fn xts(key: &[u8; 64]) -> Xts128<Aes256> {
+    Xts128::new(Aes256::new(GenericArray::from_slice(&key[..32])), Aes256::new(GenericArray::from_slice(&key[32..])))
+}
+
+/// The key that encrypts a key slot, derived from a passphrase
+fn slot_cipher(passphrase: &[u8], salt: &[u8], iterations: u32) -> Aes256Gcm {
+    let mut key = Zeroizing::new([0; 32]);
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase, salt, iterations, &mut *key);
+    Aes256Gcm::new(GenericArray::from_slice(&*key))
+}
+
+/// Data covered by the tag of a key slot without being encrypted: the signature, version and salt
+/// of the disk, and the iterations and salt of the slot
+fn slot_aad(header: &[u8], slot: &[u8]) -> Vec<u8> {
+    let mut aad = header[..48].to_vec();
+    aad.extend_from_slice(&slot[..SLOT_NONCE]);
+    aad
+}
+
+/// A disk that encrypts every block of another disk with AES-256 in XTS mode
+///
+/// The data is encrypted with a random master key, which is stored in key slots, each encrypted
+/// with AES-256-GCM under a key derived from a passphrase with PBKDF2-HMAC-SHA256. The
+/// unencrypted header in front of the data holds a random salt identifying the disk and the key
+/// slots. The tag of a slot also covers the start of the header and the iterations and salt of
+/// the slot, so a slot that was changed or copied from another disk does not unlock it.
+pub struct DiskCrypt<T: Disk> {
+    inner: T,
+    header: Vec<u8>,
+    /// Cleared when the disk is dropped
+    master: Zeroizing<[u8; 64]>,
+    xts: Xts128<Aes256>,
+}
+
+impl<T: Disk> DiskCrypt<T> {
+    /// Iterations of PBKDF2 used to derive keys from passphrases
+    pub const ITERATIONS: u32 = 100000;
+
+    /// Check if a disk has an encryption header
+    pub fn probe(disk: &T) -> Result<bool> {
+        let mut block = [0; 512];
+        let count = disk.read_at(0, &mut block)?;
+        Ok(count >= 8 && &block[..8] == CRYPT_SIGNATURE)
+    }
+
+    /// Write a new encryption header to `inner`, with a random master key unlocked by `passphrase`
+    ///
+    /// Any data already on the disk becomes unreadable.
+    pub fn create(inner: T, passphrase: &[u8]) -> Result<DiskCrypt<T>> {
+        DiskCrypt::create_with(inner, passphrase, DiskCrypt::<T>::ITERATIONS)
+    }
+
+    fn create_with(inner: T, passphrase: &[u8], iterations: u32) -> Result<DiskCrypt<T>> {
+        let mut master = Zeroizing::new([0; 64]);
+        random(&mut *master)?;
+
+        let mut header = vec![0; (HEADER_BLOCKS * 512) as usize];
+        header[..8].copy_from_slice(CRYPT_SIGNATURE);
+        header[8] = CRYPT_VERSION as u8;
+        random(&mut header[16..48])?;
+
+        let xts = xts(&master);
+        let mut crypt = DiskCrypt {
+            inner: inner,
+            header: header,
+            master: master,
+            xts: xts
+        };
+        crypt.add_key_with(passphrase, iterations)?;
+        Ok(crypt)
+    }
+
+    /// Unlock the encrypted disk on `inner` with the passphrase of any key slot
+    pub fn open(inner: T, passphrase: &[u8]) -> Result<DiskCrypt<T>> {
+        let mut header = vec![0; (HEADER_BLOCKS * 512) as usize];
+        if inner.read_at(0, &mut header)? != header.len() {
+            return Err(Error::new(EIO));
+        }
+
+        if &header[..8] != CRYPT_SIGNATURE || header[8] as u64 != CRYPT_VERSION {
+            return Err(Error::new(EINVAL));
+        }
+
+        for slot in 0..KEY_SLOTS {
+            let offset = 512 + slot * KEY_SLOT_SIZE;
+            let iterations = read_u32(&header[offset..]);
+            if iterations == 0 {
+                continue;
+            }
+
+            let cipher = slot_cipher(passphrase, &header[offset + SLOT_SALT .. offset + SLOT_NONCE], iterations);
+            let mut master = Zeroizing::new([0; 64]);
+            master.copy_from_slice(&header[offset + SLOT_MASTER .. offset + SLOT_TAG]);
+            let aad = slot_aad(&header, &header[offset .. offset + KEY_SLOT_SIZE]);
+            let unlocked = cipher.decrypt_in_place_detached(
+                Nonce::from_slice(&header[offset + SLOT_NONCE .. offset + SLOT_MASTER]), &aad, &mut *master,
+                Tag::from_slice(&header[offset + SLOT_TAG .. offset + KEY_SLOT_SIZE])
+            ).is_ok();
+
+            if unlocked {
+                let xts = xts(&master);
+                return Ok(DiskCrypt {
+                    inner: inner,
+                    header: header,
+                    master: master,
+                    xts: xts
+                });
+            }
+        }
+
+        Err(Error::new(EKEYREJECTED))
+    }
+
+
+    /// Add a passphrase that unlocks the disk, returning its key slot
+    pub fn add_key(&mut self, passphrase: &[u8]) -> Result<usize> {
+        self.add_key_with(passphrase, DiskCrypt::<T>::ITERATIONS)
+    }
+
+    fn add_key_with(&mut self, passphrase: &[u8], iterations: u32) -> Result<usize> {
+        let slot = match (0..KEY_SLOTS).find(|&slot| read_u32(&self.header[512 + slot * KEY_SLOT_SIZE..]) == 0) {
+            Some(slot) => slot,
+            None => return Err(Error::new(ENOSPC))
+        };
+
+        let offset = 512 + slot * KEY_SLOT_SIZE;
+        let mut slot_data = [0; KEY_SLOT_SIZE];
+        write_u32(&mut slot_data, iterations);
+        random(&mut slot_data[SLOT_SALT .. SLOT_MASTER])?;
+
+        let cipher = slot_cipher(passphrase, &slot_data[SLOT_SALT .. SLOT_NONCE], iterations);
+        let mut master = Zeroizing::new(*self.master);
+        let aad = slot_aad(&self.header, &slot_data);
+        let tag = match cipher.encrypt_in_place_detached(Nonce::from_slice(&slot_data[SLOT_NONCE .. SLOT_MASTER]), &aad, &mut *master) {
+            Ok(tag) => tag,
+            Err(_) => return Err(Error::new(EINVAL))
+        };
+        slot_data[SLOT_MASTER .. SLOT_TAG].copy_from_slice(&*master);
+        slot_data[SLOT_TAG ..].copy_from_slice(&tag);
+
+        self.header[offset .. offset + KEY_SLOT_SIZE].copy_from_slice(&slot_data);
+        self.write_header()?;
+
+        Ok(slot)
+    }
+
+    /// Remove a key slot, as long as another one is left to unlock the disk
+    pub fn remove_key(&mut self, slot: usize) -> Result<()> {
+        let active = (0..KEY_SLOTS).filter(|&slot| read_u32(&self.header[512 + slot * KEY_SLOT_SIZE..]) != 0).count();
+        if slot >= KEY_SLOTS || read_u32(&self.header[512 + slot * KEY_SLOT_SIZE..]) == 0 || active < 2 {
+            return Err(Error::new(EINVAL));
+        }
+
+        let offset = 512 + slot * KEY_SLOT_SIZE;
+        for b in self.header[offset .. offset + KEY_SLOT_SIZE].iter_mut() {
+            *b = 0;
+        }
+        self.write_header()
+    }
+
+    fn write_header(&mut self) -> Result<()> {
+        if self.inner.write_at(0, &self.header)? != self.header.len() {
+            return Err(Error::new(EIO));
+        }
+        self.inner.sync()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Disk> Disk for DiskCrypt<T> {
+    fn read_at(&self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        if buffer.len() % 512 == 0 {
+            // Only whole blocks can be decrypted
+            let count = self.inner.read_at(HEADER_BLOCKS + block, buffer)?/512 * 512;
+            self.xts.decrypt_area(&mut buffer[..count], 512, block as u128, get_tweak_default);
+            Ok(count)
+        } else {
+            let mut data = vec![0; (buffer.len() + 511)/512 * 512];
+            let count = self.read_at(block, &mut data)?;
+            let count = cmp::min(count, buffer.len());
+            buffer[..count].copy_from_slice(&data[..count]);
+            Ok(count)
+        }
+    }
+
+    fn write_at(&self, block: u64, buffer: &[u8]) -> Result<usize> {
+        let sectors = (buffer.len() + 511)/512;
+        let mut data = vec![0; sectors * 512];
+
+        // A partial last block is merged with its current contents
+        if buffer.len() % 512 != 0 {
+            let last = (sectors - 1) * 512;
+            self.read_at(block + (sectors - 1) as u64, &mut data[last..])?;
+        }
+        data[..buffer.len()].copy_from_slice(buffer);
+
+        self.xts.encrypt_area(&mut data, 512, block as u128, get_tweak_default);
+
+        let count = self.inner.write_at(HEADER_BLOCKS + block, &data)?;
+        Ok(cmp::min(count, buffer.len()))
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.inner.size()?.saturating_sub(HEADER_BLOCKS * 512))
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.inner.sync()
+    }
+}
+
+#[cfg(test)]
+fn copy_disk(disk: &mut ::disk::DiskMemory) -> ::disk::DiskMemory {
+    let copy = ::disk::DiskMemory::new(0);
+    copy.write_at(0, disk.as_slice()).unwrap();
+    copy
+}
+
+#[test]
+fn crypt_test() {
+    use disk::DiskMemory;
+    use filesystem::FileSystem;
+    use node::Node;
+
+    let crypt = DiskCrypt::create_with(DiskMemory::new(1024 * 1024), b"first", 10).unwrap();
+    let mut fs = FileSystem::create(crypt, 0, 0).unwrap();
+    let root = fs.header().1.root;
+    let node = fs.create_node(Node::MODE_FILE | 0o644, "secret", root, 0, 0).unwrap();
+    fs.write_node(node.0, 0, b"RedoxFS secret data", 0, 0).unwrap();
+    fs.disk.add_key_with(b"second", 10).unwrap();
+
+    // Nothing readable reaches the disk
+    let mut disk = fs.disk.into_inner();
+    assert!(DiskCrypt::probe(&disk).unwrap());
+    assert!(! disk.as_slice().windows(7).any(|window| window == b"RedoxFS"));
+
+    assert_eq!(DiskCrypt::open(copy_disk(&mut disk), b"wrong").err().map(|err| err.errno), Some(EKEYREJECTED));
+
+    let fs = FileSystem::open(DiskCrypt::open(disk, b"second").unwrap()).unwrap();
+    let node = fs.find_node("secret", root).unwrap();
+    let mut data = [0; 19];
+    fs.read_node(node.0, 0, &mut data).unwrap();
+    assert_eq!(&data, b"RedoxFS secret data");
+}
+
+#[test]
+fn crypt_key_slots_test() {
+    use disk::DiskMemory;
+
+    let mut crypt = DiskCrypt::create_with(DiskMemory::new(64 * 1024), b"first", 10).unwrap();
+    let second = crypt.add_key_with(b"second", 10).unwrap();
+
+    // Partial blocks are merged with their old contents
+    crypt.write_at(3, &[0xAA; 1024]).unwrap();
+    crypt.write_at(4, &[0xBB; 100]).unwrap();
+    let mut data = [0; 1000];
+    crypt.read_at(3, &mut data).unwrap();
+    assert!(data[..512].iter().all(|&b| b == 0xAA));
+    assert!(data[512..612].iter().all(|&b| b == 0xBB));
+    assert!(data[612..].iter().all(|&b| b == 0xAA));
+
+    crypt.remove_key(0).unwrap();
+    assert!(crypt.remove_key(second).is_err());
+
+    let mut disk = crypt.into_inner();
+    assert!(DiskCrypt::open(copy_disk(&mut disk), b"first").is_err());
+
+    // A key slot that was tampered with no longer unlocks the disk
+    let tampered = copy_disk(&mut disk);
+    let mut header = [0; 512];
+    tampered.read_at(1, &mut header).unwrap();
+    let offset = second * KEY_SLOT_SIZE;
+    write_u32(&mut header[offset..], 9);
+    tampered.write_at(1, &header).unwrap();
+    assert_eq!(DiskCrypt::open(tampered, b"second").err().map(|err| err.errno), Some(EKEYREJECTED));
+
+    let crypt = DiskCrypt::open(disk, b"second").unwrap();
+    let mut check = [0; 1000];
+    crypt.read_at(3, &mut check).unwrap();
+    assert!(check[..] == data[..]);
+}
@@ -0,0 +1,375 @@
+use std::cmp;
+use std::sync::Mutex;
+use syscall::error::{Error, Result, EIO};
+
+use disk::Disk;
+
+/// A fault to inject into a read or write
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fault {
+    /// Fail with the given error code, without transferring anything
+    Error(i32),
+    /// Transfer only the given number of bytes, and report that count
+    Short(usize),
+    /// Flip the given bit of the given byte of the buffer, then succeed
+    BitFlip(usize, u8),
+    /// Transfer only the given number of bytes, then fail with EIO, as if power was lost partway
+    /// through a write
+    Torn(usize),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Op {
+    Read,
+    Write,
+}
+
+/// The operations counted so far, and the faults still to inject
+struct Schedule {
+    reads: u64,
+    writes: u64,
+    faults: Vec<(Op, u64, Fault)>,
+}
+
+/// A disk that injects faults into reads and writes of another disk, according to a schedule
+pub struct DiskFault<T: Disk> {
+    inner: T,
+    schedule: Mutex<Schedule>,
+}
+
+impl<T: Disk> DiskFault<T> {
+    pub fn new(inner: T) -> DiskFault<T> {
+        DiskFault {
+            inner: inner,
+            schedule: Mutex::new(Schedule {
+                reads: 0,
+                writes: 0,
+                faults: Vec::new()
+            })
+        }
+    }
+
+    /// Inject `fault` into read number `n`, counting from 0
+    pub fn fail_read(&mut self, n: u64, fault: Fault) {
+        self.schedule.get_mut().unwrap().faults.push((Op::Read, n, fault));
+    }
+
+    /// Inject `fault` into write number `n`, counting from 0
+    pub fn fail_write(&mut self, n: u64, fault: Fault) {
+        self.schedule.get_mut().unwrap().faults.push((Op::Write, n, fault));
+    }
+
+    /// Remove all scheduled faults
+    pub fn clear(&mut self) {
+        self.schedule.get_mut().unwrap().faults.clear();
+    }
+
+    /// Number of reads so far
+    pub fn reads(&self) -> u64 {
+        self.schedule.lock().unwrap().reads
+    }
+
+    /// Number of writes so far
+    pub fn writes(&self) -> u64 {
+        self.schedule.lock().unwrap().writes
+    }
+
+    pub fn inner(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Count an operation, returning the fault scheduled for it
+    fn fault(&self, op: Op) -> Option<Fault> {
+        let mut schedule = self.schedule.lock().unwrap();
+        let n = match op {
+            Op::Read => { schedule.reads += 1; schedule.reads - 1 },
+            Op::Write => { schedule.writes += 1; schedule.writes - 1 },
+        };
+
+        let position = schedule.faults.iter().position(|&(fault_op, fault_n, _)| fault_op == op && fault_n == n);
+        position.map(|i| schedule.faults.remove(i).2)
+    }
+}
+
+impl<T: Disk> Disk for DiskFault<T> {
+    fn read_at(&self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let fault = self.fault(Op::Read);
+
+        match fault {
+            None => self.inner.read_at(block, buffer),
+            Some(Fault::Error(errno)) => Err(Error::new(errno)),
+            Some(Fault::Short(len)) => {
+                let len = cmp::min(len, buffer.len());
+                self.inner.read_at(block, &mut buffer[..len])
+            },
+            Some(Fault::BitFlip(byte, bit)) => {
+                let count = self.inner.read_at(block, buffer)?;
+                if byte < buffer.len() {
+                    buffer[byte] ^= 1 << bit;
+                }
+                Ok(count)
+            },
+            Some(Fault::Torn(len)) => {
+                let len = cmp::min(len, buffer.len());
+                self.inner.read_at(block, &mut buffer[..len])?;
+                Err(Error::new(EIO))
+            }
+        }
+    }
+
+    fn write_at(&self, block: u64, buffer: &[u8]) -> Result<usize> {
+        let fault = self.fault(Op::Write);
+
+        match fault {
+            None => self.inner.write_at(block, buffer),
+            Some(Fault::Error(errno)) => Err(Error::new(errno)),
+            Some(Fault::Short(len)) => {
+                let len = cmp::min(len, buffer.len());
+                self.inner.write_at(block, &buffer[..len])
+            },
+            Some(Fault::BitFlip(byte, bit)) => {
+                let mut data = buffer.to_vec();
+                if byte < data.len() {
+                    data[byte] ^= 1 << bit;
+                }
+                self.inner.write_at(block, &data)
+            },
+            Some(Fault::Torn(len)) => {
+                // Anything past the last whole sector is merged with the old contents of that sector
+                let len = cmp::min(len, buffer.len());
+                let whole = len/512 * 512;
+                if whole > 0 {
+                    self.inner.write_at(block, &buffer[..whole])?;
+                }
+                if len > whole {
+                    let mut sector = [0; 512];
+                    let sector_block = block + (whole/512) as u64;
+                    self.inner.read_at(sector_block, &mut sector)?;
+                    sector[..len - whole].copy_from_slice(&buffer[whole..len]);
+                    self.inner.write_at(sector_block, &sector)?;
+                }
+                Err(Error::new(EIO))
+            }
+        }
+    }
+
+    fn size(&self) -> Result<u64> {
+        self.inner.size()
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.inner.sync()
+    }
+}
+
+#[cfg(test)]
+type TestFileSystem = ::filesystem::FileSystem<DiskFault<::disk::DiskMemory>>;
+
+#[cfg(test)]
+fn mark_blocks(used: &mut [bool], block: u64, count: u64) {
+    for block in block..block + count {
+        assert!(! used[block as usize], "block {} is used twice", block);
+        used[block as usize] = true;
+    }
+}
+
+/// Mark the blocks of a node, of the nodes it continues in and of its data, or of its children if
+/// it is a directory
+#[cfg(test)]
+fn mark_node(fs: &TestFileSystem, used: &mut Vec<bool>, block: u64) {
+    let block_size = fs.block_size();
+    let dir = fs.node(block).unwrap().1.is_dir();
+
+    let mut next = block;
+    while next != 0 {
+        let node = fs.node(next).unwrap();
+        mark_blocks(used, next, 1);
+        for extent in node.1.extents.iter() {
+            if dir {
+                for (child, _size) in extent.blocks(block_size) {
+                    mark_node(fs, used, child);
+                }
+            } else {
+                mark_blocks(used, extent.block, (extent.length + block_size - 1)/block_size);
+            }
+        }
+        next = node.1.next;
+    }
+}
+
+/// Check that every block is used exactly once, by the header, the journal, the tree or the free
+/// list, so that none were leaked or handed out twice
+#[cfg(test)]
+fn check_blocks(fs: &TestFileSystem) {
+    let block_size = fs.block_size();
+    let header = fs.header().1;
+    let mut used = vec![false; (header.size/block_size) as usize];
+
+    // Block 3, after the free node, is reserved
+    mark_blocks(&mut used, 0, 1);
+    mark_blocks(&mut used, 3, 1);
+    for block in header.backup_blocks() {
+        mark_blocks(&mut used, block, 1);
+    }
+    if header.journal != 0 {
+        mark_blocks(&mut used, header.journal, header.journal_len);
+    }
+
+    mark_node(fs, &mut used, header.root);
+    mark_node(fs, &mut used, header.free);
+
+    let leaked: Vec<usize> = used.iter().enumerate().filter(|&(_, &used)| ! used).map(|(block, _)| block).collect();
+    assert!(leaked.is_empty(), "blocks {:?} are leaked", leaked);
+}
+
+/// Run `op` once for every write it makes, injecting `fault` into that write
+#[cfg(test)]
+fn fault_sweep<S, O>(fault: Fault, setup: &S, op: &O)
+    where S: Fn(&mut TestFileSystem) -> Result<()>, O: Fn(&mut TestFileSystem) -> Result<()>
+{
+    use disk::DiskMemory;
+    use filesystem::FileSystem;
+
+    let mut n = 0;
+    loop {
+        let mut fs = FileSystem::create(DiskFault::new(DiskMemory::new(1024 * 1024)), 0, 0).unwrap();
+        setup(&mut fs).unwrap();
+
+        let writes = fs.disk.writes();
+        fs.disk.fail_write(writes + n, fault);
+        let result = op(&mut fs);
+        if fs.disk.writes() <= writes + n {
+            // The operation finished before reaching the fault
+            result.unwrap();
+            break;
+        }
+        assert_eq!(result.err().map(|err| err.errno), Some(EIO), "{:?} on write {}", fault, n);
+
+        // The file system must still be readable, and intact once the journal is replayed
+        fs.disk.clear();
+        let fs = FileSystem::open(fs.disk).unwrap();
+        let root = fs.header().1.root;
+        let mut children = Vec::new();
+        fs.child_nodes(&mut children, root).unwrap();
+        check_blocks(&fs);
+
+        n += 1;
+    }
+    assert!(n > 0);
+}
+
+#[test]
+fn fault_create_node_test() {
+    use node::Node;
+
+    let setup = |_fs: &mut TestFileSystem| -> Result<()> { Ok(()) };
+    let op = |fs: &mut TestFileSystem| -> Result<()> {
+        let root = fs.header().1.root;
+        fs.create_node(Node::MODE_FILE | 0o644, "test", root, 0, 0).map(|_| ())
+    };
+
+    for &fault in [Fault::Error(EIO), Fault::Short(0), Fault::Torn(100)].iter() {
+        fault_sweep(fault, &setup, &op);
+    }
+}
+
+#[test]
+fn fault_write_node_test() {
+    use node::Node;
+
+    let setup = |fs: &mut TestFileSystem| -> Result<()> {
+        let root = fs.header().1.root;
+        fs.create_node(Node::MODE_FILE | 0o644, "test", root, 0, 0).map(|_| ())
+    };
+    let op = |fs: &mut TestFileSystem| -> Result<()> {
+        let root = fs.header().1.root;
+        let node = fs.find_node("test", root)?;
+        fs.write_node(node.0, 4096, &[1; 4096], 0, 0).map(|_| ())
+    };
+
+    for &fault in [Fault::Error(EIO), Fault::Short(256), Fault::Torn(600)].iter() {
+        fault_sweep(fault, &setup, &op);
+    }
+}
+
+#[test]
+fn fault_remove_node_test() {
+    use node::Node;
+
+    let setup = |fs: &mut TestFileSystem| -> Result<()> {
+        let root = fs.header().1.root;
+        for name in ["a", "b", "c"].iter() {
+            let node = fs.create_node(Node::MODE_FILE | 0o644, name, root, 0, 0)?;
+            fs.write_node(node.0, 0, &[1; 2048], 0, 0)?;
+        }
+        Ok(())
+    };
+    let op = |fs: &mut TestFileSystem| -> Result<()> {
+        let root = fs.header().1.root;
+        fs.remove_node(Node::MODE_FILE, "b", root)
+    };
+
+    for &fault in [Fault::Error(EIO), Fault::Short(0), Fault::Torn(1)].iter() {
+        fault_sweep(fault, &setup, &op);
+    }
+}
+
+/// Write two files a block at a time, so that every block of each is apart from the one before it
+#[cfg(test)]
+fn write_fragmented(fs: &TestFileSystem, blocks: u64) -> Result<()> {
+    use node::Node;
+
+    let root = fs.header().1.root;
+    let a = fs.create_node(Node::MODE_FILE | 0o644, "a", root, 0, 0)?;
+    let b = fs.create_node(Node::MODE_FILE | 0o644, "b", root, 0, 0)?;
+    for i in 0..blocks {
+        fs.write_node(a.0, i * 512, &[1; 512], 0, 0)?;
+        fs.write_node(b.0, i * 512, &[2; 512], 0, 0)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn fault_remove_fragmented_test() {
+    use disk::DiskMemory;
+    use filesystem::FileSystem;
+    use node::Node;
+
+    // Far more extents and continuation nodes than the journal holds in one transaction
+    let fs = FileSystem::create(DiskFault::new(DiskMemory::new(4 * 1024 * 1024)), 0, 0).unwrap();
+    write_fragmented(&fs, 1000).unwrap();
+    let root = fs.header().1.root;
+    let b = fs.find_node("b", root).unwrap();
+    fs.node_set_len(b.0, 512).unwrap();
+    assert_eq!(fs.node_len(b.0).unwrap(), 512);
+    fs.remove_node(Node::MODE_FILE, "a", root).unwrap();
+    check_blocks(&fs);
+
+    // Each of the transactions leaves the file system intact
+    let setup = |fs: &mut TestFileSystem| write_fragmented(fs, 40);
+    let op = |fs: &mut TestFileSystem| -> Result<()> {
+        let root = fs.header().1.root;
+        fs.remove_node(Node::MODE_FILE, "a", root)
+    };
+    fault_sweep(Fault::Error(EIO), &setup, &op);
+}
+
+#[test]
+fn fault_bit_flip_test() {
+    use disk::DiskMemory;
+
+    let mut disk = DiskFault::new(DiskMemory::new(4 * 512));
+    disk.fail_write(0, Fault::BitFlip(3, 1));
+    disk.fail_read(1, Fault::BitFlip(3, 1));
+    disk.write_at(0, &[0; 512]).unwrap();
+
+    let mut buf = [0; 512];
+    disk.read_at(0, &mut buf).unwrap();
+    assert_eq!(buf[3], 2);
+    disk.read_at(0, &mut buf).unwrap();
+    assert_eq!(buf[3], 0);
+}
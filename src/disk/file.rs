@@ -1,55 +1,266 @@
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Write, Seek, SeekFrom};
-use syscall::error::{Error, Result, EIO};
+use std::io::{self, Seek, SeekFrom};
+#[cfg(not(unix))]
+use std::sync::Mutex;
+use syscall::error::{Error, Result, EACCES, EAGAIN, EBUSY, EEXIST, EINTR, EINVAL, EIO, ENOENT, ENOSPC, ETIMEDOUT};
 
 use disk::Disk;
 
+/// Convert an I/O error into the closest matching error code
+pub fn io_error(err: io::Error) -> Error {
+    Error::new(match err.kind() {
+        io::ErrorKind::NotFound => ENOENT,
+        io::ErrorKind::PermissionDenied => EACCES,
+        io::ErrorKind::AlreadyExists => EEXIST,
+        io::ErrorKind::WouldBlock => EAGAIN,
+        io::ErrorKind::InvalidInput => EINVAL,
+        io::ErrorKind::TimedOut => ETIMEDOUT,
+        io::ErrorKind::Interrupted => EINTR,
+        io::ErrorKind::WriteZero => ENOSPC,
+        _ => os_error(&err)
+    })
+}
+
+/// Error codes from the OS match ours on Linux and Redox
+#[cfg(any(target_os = "linux", target_os = "redox"))]
+fn os_error(err: &io::Error) -> i32 {
+    err.raw_os_error().unwrap_or(EIO)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "redox")))]
+fn os_error(_err: &io::Error) -> i32 {
+    EIO
+}
+
+#[cfg(unix)]
+fn is_block_device(file: &File) -> io::Result<bool> {
+    use std::os::unix::fs::FileTypeExt;
+    Ok(file.metadata()?.file_type().is_block_device())
+}
+
+#[cfg(not(unix))]
+fn is_block_device(_file: &File) -> io::Result<bool> {
+    Ok(false)
+}
+
+/// Take an advisory lock on the whole file, failing with `EBUSY` if another open of it holds a
+/// conflicting one
+#[cfg(unix)]
+fn lock(file: &File, exclusive: bool) -> Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let operation = if exclusive { libc::LOCK_EX } else { libc::LOCK_SH };
+    if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } < 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::WouldBlock {
+            return Err(Error::new(EBUSY));
+        }
+        return Err(io_error(err));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn lock(_file: &File, _exclusive: bool) -> Result<()> {
+    Ok(())
+}
+
+/// Size in bytes and logical sector size of a block device
+#[cfg(target_os = "linux")]
+fn block_device_size(file: &mut File) -> io::Result<(u64, u64)> {
+    use std::os::unix::io::AsRawFd;
+
+    const BLKSSZGET: libc::c_ulong = 0x1268;
+    const BLKGETSIZE64: libc::c_ulong = 0x80081272;
+
+    let mut size: u64 = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), BLKGETSIZE64 as _, &mut size) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut sector_size: libc::c_int = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), BLKSSZGET as _, &mut sector_size) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok((size, sector_size as u64))
+}
+
+/// Size in bytes and logical sector size of a block device
+#[cfg(target_os = "macos")]
+fn block_device_size(file: &mut File) -> io::Result<(u64, u64)> {
+    use std::os::unix::io::AsRawFd;
+
+    const DKIOCGETBLOCKSIZE: libc::c_ulong = 0x40046418;
+    const DKIOCGETBLOCKCOUNT: libc::c_ulong = 0x40086419;
+
+    let mut sector_size: u32 = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), DKIOCGETBLOCKSIZE, &mut sector_size) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut sectors: u64 = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), DKIOCGETBLOCKCOUNT, &mut sectors) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok((sectors * sector_size as u64, sector_size as u64))
+}
+
+/// Size in bytes and logical sector size of a block device
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn block_device_size(file: &mut File) -> io::Result<(u64, u64)> {
+    let size = file.seek(SeekFrom::End(0))?;
+    Ok((size, 512))
+}
+
 macro_rules! try_disk {
     ($expr:expr) => (match $expr {
         Ok(val) => val,
-        Err(err) => {
-            eprintln!("Disk I/O Error: {}", err);
-            return Err(Error::new(EIO));
-        }
+        Err(err) => return Err(io_error(err))
     })
 }
 
+/// A disk image or block device
+///
+/// Images opened for writing are locked exclusively, and those opened read only are shared, for
+/// as long as the `DiskFile` is open. A mounted image cannot be changed by the other tools, even
+/// where `mounted` does not see it, as with images mounted through FUSE.
 pub struct DiskFile {
-    file: File
+    file: File,
+    /// Size and logical sector size, if the file is a block device
+    device: Option<(u64, u64)>,
+    /// Held while seeking and transferring, on platforms without positional I/O
+    #[cfg(not(unix))]
+    position: Mutex<()>,
 }
 
 impl DiskFile {
-    pub fn open(path: &str) -> Result<DiskFile> {
-        let file = try_disk!(OpenOptions::new().read(true).write(true).open(path));
+    fn new(mut file: File, exclusive: bool) -> Result<DiskFile> {
+        lock(&file, exclusive)?;
+        let device = if try_disk!(is_block_device(&file)) {
+            Some(try_disk!(block_device_size(&mut file)))
+        } else {
+            None
+        };
+
         Ok(DiskFile {
-            file: file
+            file: file,
+            device: device,
+            #[cfg(not(unix))]
+            position: Mutex::new(())
         })
     }
 
+    pub fn open(path: &str) -> Result<DiskFile> {
+        let file = try_disk!(OpenOptions::new().read(true).write(true).open(path));
+        DiskFile::new(file, true)
+    }
+
+    pub fn open_read_only(path: &str) -> Result<DiskFile> {
+        let file = try_disk!(OpenOptions::new().read(true).open(path));
+        DiskFile::new(file, false)
+    }
+
+    /// Create an image of `size` bytes, discarding the contents of an existing one
     pub fn create(path: &str, size: u64) -> Result<DiskFile> {
-        let file = try_disk!(OpenOptions::new().read(true).write(true).create(true).open(path));
-        try_disk!(file.set_len(size));
-        Ok(DiskFile {
-            file: file
-        })
+        let file = try_disk!(OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path));
+        let disk = DiskFile::new(file, true)?;
+        match disk.device {
+            // Block devices cannot be resized
+            Some((device_size, _)) => if device_size < size {
+                return Err(Error::new(ENOSPC));
+            },
+            None => {
+                try_disk!(disk.file.set_len(0));
+                try_disk!(disk.file.set_len(size));
+            }
+        }
+        Ok(disk)
+    }
+
+    pub fn is_block_device(&self) -> bool {
+        self.device.is_some()
+    }
+
+    /// The smallest unit the underlying device can write, 512 for regular files
+    pub fn sector_size(&self) -> u64 {
+        self.device.map_or(512, |(_, sector_size)| sector_size)
+    }
+
+    #[cfg(unix)]
+    fn pread(&self, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+        use std::os::unix::fs::FileExt;
+        self.file.read_at(buffer, offset)
+    }
+
+    #[cfg(unix)]
+    fn pwrite(&self, buffer: &[u8], offset: u64) -> io::Result<usize> {
+        use std::os::unix::fs::FileExt;
+        self.file.write_at(buffer, offset)
+    }
+
+    #[cfg(not(unix))]
+    fn pread(&self, buffer: &mut [u8], offset: u64) -> io::Result<usize> {
+        use std::io::Read;
+        let _position = self.position.lock().unwrap();
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.read(buffer)
+    }
+
+    #[cfg(not(unix))]
+    fn pwrite(&self, buffer: &[u8], offset: u64) -> io::Result<usize> {
+        use std::io::Write;
+        let _position = self.position.lock().unwrap();
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(offset))?;
+        file.write(buffer)
     }
 }
 
 impl Disk for DiskFile {
-    fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
-        try_disk!(self.file.seek(SeekFrom::Start(block * 512)));
-        let count = try_disk!(self.file.read(buffer));
+    /// Read until the buffer is full, returning a short count only at the end of the file
+    fn read_at(&self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let offset = block * 512;
+        let mut count = 0;
+        while count < buffer.len() {
+            match self.pread(&mut buffer[count..], offset + count as u64) {
+                Ok(0) => break,
+                Ok(read) => count += read,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(io_error(err))
+            }
+        }
         Ok(count)
     }
 
-    fn write_at(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
-        try_disk!(self.file.seek(SeekFrom::Start(block * 512)));
-        let count = try_disk!(self.file.write(buffer));
+    /// Write the entire buffer
+    fn write_at(&self, block: u64, buffer: &[u8]) -> Result<usize> {
+        let offset = block * 512;
+        let mut count = 0;
+        while count < buffer.len() {
+            match self.pwrite(&buffer[count..], offset + count as u64) {
+                Ok(0) => return Err(Error::new(ENOSPC)),
+                Ok(written) => count += written,
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(io_error(err))
+            }
+        }
         Ok(count)
     }
 
-    fn size(&mut self) -> Result<u64> {
-        let size = try_disk!(self.file.seek(SeekFrom::End(0)));
+    fn size(&self) -> Result<u64> {
+        if let Some((size, _)) = self.device {
+            return Ok(size);
+        }
+
+        let size = try_disk!((&self.file).seek(SeekFrom::End(0)));
         Ok(size)
     }
+
+    fn sync(&self) -> Result<()> {
+        try_disk!(self.file.sync_all());
+        Ok(())
+    }
 }
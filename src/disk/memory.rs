@@ -0,0 +1,107 @@
+use std::cmp;
+use std::sync::RwLock;
+use syscall::error::Result;
+
+use disk::Disk;
+
+/// A disk held entirely in memory, growing as blocks past the end are written
+pub struct DiskMemory {
+    data: RwLock<Vec<u8>>
+}
+
+impl DiskMemory {
+    pub fn new(size: u64) -> DiskMemory {
+        DiskMemory {
+            data: RwLock::new(vec![0; size as usize])
+        }
+    }
+
+    /// Load the entire contents of another disk into memory
+    pub fn load<D: Disk>(disk: &D) -> Result<DiskMemory> {
+        let size = disk.size()?;
+        let mut memory = DiskMemory::new(size);
+
+        // Read in 1 MB chunks
+        let mut block = 0;
+        for chunk in memory.data.get_mut().unwrap().chunks_mut(1024 * 1024) {
+            disk.read_at(block, chunk)?;
+            block += (chunk.len() as u64 + 511)/512;
+        }
+
+        Ok(memory)
+    }
+
+    /// Save the entire contents of memory to another disk
+    pub fn save<D: Disk>(&self, disk: &D) -> Result<()> {
+        let data = self.data.read().unwrap();
+        // Write in 1 MB chunks
+        let mut block = 0;
+        for chunk in data.chunks(1024 * 1024) {
+            disk.write_at(block, chunk)?;
+            block += (chunk.len() as u64 + 511)/512;
+        }
+
+        Ok(())
+    }
+
+    pub fn as_slice(&mut self) -> &[u8] {
+        self.data.get_mut().unwrap()
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data.into_inner().unwrap()
+    }
+}
+
+impl Disk for DiskMemory {
+    fn read_at(&self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let data = self.data.read().unwrap();
+        let start = cmp::min(block * 512, data.len() as u64) as usize;
+        let end = cmp::min(start + buffer.len(), data.len());
+        let count = end - start;
+        buffer[..count].copy_from_slice(&data[start..end]);
+        Ok(count)
+    }
+
+    fn write_at(&self, block: u64, buffer: &[u8]) -> Result<usize> {
+        let mut data = self.data.write().unwrap();
+        let start = (block * 512) as usize;
+        let end = start + buffer.len();
+        if end > data.len() {
+            data.resize(end, 0);
+        }
+        data[start..end].copy_from_slice(buffer);
+        Ok(buffer.len())
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.data.read().unwrap().len() as u64)
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[test]
+fn disk_memory_test() {
+    use filesystem::FileSystem;
+    use node::Node;
+
+    let disk = DiskMemory::new(1024 * 1024);
+    let fs = FileSystem::create(disk, 0, 0).unwrap();
+    let root = fs.header().1.root;
+    let node = fs.create_node(Node::MODE_FILE | 0o644, "test", root, 0, 0).unwrap();
+    assert_eq!(fs.write_node(node.0, 0, b"Hello, world!", 0, 0).unwrap(), 13);
+
+    let image = DiskMemory::new(0);
+    fs.disk.save(&image).unwrap();
+    assert_eq!(image.size().unwrap(), 1024 * 1024);
+
+    let fs = FileSystem::open(DiskMemory::load(&image).unwrap()).unwrap();
+    let root = fs.header().1.root;
+    let node = fs.find_node("test", root).unwrap();
+    let mut buf = [0; 13];
+    assert_eq!(fs.read_node(node.0, 0, &mut buf).unwrap(), 13);
+    assert_eq!(&buf, b"Hello, world!");
+}
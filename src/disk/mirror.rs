@@ -0,0 +1,578 @@
+use std::cmp;
+use std::sync::Mutex;
+use syscall::error::{Error, Result, EINVAL, EIO, ENOSPC};
+
+use disk::{Disk, random};
+
+const MIRROR_SIGNATURE: &'static [u8; 8] = b"RFSMIRRR";
+const MIRROR_VERSION: u64 = 1;
+/// Most members a mirror can have, one for each bit of the mask in its record
+const MIRROR_SLOTS: usize = 64;
+
+fn read_u64(buf: &[u8]) -> u64 {
+    buf[..8].iter().rev().fold(0, |value, &b| value << 8 | b as u64)
+}
+
+fn write_u64(buf: &mut [u8], value: u64) {
+    for (i, b) in buf[..8].iter_mut().enumerate() {
+        *b = (value >> (i * 8)) as u8;
+    }
+}
+
+/// The record in the first block of every member
+struct Record {
+    /// Identifies the mirror, so members of another mirror are not used
+    id: [u8; 16],
+    /// Increased whenever a member is dropped, members with an older generation are out of date
+    generation: u64,
+    /// Size of the mirrored data in bytes
+    size: u64,
+    /// Slot of this member in the mirror
+    slot: u64,
+    /// Slots of the members that were up to date when this generation was written, as a bit mask
+    current: u64,
+}
+
+impl Record {
+    fn read<T: Disk>(member: &T) -> Result<Option<Record>> {
+        let mut block = [0; 512];
+        if member.read_at(0, &mut block)? != block.len() {
+            return Ok(None);
+        }
+
+        if &block[..8] != MIRROR_SIGNATURE || read_u64(&block[8..]) != MIRROR_VERSION {
+            return Ok(None);
+        }
+
+        let mut id = [0; 16];
+        id.copy_from_slice(&block[16..32]);
+        Ok(Some(Record {
+            id: id,
+            generation: read_u64(&block[32..]),
+            size: read_u64(&block[40..]),
+            slot: read_u64(&block[48..]),
+            current: read_u64(&block[56..])
+        }))
+    }
+
+    fn write<T: Disk>(&self, member: &T) -> Result<()> {
+        let mut block = [0; 512];
+        block[..8].copy_from_slice(MIRROR_SIGNATURE);
+        write_u64(&mut block[8..], MIRROR_VERSION);
+        block[16..32].copy_from_slice(&self.id);
+        write_u64(&mut block[32..], self.generation);
+        write_u64(&mut block[40..], self.size);
+        write_u64(&mut block[48..], self.slot);
+        write_u64(&mut block[56..], self.current);
+        if member.write_at(0, &block)? != block.len() {
+            return Err(Error::new(EIO));
+        }
+        member.sync()
+    }
+}
+
+struct MirrorState {
+    healthy: Vec<bool>,
+    generation: u64,
+    /// Members were missing when the mirror was opened, which is recorded before the first write
+    missing: bool,
+}
+
+/// A disk that keeps the same data on every one of its members, like RAID 1
+///
+/// Writes go to every healthy member, and reads to the first healthy member that succeeds. When a
+/// member fails a read, for example with a checksum mismatch from a `DiskChecksum` below it, the
+/// data read from another member is written back to it. A member that fails a write is no longer
+/// used until it is resynced.
+///
+/// The first block of every member holds a record with the generation of the mirror and the slots
+/// of the members that are up to date. The generation is increased on the remaining members
+/// whenever one is dropped, or is missing when the mirror is opened, so a member that missed
+/// writes is still known to be out of date after a restart.
+pub struct DiskMirror<T: Disk> {
+    members: Vec<T>,
+    /// Slot of each member, which stays the same when the members are given in another order
+    slots: Vec<u64>,
+    id: [u8; 16],
+    size: u64,
+    state: Mutex<MirrorState>,
+}
+
+impl<T: Disk> DiskMirror<T> {
+    /// Check if a disk is a member of a mirror
+    pub fn probe(disk: &T) -> Result<bool> {
+        Ok(Record::read(disk)?.is_some())
+    }
+
+    /// Write a new mirror record to every member, the size is that of the smallest one
+    pub fn create(members: Vec<T>) -> Result<DiskMirror<T>> {
+        if members.is_empty() || members.len() > MIRROR_SLOTS {
+            return Err(Error::new(EINVAL));
+        }
+
+        let mut size = None;
+        for member in members.iter() {
+            let member_size = member.size()?/512 * 512;
+            size = Some(size.map_or(member_size, |size| cmp::min(size, member_size)));
+        }
+        let size = size.unwrap_or(0);
+        if size < 2 * 512 {
+            return Err(Error::new(ENOSPC));
+        }
+
+        let mut id = [0; 16];
+        random(&mut id)?;
+
+        let healthy = vec![true; members.len()];
+        let mirror = DiskMirror {
+            slots: (0..members.len() as u64).collect(),
+            members: members,
+            id: id,
+            size: size - 512,
+            state: Mutex::new(MirrorState {
+                healthy: healthy,
+                generation: 0,
+                missing: false
+            })
+        };
+        mirror.commit(&mut mirror.state.lock().unwrap())?;
+        Ok(mirror)
+    }
+
+    /// Open the members of a mirror
+    ///
+    /// Members with the latest generation are used, the others, including new disks without a
+    /// record, are kept out of date until they are resynced. A new disk takes the slot of a missing
+    /// member, which can no longer be used with it. If members are missing, the
+    /// generation is increased on the ones that are present before they are first written, so the
+    /// missing ones are out of date once they are back. Nothing is written until then, so the
+    /// members may be opened read only. Members that were each written without the other are
+    /// refused, as neither has all of the writes.
+    pub fn open(members: Vec<T>) -> Result<DiskMirror<T>> {
+        let mut records = Vec::new();
+        for member in members.iter() {
+            records.push(Record::read(member)?);
+        }
+
+        let (id, generation, size, current) = match records.iter().filter_map(|record| record.as_ref()).max_by_key(|record| record.generation) {
+            Some(latest) => (latest.id, latest.generation, latest.size, latest.current),
+            None => return Err(Error::new(EINVAL))
+        };
+
+        // Records of other mirrors are ignored, so their disks can be resynced into this one
+        let records: Vec<Option<Record>> = records.into_iter().map(|record| record.filter(|record| record.id == id)).collect();
+
+        let mut used = 0u64;
+        for (i, record) in records.iter().enumerate() {
+            if let Some(ref record) = *record {
+                if record.slot >= MIRROR_SLOTS as u64 {
+                    return Err(Error::new(EINVAL));
+                }
+                if used & 1 << record.slot != 0 {
+                    let other = records.iter().position(|other| other.as_ref().map_or(false, |other| other.slot == record.slot)).unwrap_or(0);
+                    eprintln!("redoxfs: mirror members {} and {} are in the same slot, one of them replaced the other", other, i);
+                    return Err(Error::new(EINVAL));
+                }
+                used |= 1 << record.slot;
+            }
+        }
+
+        for (i, a) in records.iter().enumerate() {
+            for (j, b) in records.iter().enumerate().skip(i + 1) {
+                if let (Some(a), Some(b)) = (a, b) {
+                    if a.current & 1 << b.slot == 0 && b.current & 1 << a.slot == 0 {
+                        eprintln!("redoxfs: mirror members {} and {} were each used without the other", i, j);
+                        return Err(Error::new(EINVAL));
+                    }
+                }
+            }
+        }
+
+        // Disks without a record take the first free slots, replacing members that are missing
+        let mut slots = Vec::new();
+        for record in records.iter() {
+            let slot = match *record {
+                Some(ref record) => record.slot,
+                None => match (0..MIRROR_SLOTS as u64).find(|&slot| used & 1 << slot == 0) {
+                    Some(slot) => slot,
+                    None => return Err(Error::new(EINVAL))
+                }
+            };
+            used |= 1 << slot;
+            slots.push(slot);
+        }
+
+        let mut healthy = Vec::new();
+        for (i, record) in records.iter().enumerate() {
+            let up_to_date = record.as_ref().map_or(false, |record| {
+                record.generation == generation && current & 1 << record.slot != 0
+            });
+            if ! up_to_date {
+                eprintln!("redoxfs: mirror member {} is out of date, resync it", i);
+            }
+            healthy.push(up_to_date);
+        }
+
+        let mirror = DiskMirror {
+            members: members,
+            slots: slots,
+            id: id,
+            size: size,
+            state: Mutex::new(MirrorState {
+                healthy: healthy,
+                generation: generation,
+                missing: false
+            })
+        };
+
+        {
+            let mut state = mirror.state.lock().unwrap();
+            if mirror.current(&state) != current {
+                eprintln!("redoxfs: mirror is missing members, they will be out of date once it is written");
+                state.missing = true;
+            }
+        }
+
+        Ok(mirror)
+    }
+
+    /// Number of members, healthy or not
+    pub fn members(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn healthy(&self, member: usize) -> bool {
+        self.state.lock().unwrap().healthy.get(member).map_or(false, |&healthy| healthy)
+    }
+
+    pub fn into_inner(self) -> Vec<T> {
+        self.members
+    }
+
+    /// Slots of the healthy members, as a bit mask
+    fn current(&self, state: &MirrorState) -> u64 {
+        self.slots.iter().zip(state.healthy.iter()).filter(|&(_, &healthy)| healthy).fold(0, |current, (&slot, _)| current | 1 << slot)
+    }
+
+    /// Write a record with a new generation to every healthy member
+    fn commit(&self, state: &mut MirrorState) -> Result<()> {
+        // A member that fails to take the new generation is dropped too, and the generation is
+        // increased again, so that every healthy member has the latest one
+        state.missing = false;
+        loop {
+            state.generation += 1;
+            let current = self.current(state);
+
+            let mut failed = false;
+            for i in 0..self.members.len() {
+                if state.healthy[i] {
+                    let record = Record {
+                        id: self.id,
+                        generation: state.generation,
+                        size: self.size,
+                        slot: self.slots[i],
+                        current: current
+                    };
+                    if let Err(err) = record.write(&self.members[i]) {
+                        eprintln!("redoxfs: mirror member {} failed: {}, removing it from the mirror", i, err);
+                        state.healthy[i] = false;
+                        failed = true;
+                    }
+                }
+            }
+
+            if ! failed {
+                break;
+            }
+        }
+
+        if state.healthy.iter().any(|&healthy| healthy) {
+            Ok(())
+        } else {
+            Err(Error::new(EIO))
+        }
+    }
+
+    /// Stop using `member`, and record on the others that it is out of date
+    fn drop_member(&self, member: usize) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        state.healthy[member] = false;
+        self.commit(&mut state)
+    }
+
+    fn fail(&self, member: usize, err: Error) {
+        eprintln!("redoxfs: mirror member {} failed: {}, removing it from the mirror", member, err);
+        let _ = self.drop_member(member);
+    }
+
+    /// Copy everything from the healthy members to `member`, then use it again
+    pub fn resync(&mut self, member: usize) -> Result<()> {
+        if member >= self.members.len() {
+            return Err(Error::new(EINVAL));
+        }
+
+        // The member must not serve reads until it has been rebuilt, and must stay out of date if
+        // the rebuild is interrupted
+        self.drop_member(member)?;
+        let size = self.size;
+        if self.members[member].size()? < size + 512 {
+            return Err(Error::new(ENOSPC));
+        }
+
+        let mut buffer = vec![0; 1024 * 1024];
+        let mut block = 0;
+        while block * 512 < size {
+            let len = cmp::min(buffer.len() as u64, size - block * 512) as usize;
+            if self.read_at(block, &mut buffer[..len])? != len
+                || self.members[member].write_at(1 + block, &buffer[..len])? != len {
+                return Err(Error::new(EIO));
+            }
+            block += (len/512) as u64;
+        }
+        self.members[member].sync()?;
+
+        let mut state = self.state.lock().unwrap();
+        state.healthy[member] = true;
+        self.commit(&mut state)
+    }
+
+    /// Length of an access at `block` after clipping it to the end of the mirrored data
+    fn clip(&self, block: u64, len: usize) -> usize {
+        if block >= self.size/512 {
+            0
+        } else {
+            cmp::min(len as u64, self.size - block * 512) as usize
+        }
+    }
+}
+
+impl<T: Disk> Disk for DiskMirror<T> {
+    fn read_at(&self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let len = self.clip(block, buffer.len());
+        let mut failed = Vec::new();
+        let mut result = Err(Error::new(EIO));
+        for i in 0..self.members.len() {
+            if ! self.healthy(i) {
+                continue;
+            }
+
+            // A short read is a failure, as the rest of the data may be on another member
+            match self.members[i].read_at(1 + block, &mut buffer[..len]) {
+                Ok(count) if count == len => {
+                    result = Ok(len);
+                    break;
+                },
+                Ok(count) => {
+                    eprintln!("redoxfs: mirror member {} read {} of {} bytes at block {}", i, count, len, block);
+                    failed.push(i);
+                },
+                Err(err) => {
+                    eprintln!("redoxfs: mirror member {} failed to read block {}: {}", i, block, err);
+                    result = Err(err);
+                    failed.push(i);
+                }
+            }
+        }
+
+        // Repair the members that failed with the data that was read
+        if result.is_ok() {
+            for i in failed {
+                match self.members[i].write_at(1 + block, &buffer[..len]) {
+                    Ok(written) if written == len => eprintln!("redoxfs: mirror member {} repaired at block {}", i, block),
+                    Ok(_) => self.fail(i, Error::new(EIO)),
+                    Err(err) => self.fail(i, err)
+                }
+            }
+        }
+
+        result
+    }
+
+    fn write_at(&self, block: u64, buffer: &[u8]) -> Result<usize> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.missing {
+                self.commit(&mut state)?;
+            }
+        }
+
+        let len = self.clip(block, buffer.len());
+        let mut result = Err(Error::new(EIO));
+        for i in 0..self.members.len() {
+            if ! self.healthy(i) {
+                continue;
+            }
+
+            match self.members[i].write_at(1 + block, &buffer[..len]) {
+                Ok(count) if count == len => result = Ok(len),
+                Ok(_) => self.fail(i, Error::new(EIO)),
+                Err(err) => {
+                    if result.is_err() {
+                        result = Err(Error::new(err.errno));
+                    }
+                    self.fail(i, err);
+                }
+            }
+        }
+        result
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.size)
+    }
+
+    fn sync(&self) -> Result<()> {
+        let mut result = Err(Error::new(EIO));
+        for i in 0..self.members.len() {
+            if ! self.healthy(i) {
+                continue;
+            }
+
+            match self.members[i].sync() {
+                Ok(()) => result = Ok(()),
+                Err(err) => {
+                    if result.is_err() {
+                        result = Err(Error::new(err.errno));
+                    }
+                    self.fail(i, err);
+                }
+            }
+        }
+        result
+    }
+}
+
+#[test]
+fn mirror_repair_test() {
+    use disk::{DiskChecksum, DiskMemory};
+    use filesystem::FileSystem;
+    use node::Node;
+
+    let members = vec![
+        DiskChecksum::create(DiskMemory::new(1024 * 1024)).unwrap(),
+        DiskChecksum::create(DiskMemory::new(1024 * 1024)).unwrap()
+    ];
+    let fs = FileSystem::create(DiskMirror::create(members).unwrap(), 0, 0).unwrap();
+    let root = fs.header().1.root;
+    let node = fs.create_node(Node::MODE_FILE | 0o644, "test", root, 0, 0).unwrap();
+
+    // Corrupt the node on the first member only, its data starts after the checksum header, 32
+    // checksum blocks and the mirror record
+    let mut members = fs.disk.into_inner();
+    let mut block = [0; 512];
+    let first = members.remove(0);
+    let inner = first.into_inner();
+    inner.read_at(34 + node.0, &mut block).unwrap();
+    block[300] ^= 1;
+    inner.write_at(34 + node.0, &block).unwrap();
+    members.insert(0, DiskChecksum::open(inner).unwrap());
+
+    let fs = FileSystem::open(DiskMirror::open(members).unwrap()).unwrap();
+    assert!(fs.find_node("test", root).is_ok());
+    assert!(fs.disk.healthy(0));
+
+    let members = fs.disk.into_inner();
+    assert!(members[0].scrub().unwrap().is_empty());
+}
+
+#[test]
+fn mirror_resync_test() {
+    use disk::{DiskFault, DiskMemory, Fault};
+    use filesystem::FileSystem;
+    use node::Node;
+
+    let members = vec![DiskFault::new(DiskMemory::new(1024 * 1024)), DiskFault::new(DiskMemory::new(1024 * 1024))];
+    let mut fs = FileSystem::create(DiskMirror::create(members).unwrap(), 0, 0).unwrap();
+    let root = fs.header().1.root;
+
+    // A failed write drops the member, and the mirror keeps working without it
+    let writes = fs.disk.members[1].writes();
+    fs.disk.members[1].fail_write(writes, Fault::Error(EIO));
+    fs.create_node(Node::MODE_FILE | 0o644, "test", root, 0, 0).unwrap();
+    assert!(! fs.disk.healthy(1));
+
+    // The member is still out of date when the mirror is opened again
+    let mut members = fs.disk.into_inner();
+    members[1].clear();
+    let mut fs = FileSystem::open(DiskMirror::open(members).unwrap()).unwrap();
+    assert!(! fs.disk.healthy(1));
+    assert!(fs.find_node("test", root).is_ok());
+
+    fs.disk.resync(1).unwrap();
+    assert!(fs.disk.healthy(1));
+    let mirror = DiskMirror::open(fs.disk.into_inner()).unwrap();
+    assert!(mirror.healthy(0) && mirror.healthy(1));
+
+    let mut members = mirror.into_inner().into_iter().map(|member| member.into_inner());
+    let mut first: DiskMemory = members.next().unwrap();
+    let mut second = members.next().unwrap();
+    assert!(first.as_slice()[512..] == second.as_slice()[512..]);
+
+    let fs = FileSystem::open(DiskMirror::open(vec![second]).unwrap()).unwrap();
+    assert!(fs.find_node("test", root).is_ok());
+}
+
+#[test]
+fn mirror_short_read_test() {
+    use disk::{DiskFault, DiskMemory, Fault};
+
+    let members = vec![DiskFault::new(DiskMemory::new(1024 * 1024)), DiskFault::new(DiskMemory::new(1024 * 1024))];
+    let mut mirror = DiskMirror::create(members).unwrap();
+    mirror.write_at(0, &[1; 1024]).unwrap();
+
+    // The short read is retried on the other member, and the first is repaired
+    let reads = mirror.members[0].reads();
+    mirror.members[0].fail_read(reads, Fault::Short(512));
+    let mut buffer = [0; 1024];
+    assert_eq!(mirror.read_at(0, &mut buffer).unwrap(), 1024);
+    assert!(buffer.iter().all(|&b| b == 1));
+    assert!(mirror.healthy(0));
+}
+
+#[test]
+fn mirror_missing_member_test() {
+    use disk::DiskMemory;
+    use filesystem::FileSystem;
+    use node::Node;
+
+    let members = vec![DiskMemory::new(1024 * 1024), DiskMemory::new(1024 * 1024)];
+    let fs = FileSystem::create(DiskMirror::create(members).unwrap(), 0, 0).unwrap();
+    let root = fs.header().1.root;
+    let mut members = fs.disk.into_inner();
+    let b = members.remove(1);
+    let a = members.remove(0);
+
+    // Opening without the second member and without writing leaves both up to date
+    let a = DiskMirror::open(vec![a]).unwrap().into_inner().remove(0);
+    let mirror = DiskMirror::open(vec![a, b]).unwrap();
+    assert!(mirror.healthy(0) && mirror.healthy(1));
+    let mut members = mirror.into_inner();
+    let b = members.remove(1);
+    let a = members.remove(0);
+
+    // Changes made without the second member leave it out of date, whatever the order
+    let fs = FileSystem::open(DiskMirror::open(vec![a]).unwrap()).unwrap();
+    fs.create_node(Node::MODE_FILE | 0o644, "test", root, 0, 0).unwrap();
+    fs.sync().unwrap();
+    let a = fs.disk.into_inner().remove(0);
+
+    let mirror = DiskMirror::open(vec![b, a]).unwrap();
+    assert!(! mirror.healthy(0) && mirror.healthy(1));
+    let fs = FileSystem::open(mirror).unwrap();
+    assert!(fs.find_node("test", root).is_ok());
+
+    // Members that were each written alone are refused together
+    let mut members = fs.disk.into_inner();
+    let a = members.remove(1);
+    let b = members.remove(0);
+    let write_alone = |member| {
+        let mirror = DiskMirror::open(vec![member]).unwrap();
+        let mut block = [0; 512];
+        mirror.read_at(0, &mut block).unwrap();
+        mirror.write_at(0, &block).unwrap();
+        mirror.into_inner().remove(0)
+    };
+    let a = write_alone(a);
+    let b = write_alone(b);
+    assert_eq!(DiskMirror::open(vec![a, b]).err().map(|err| err.errno), Some(EINVAL));
+}
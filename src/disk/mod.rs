@@ -1,14 +1,94 @@
+use std::sync::Arc;
 use syscall::error::Result;
 
-pub use self::cache::DiskCache;
-pub use self::file::DiskFile;
+pub use self::cache::{DiskCache, DiskCacheStats};
+pub use self::checksum::{Crc32c, DiskChecksum};
+pub use self::crypt::{DiskCrypt, random, read_passphrase};
+pub use self::fault::{DiskFault, Fault};
+pub use self::file::{DiskFile, io_error};
+pub use self::memory::DiskMemory;
+pub use self::mirror::DiskMirror;
+pub use self::overlay::DiskOverlay;
+pub use self::partition::{DiskPartition, Partition, PartitionKind};
+pub use self::probe::{mounted, open_crypt, open_disk, open_mirror, open_partition};
+pub use self::qcow2::DiskQcow2;
+pub use self::trace::{DiskTrace, ReplayStats, TraceOp, TraceReader, TraceRecord, TRACE_DATA, TRACE_HASH, replay};
 
 mod cache;
+mod checksum;
+mod crypt;
+mod fault;
 mod file;
+mod memory;
+mod mirror;
+mod overlay;
+mod partition;
+mod probe;
+mod qcow2;
+mod trace;
 
 /// A disk
+///
+/// Every access names its position and takes `&self`, so one disk can be shared by several
+/// threads, for example through an `Arc`. Disks that keep state lock it internally.
 pub trait Disk {
-    fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize>;
-    fn write_at(&mut self, block: u64, buffer: &[u8]) -> Result<usize>;
-    fn size(&mut self) -> Result<u64>;
+    fn read_at(&self, block: u64, buffer: &mut [u8]) -> Result<usize>;
+    fn write_at(&self, block: u64, buffer: &[u8]) -> Result<usize>;
+    fn size(&self) -> Result<u64>;
+    /// Ensure all previous writes have reached stable storage
+    fn sync(&self) -> Result<()>;
+}
+
+impl<D: Disk + ?Sized> Disk for Box<D> {
+    fn read_at(&self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        (**self).read_at(block, buffer)
+    }
+
+    fn write_at(&self, block: u64, buffer: &[u8]) -> Result<usize> {
+        (**self).write_at(block, buffer)
+    }
+
+    fn size(&self) -> Result<u64> {
+        (**self).size()
+    }
+
+    fn sync(&self) -> Result<()> {
+        (**self).sync()
+    }
+}
+
+impl<D: Disk + ?Sized> Disk for &D {
+    fn read_at(&self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        (**self).read_at(block, buffer)
+    }
+
+    fn write_at(&self, block: u64, buffer: &[u8]) -> Result<usize> {
+        (**self).write_at(block, buffer)
+    }
+
+    fn size(&self) -> Result<u64> {
+        (**self).size()
+    }
+
+    fn sync(&self) -> Result<()> {
+        (**self).sync()
+    }
+}
+
+impl<D: Disk + ?Sized> Disk for Arc<D> {
+    fn read_at(&self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        (**self).read_at(block, buffer)
+    }
+
+    fn write_at(&self, block: u64, buffer: &[u8]) -> Result<usize> {
+        (**self).write_at(block, buffer)
+    }
+
+    fn size(&self) -> Result<u64> {
+        (**self).size()
+    }
+
+    fn sync(&self) -> Result<()> {
+        (**self).sync()
+    }
 }
@@ -0,0 +1,295 @@
+use std::cmp;
+use std::sync::Mutex;
+use syscall::error::{Error, Result, EINVAL, EIO};
+
+use disk::Disk;
+
+const OVERLAY_SIGNATURE: &'static [u8; 8] = b"RFSDELTA";
+
+/// Bits in one block of the map
+const MAP_BITS: u64 = 512 * 8;
+
+fn read_u64(buf: &[u8]) -> u64 {
+    buf[..8].iter().enumerate().fold(0, |value, (i, &b)| value | (b as u64) << (i * 8))
+}
+
+fn write_u64(buf: &mut [u8], value: u64) {
+    for (i, b) in buf[..8].iter_mut().enumerate() {
+        *b = (value >> (i * 8)) as u8;
+    }
+}
+
+fn read_exact<D: Disk>(disk: &D, block: u64, buffer: &mut [u8]) -> Result<()> {
+    if disk.read_at(block, buffer)? == buffer.len() {
+        Ok(())
+    } else {
+        Err(Error::new(EIO))
+    }
+}
+
+fn write_all<D: Disk>(disk: &D, block: u64, buffer: &[u8]) -> Result<()> {
+    if disk.write_at(block, buffer)? == buffer.len() {
+        Ok(())
+    } else {
+        Err(Error::new(EIO))
+    }
+}
+
+/// A disk that reads from a base disk, and keeps every block written to it in a delta disk
+///
+/// The delta starts with a header block holding the signature and the size of the base, followed
+/// by a map with one bit for each block of the base, set if that block is in the delta. Changed
+/// blocks come after the map, in the same order as in the base, so a delta file stays sparse.
+pub struct DiskOverlay<B: Disk, D: Disk> {
+    base: B,
+    delta: D,
+    /// Size of the base, in bytes
+    size: u64,
+    map: Mutex<Vec<u8>>,
+}
+
+impl<B: Disk, D: Disk> DiskOverlay<B, D> {
+    /// Start an empty delta over `base`
+    pub fn create(base: B, delta: D) -> Result<DiskOverlay<B, D>> {
+        let size = base.size()?;
+        let map = vec![0; (DiskOverlay::<B, D>::map_blocks(size) * 512) as usize];
+
+        // The header goes last, so an interrupted create does not leave a valid delta
+        let mut header = [0; 512];
+        header[..8].copy_from_slice(OVERLAY_SIGNATURE);
+        write_u64(&mut header[8..], size);
+        write_all(&delta, 1, &map)?;
+        write_all(&delta, 0, &header)?;
+        delta.sync()?;
+
+        Ok(DiskOverlay {
+            base: base,
+            delta: delta,
+            size: size,
+            map: Mutex::new(map)
+        })
+    }
+
+    /// Open an existing delta over `base`, which must have the same size as when the delta was created
+    pub fn open(base: B, delta: D) -> Result<DiskOverlay<B, D>> {
+        let mut header = [0; 512];
+        read_exact(&delta, 0, &mut header)?;
+        if &header[..8] != OVERLAY_SIGNATURE {
+            return Err(Error::new(EINVAL));
+        }
+
+        let size = read_u64(&header[8..]);
+        if size != base.size()? {
+            return Err(Error::new(EINVAL));
+        }
+
+        let mut map = vec![0; (DiskOverlay::<B, D>::map_blocks(size) * 512) as usize];
+        read_exact(&delta, 1, &mut map)?;
+
+        Ok(DiskOverlay {
+            base: base,
+            delta: delta,
+            size: size,
+            map: Mutex::new(map)
+        })
+    }
+
+    fn map_blocks(size: u64) -> u64 {
+        ((size + 511)/512 + MAP_BITS - 1)/MAP_BITS
+    }
+
+    /// First block of the delta holding changed blocks
+    fn data_start(&self) -> u64 {
+        1 + DiskOverlay::<B, D>::map_blocks(self.size)
+    }
+
+    /// Whether `block` has been written since the delta was created or last emptied
+    pub fn changed(&self, block: u64) -> bool {
+        self.map.lock().unwrap()[(block/8) as usize] & 1 << (block % 8) != 0
+    }
+
+    /// Number of blocks in the delta
+    pub fn changed_blocks(&self) -> u64 {
+        self.map.lock().unwrap().iter().map(|b| b.count_ones() as u64).sum()
+    }
+
+    /// Mark `count` blocks starting at `block` as changed, and write the affected parts of the map
+    fn set_changed(&self, block: u64, count: u64) -> Result<()> {
+        let mut map = self.map.lock().unwrap();
+        for i in block..block + count {
+            map[(i/8) as usize] |= 1 << (i % 8);
+        }
+
+        let first = block/MAP_BITS;
+        let last = (block + count - 1)/MAP_BITS;
+        let start = (first * 512) as usize;
+        let end = ((last + 1) * 512) as usize;
+        write_all(&self.delta, 1 + first, &map[start..end])
+    }
+
+    /// Write every changed block to the base, then empty the delta
+    pub fn commit(&mut self) -> Result<()> {
+        let data_start = self.data_start();
+        let mut buffer = [0; 512];
+        for (i, &byte) in self.map.lock().unwrap().iter().enumerate() {
+            if byte == 0 {
+                continue;
+            }
+
+            for bit in 0..8 {
+                if byte & 1 << bit != 0 {
+                    let block = i as u64 * 8 + bit;
+                    let len = self.clip(block, buffer.len());
+                    read_exact(&self.delta, data_start + block, &mut buffer[..len])?;
+                    write_all(&self.base, block, &buffer[..len])?;
+                }
+            }
+        }
+        self.base.sync()?;
+
+        self.discard()
+    }
+
+    /// Empty the delta, dropping every change
+    ///
+    /// Space used by the changed blocks is not released, delete the delta to reclaim it.
+    pub fn discard(&mut self) -> Result<()> {
+        let map = self.map.get_mut().unwrap();
+        for b in map.iter_mut() {
+            *b = 0;
+        }
+        write_all(&self.delta, 1, map)?;
+        self.delta.sync()
+    }
+
+    pub fn into_inner(self) -> (B, D) {
+        (self.base, self.delta)
+    }
+
+    /// Length of an access at `block` after clipping it to the end of the base
+    fn clip(&self, block: u64, len: usize) -> usize {
+        let offset = block * 512;
+        if offset >= self.size {
+            0
+        } else {
+            cmp::min(len as u64, self.size - offset) as usize
+        }
+    }
+}
+
+impl<B: Disk, D: Disk> Disk for DiskOverlay<B, D> {
+    fn read_at(&self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let len = self.clip(block, buffer.len());
+        let blocks = (len as u64 + 511)/512;
+        let data_start = self.data_start();
+
+        // Read runs of blocks that come from the same disk
+        let mut i = 0;
+        while i < blocks {
+            let changed = self.changed(block + i);
+            let mut j = i + 1;
+            while j < blocks && self.changed(block + j) == changed {
+                j += 1;
+            }
+
+            let run = &mut buffer[(i * 512) as usize .. cmp::min(j * 512, len as u64) as usize];
+            if changed {
+                read_exact(&self.delta, data_start + block + i, run)?;
+            } else {
+                read_exact(&self.base, block + i, run)?;
+            }
+
+            i = j;
+        }
+
+        Ok(len)
+    }
+
+    fn write_at(&self, block: u64, buffer: &[u8]) -> Result<usize> {
+        let len = self.clip(block, buffer.len());
+        if len == 0 {
+            return Ok(0);
+        }
+
+        let data_start = self.data_start();
+        let whole = len/512 * 512;
+        if whole > 0 {
+            write_all(&self.delta, data_start + block, &buffer[..whole])?;
+        }
+
+        // A partial block is merged with its current contents
+        if len > whole {
+            let last = block + (whole/512) as u64;
+            let sector_len = self.clip(last, 512);
+            let mut sector = [0; 512];
+            self.read_at(last, &mut sector[..sector_len])?;
+            sector[..len - whole].copy_from_slice(&buffer[whole..len]);
+            write_all(&self.delta, data_start + last, &sector[..sector_len])?;
+        }
+
+        // The data is synced before the map is written, so after a crash the map never points
+        // at blocks that were not written. Blocks already in the delta need neither.
+        let count = (len as u64 + 511)/512;
+        if (block..block + count).any(|i| ! self.changed(i)) {
+            self.delta.sync()?;
+            self.set_changed(block, count)?;
+        }
+
+        Ok(len)
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.size)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.delta.sync()
+    }
+}
+
+#[test]
+fn overlay_test() {
+    use disk::DiskMemory;
+    use filesystem::FileSystem;
+    use node::Node;
+
+    let mut base = FileSystem::create(DiskMemory::new(1024 * 1024), 0, 0).unwrap().disk;
+    let original = base.as_slice().to_vec();
+
+    let overlay = DiskOverlay::create(base, DiskMemory::new(0)).unwrap();
+    let fs = FileSystem::open(overlay).unwrap();
+    let root = fs.header().1.root;
+    fs.create_node(Node::MODE_FILE | 0o644, "test", root, 0, 0).unwrap();
+    fs.sync().unwrap();
+
+    // The base is untouched, and the delta can be opened again
+    let (mut base, delta) = fs.disk.into_inner();
+    assert!(base.as_slice() == &original[..]);
+    let overlay = DiskOverlay::open(base, delta).unwrap();
+    assert!(overlay.changed_blocks() > 0);
+
+    // Partial block writes keep the rest of the block
+    let mut block = [0; 512];
+    overlay.read_at(0, &mut block).unwrap();
+    overlay.write_at(0, &[0xFF; 16]).unwrap();
+    let mut changed = [0; 512];
+    overlay.read_at(0, &mut changed).unwrap();
+    assert_eq!(&changed[..16], &[0xFF; 16]);
+    assert_eq!(&changed[16..], &block[16..]);
+    overlay.write_at(0, &block).unwrap();
+
+    let mut fs = FileSystem::open(overlay).unwrap();
+    assert!(fs.find_node("test", root).is_ok());
+
+    fs.disk.discard().unwrap();
+    assert_eq!(fs.disk.changed_blocks(), 0);
+    let mut fs = FileSystem::open(fs.disk).unwrap();
+    assert!(fs.find_node("test", root).is_err());
+
+    fs.create_node(Node::MODE_FILE | 0o644, "test", root, 0, 0).unwrap();
+    fs.disk.commit().unwrap();
+    assert_eq!(fs.disk.changed_blocks(), 0);
+    let (base, _delta) = fs.disk.into_inner();
+    let fs = FileSystem::open(base).unwrap();
+    assert!(fs.find_node("test", root).is_ok());
+}
@@ -0,0 +1,299 @@
+use std::cmp;
+use syscall::error::{Error, Result, ENOENT};
+
+use disk::{Disk, DiskChecksum, DiskCrypt, DiskMirror};
+use header::Header;
+
+fn read_u32(buf: &[u8]) -> u32 {
+    (buf[0] as u32) | (buf[1] as u32) << 8 | (buf[2] as u32) << 16 | (buf[3] as u32) << 24
+}
+
+fn read_u64(buf: &[u8]) -> u64 {
+    (read_u32(buf) as u64) | (read_u32(&buf[4..]) as u64) << 32
+}
+
+/// The type of a partition table entry
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PartitionKind {
+    /// MBR partition type byte
+    Mbr(u8),
+    /// GPT partition type GUID, in on-disk byte order
+    Gpt([u8; 16]),
+}
+
+/// An entry in a partition table
+#[derive(Clone, Copy, Debug)]
+pub struct Partition {
+    /// Partition number, starting at 1, in table order
+    pub number: usize,
+    /// First block of the partition
+    pub start: u64,
+    /// Size of the partition, in 512-byte blocks
+    pub size: u64,
+    pub kind: PartitionKind,
+}
+
+impl Partition {
+    /// GPT partition type GUID for RedoxFS, 523efe7a-7a5f-4ac5-9a21-6b3a55c3e7f1, in on-disk byte order
+    pub const REDOXFS_GUID: [u8; 16] = [
+        0x7a, 0xfe, 0x3e, 0x52, 0x5f, 0x7a, 0xc5, 0x4a,
+        0x9a, 0x21, 0x6b, 0x3a, 0x55, 0xc3, 0xe7, 0xf1
+    ];
+
+    const MBR_GPT_PROTECTIVE: u8 = 0xEE;
+
+    /// Read the partition table of a disk, using the GPT if there is one and the MBR otherwise
+    ///
+    /// Logical partitions inside MBR extended partitions are not listed.
+    pub fn table<D: Disk>(disk: &D) -> Result<Vec<Partition>> {
+        let mut partitions = Vec::new();
+
+        let mut mbr = [0; 512];
+        disk.read_at(0, &mut mbr)?;
+        if mbr[510] != 0x55 || mbr[511] != 0xAA {
+            return Ok(partitions);
+        }
+
+        let mut protective = false;
+        for i in 0..4 {
+            let entry = &mbr[446 + i * 16 .. 446 + (i + 1) * 16];
+            let kind = entry[4];
+            let start = read_u32(&entry[8..]) as u64;
+            let size = read_u32(&entry[12..]) as u64;
+            if kind == Partition::MBR_GPT_PROTECTIVE {
+                protective = true;
+            } else if kind != 0 && size > 0 {
+                partitions.push(Partition {
+                    number: i + 1,
+                    start: start,
+                    size: size,
+                    kind: PartitionKind::Mbr(kind),
+                });
+            }
+        }
+
+        if protective {
+            partitions.clear();
+            Partition::gpt(disk, &mut partitions)?;
+        }
+
+        Ok(partitions)
+    }
+
+    fn gpt<D: Disk>(disk: &D, partitions: &mut Vec<Partition>) -> Result<()> {
+        let mut header = [0; 512];
+        disk.read_at(1, &mut header)?;
+        if &header[..8] != b"EFI PART" {
+            return Ok(());
+        }
+
+        let entries_block = read_u64(&header[72..]);
+        let entries = read_u32(&header[80..]) as usize;
+        let entry_size = read_u32(&header[84..]) as usize;
+        if entry_size < 128 || entries * entry_size > 1024 * 1024 {
+            return Ok(());
+        }
+
+        let mut table = vec![0; ((entries * entry_size + 511)/512) * 512];
+        disk.read_at(entries_block, &mut table)?;
+
+        for i in 0..entries {
+            let entry = &table[i * entry_size .. (i + 1) * entry_size];
+
+            let mut guid = [0; 16];
+            guid.copy_from_slice(&entry[..16]);
+            if guid == [0; 16] {
+                continue;
+            }
+
+            let first = read_u64(&entry[32..]);
+            let last = read_u64(&entry[40..]);
+            if last >= first {
+                partitions.push(Partition {
+                    number: i + 1,
+                    start: first,
+                    size: last - first + 1,
+                    kind: PartitionKind::Gpt(guid),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Find the partitions of a disk that contain RedoxFS
+    ///
+    /// GPT partitions are matched by type GUID, MBR partitions by looking for a valid header.
+    pub fn find_redoxfs<D: Disk>(disk: &D) -> Result<Vec<Partition>> {
+        let mut partitions = Vec::new();
+        for partition in Partition::table(disk)? {
+            let redoxfs = match partition.kind {
+                PartitionKind::Gpt(guid) => guid == Partition::REDOXFS_GUID,
+                PartitionKind::Mbr(_) => {
+                    // The filesystem may be below checksums, a mirror or encryption, which
+                    // each start with their own signature
+                    let inner = DiskPartition::new(disk, partition.start, partition.size);
+                    let mut header = Header::default();
+                    inner.read_at(0, &mut header)?;
+                    header.valid() || DiskChecksum::probe(&inner)? || DiskMirror::probe(&inner)? || DiskCrypt::probe(&inner)?
+                }
+            };
+
+            if redoxfs {
+                partitions.push(partition);
+            }
+        }
+        Ok(partitions)
+    }
+}
+
+/// A range of blocks of another disk
+pub struct DiskPartition<T: Disk> {
+    inner: T,
+    start: u64,
+    size: u64,
+}
+
+impl<T: Disk> DiskPartition<T> {
+    /// Expose `size` blocks of `inner`, starting at block `start`
+    pub fn new(inner: T, start: u64, size: u64) -> DiskPartition<T> {
+        DiskPartition {
+            inner: inner,
+            start: start,
+            size: size
+        }
+    }
+
+    /// Open partition `number` of a disk
+    ///
+    /// If `number` is `None`, the first RedoxFS partition is used, or the whole disk if none is found.
+    pub fn open(inner: T, number: Option<usize>) -> Result<DiskPartition<T>> {
+        let partition = match number {
+            Some(number) => match Partition::table(&inner)?.into_iter().find(|partition| partition.number == number) {
+                Some(partition) => Some(partition),
+                None => return Err(Error::new(ENOENT))
+            },
+            None => Partition::find_redoxfs(&inner)?.into_iter().next()
+        };
+
+        match partition {
+            Some(partition) => Ok(DiskPartition::new(inner, partition.start, partition.size)),
+            None => {
+                let size = inner.size()?/512;
+                Ok(DiskPartition::new(inner, 0, size))
+            }
+        }
+    }
+
+    /// Length of an access at `block` after clipping it to the end of the partition
+    fn clip(&self, block: u64, len: usize) -> usize {
+        if block >= self.size {
+            0
+        } else {
+            cmp::min(len as u64, (self.size - block) * 512) as usize
+        }
+    }
+}
+
+impl<T: Disk> Disk for DiskPartition<T> {
+    fn read_at(&self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let len = self.clip(block, buffer.len());
+        if len > 0 {
+            self.inner.read_at(self.start + block, &mut buffer[..len])
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn write_at(&self, block: u64, buffer: &[u8]) -> Result<usize> {
+        let len = self.clip(block, buffer.len());
+        if len > 0 {
+            self.inner.write_at(self.start + block, &buffer[..len])
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.size * 512)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.inner.sync()
+    }
+}
+
+#[test]
+fn partition_gpt_test() {
+    use disk::DiskMemory;
+    use filesystem::FileSystem;
+
+    let disk = DiskMemory::new(4096 * 512);
+
+    let mut mbr = [0; 512];
+    mbr[446 + 4] = 0xEE;
+    mbr[446 + 8] = 1;
+    mbr[446 + 12] = 0xFF;
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+    disk.write_at(0, &mbr).unwrap();
+
+    let mut gpt = [0; 512];
+    gpt[..8].copy_from_slice(b"EFI PART");
+    gpt[72] = 2; // Entries at block 2
+    gpt[80] = 4; // 4 entries
+    gpt[84] = 128; // 128 bytes each
+    disk.write_at(1, &gpt).unwrap();
+
+    let mut entries = [0; 512];
+    // Entry 2: RedoxFS from block 2048 to 4095
+    entries[128 .. 144].copy_from_slice(&Partition::REDOXFS_GUID);
+    entries[128 + 16] = 1;
+    entries[128 + 33] = 0x08;
+    entries[128 + 40] = 0xFF;
+    entries[128 + 41] = 0x0F;
+    disk.write_at(2, &entries).unwrap();
+
+    let partitions = Partition::find_redoxfs(&disk).unwrap();
+    assert_eq!(partitions.len(), 1);
+    assert_eq!(partitions[0].number, 2);
+    assert_eq!(partitions[0].start, 2048);
+    assert_eq!(partitions[0].size, 2048);
+
+    let fs = FileSystem::create(DiskPartition::open(disk, None).unwrap(), 0, 0).unwrap();
+    let disk = fs.disk.inner;
+    let mut header = Header::default();
+    disk.read_at(2048, &mut header).unwrap();
+    assert!(header.valid());
+    assert_eq!({ header.size }, 2048 * 512);
+}
+
+#[test]
+fn partition_mbr_test() {
+    use disk::DiskMemory;
+    use filesystem::FileSystem;
+
+    let disk = DiskMemory::new(4096 * 512);
+
+    // Partition 1 from block 1 to 2047, partition 2 from block 2048 to 4095
+    let mut mbr = [0; 512];
+    mbr[446 + 4] = 0x83;
+    mbr[446 + 8] = 1;
+    mbr[446 + 12] = 0xFF;
+    mbr[446 + 13] = 0x07;
+    mbr[446 + 16 + 4] = 0x83;
+    mbr[446 + 16 + 9] = 0x08;
+    mbr[446 + 16 + 13] = 0x08;
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+    disk.write_at(0, &mbr).unwrap();
+    assert!(Partition::find_redoxfs(&disk).unwrap().is_empty());
+
+    // A filesystem below checksums is found by the checksum signature
+    let checksum = DiskChecksum::create(DiskPartition::open(&disk, Some(2)).unwrap()).unwrap();
+    FileSystem::create(checksum, 0, 0).unwrap();
+    let partitions = Partition::find_redoxfs(&disk).unwrap();
+    assert_eq!(partitions.len(), 1);
+    assert_eq!(partitions[0].number, 2);
+    assert_eq!(partitions[0].start, 2048);
+}
@@ -0,0 +1,95 @@
+use syscall::error::Result;
+
+use super::{Disk, DiskChecksum, DiskCrypt, DiskMirror, DiskPartition, DiskQcow2};
+
+/// The whole disk that a partition belongs to, according to sysfs
+#[cfg(target_os = "linux")]
+fn parent_disk(device: &::std::path::Path) -> Option<::std::path::PathBuf> {
+    use std::fs;
+    use std::path::Path;
+
+    let name = device.file_name()?;
+    let sys = Path::new("/sys/class/block").join(name);
+    if ! sys.join("partition").exists() {
+        return None;
+    }
+
+    let parent = fs::canonicalize(&sys).ok()?.parent()?.file_name()?.to_owned();
+    fs::canonicalize(Path::new("/dev").join(parent)).ok()
+}
+
+/// Check if a path, one of its partitions or the disk it is a partition of is mounted, according
+/// to /proc/mounts
+///
+/// Images mounted by redoxfs are not listed there by path, they are locked by `DiskFile` instead.
+#[cfg(target_os = "linux")]
+pub fn mounted(path: &str) -> bool {
+    use std::fs;
+    use std::io::Read;
+
+    let path = match fs::canonicalize(path) {
+        Ok(path) => path,
+        Err(_) => return false
+    };
+    let path_parent = parent_disk(&path);
+
+    let mut mounts = String::new();
+    if let Ok(mut file) = fs::File::open("/proc/mounts") {
+        let _ = file.read_to_string(&mut mounts);
+    }
+
+    mounts.lines().any(|line| {
+        let device = line.split(' ').next().unwrap_or("").replace("\\040", " ");
+        fs::canonicalize(device).ok().map_or(false, |device| {
+            device == path
+                || parent_disk(&device).map_or(false, |parent| parent == path)
+                || path_parent.as_ref().map_or(false, |parent| *parent == device)
+        })
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn mounted(_path: &str) -> bool {
+    false
+}
+
+/// Open the layers of one disk below the checksums: a qcow2 image and a partition
+pub fn open_partition<D: Disk + Send + Sync + 'static>(disk: D, partition: Option<usize>) -> Result<DiskPartition<Box<dyn Disk + Send + Sync>>> {
+    let disk = if DiskQcow2::probe(&disk)? {
+        Box::new(DiskQcow2::open(disk)?) as Box<dyn Disk + Send + Sync>
+    } else {
+        Box::new(disk) as Box<dyn Disk + Send + Sync>
+    };
+
+    DiskPartition::open(disk, partition)
+}
+
+/// Open the layers that belong to one disk: a qcow2 image, a partition and checksums
+///
+/// Each disk of a mirror has its own, so a bad block can be repaired from another.
+pub fn open_disk<D: Disk + Send + Sync + 'static>(disk: D, partition: Option<usize>) -> Result<Box<dyn Disk + Send + Sync>> {
+    let disk = open_partition(disk, partition)?;
+    if DiskChecksum::probe(&disk)? {
+        Ok(Box::new(DiskChecksum::open(disk)?))
+    } else {
+        Ok(Box::new(disk))
+    }
+}
+
+/// Open disks from `open_disk` as a mirror, unless there is a single disk that is not a member of one
+pub fn open_mirror(mut disks: Vec<Box<dyn Disk + Send + Sync>>) -> Result<Box<dyn Disk + Send + Sync>> {
+    if disks.len() == 1 && ! DiskMirror::probe(&disks[0])? {
+        Ok(disks.remove(0))
+    } else {
+        Ok(Box::new(DiskMirror::open(disks)?))
+    }
+}
+
+/// Unlock an encrypted disk, calling `passphrase` only if it is encrypted
+pub fn open_crypt<F: FnOnce() -> Vec<u8>>(disk: Box<dyn Disk + Send + Sync>, passphrase: F) -> Result<Box<dyn Disk + Send + Sync>> {
+    if DiskCrypt::probe(&disk)? {
+        Ok(Box::new(DiskCrypt::open(disk, &passphrase())?))
+    } else {
+        Ok(disk)
+    }
+}
@@ -0,0 +1,256 @@
+//! A small decoder for raw deflate streams (RFC 1951), as used by qcow2 compressed clusters
+
+use syscall::error::{Error, Result, EIO};
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13
+];
+/// Order in which code length code lengths are stored
+const CLEN_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+fn corrupt() -> Error {
+    Error::new(EIO)
+}
+
+struct Input<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bit: u32,
+}
+
+impl<'a> Input<'a> {
+    fn bits(&mut self, count: u32) -> Result<u32> {
+        let mut value = 0;
+        for i in 0..count {
+            if self.pos >= self.data.len() {
+                return Err(corrupt());
+            }
+            value |= ((self.data[self.pos] >> self.bit) as u32 & 1) << i;
+            self.bit += 1;
+            if self.bit == 8 {
+                self.bit = 0;
+                self.pos += 1;
+            }
+        }
+        Ok(value)
+    }
+
+    fn align(&mut self) {
+        if self.bit > 0 {
+            self.bit = 0;
+            self.pos += 1;
+        }
+    }
+}
+
+/// A canonical Huffman code, stored as the number of codes of each length and the symbols in code order
+struct Huffman {
+    count: [u16; 16],
+    symbol: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Huffman {
+        let mut count = [0; 16];
+        for &length in lengths.iter() {
+            count[length as usize] += 1;
+        }
+        count[0] = 0;
+
+        let mut offset = [0; 16];
+        for i in 1..15 {
+            offset[i + 1] = offset[i] + count[i];
+        }
+
+        let mut symbol = vec![0; lengths.len()];
+        for (i, &length) in lengths.iter().enumerate() {
+            if length != 0 {
+                symbol[offset[length as usize] as usize] = i as u16;
+                offset[length as usize] += 1;
+            }
+        }
+
+        Huffman {
+            count: count,
+            symbol: symbol
+        }
+    }
+
+    fn decode(&self, input: &mut Input) -> Result<u16> {
+        let mut code: i32 = 0;
+        let mut first: i32 = 0;
+        let mut index: i32 = 0;
+        for length in 1..16 {
+            code |= input.bits(1)? as i32;
+            let count = self.count[length] as i32;
+            if code - first < count {
+                return Ok(self.symbol[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(corrupt())
+    }
+}
+
+fn fixed() -> (Huffman, Huffman) {
+    let mut lengths = [0; 288];
+    for (i, length) in lengths.iter_mut().enumerate() {
+        *length = match i {
+            0 ..= 143 => 8,
+            144 ..= 255 => 9,
+            256 ..= 279 => 7,
+            _ => 8
+        };
+    }
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+fn dynamic(input: &mut Input) -> Result<(Huffman, Huffman)> {
+    let literals = input.bits(5)? as usize + 257;
+    let distances = input.bits(5)? as usize + 1;
+    let code_lengths = input.bits(4)? as usize + 4;
+
+    let mut lengths = [0; 19];
+    for &i in CLEN_ORDER[..code_lengths].iter() {
+        lengths[i] = input.bits(3)? as u8;
+    }
+    let code = Huffman::new(&lengths);
+
+    let mut lengths = vec![0; literals + distances];
+    let mut i = 0;
+    while i < lengths.len() {
+        let symbol = code.decode(input)?;
+        let (value, repeat) = match symbol {
+            0 ..= 15 => (symbol as u8, 1),
+            16 => match i.checked_sub(1) {
+                Some(previous) => (lengths[previous], 3 + input.bits(2)? as usize),
+                None => return Err(corrupt())
+            },
+            17 => (0, 3 + input.bits(3)? as usize),
+            _ => (0, 11 + input.bits(7)? as usize)
+        };
+        if i + repeat > lengths.len() {
+            return Err(corrupt());
+        }
+        for length in lengths[i..i + repeat].iter_mut() {
+            *length = value;
+        }
+        i += repeat;
+    }
+
+    Ok((Huffman::new(&lengths[..literals]), Huffman::new(&lengths[literals..])))
+}
+
+/// Decompress a raw deflate stream, stopping at the last block or after `limit` bytes
+pub fn inflate(data: &[u8], limit: usize) -> Result<Vec<u8>> {
+    let mut input = Input {
+        data: data,
+        pos: 0,
+        bit: 0
+    };
+    let mut output = Vec::with_capacity(limit);
+
+    loop {
+        let last = input.bits(1)? == 1;
+        match input.bits(2)? {
+            0 => {
+                input.align();
+                if input.pos + 4 > data.len() {
+                    return Err(corrupt());
+                }
+                let len = data[input.pos] as usize | (data[input.pos + 1] as usize) << 8;
+                input.pos += 4;
+                if input.pos + len > data.len() {
+                    return Err(corrupt());
+                }
+                output.extend_from_slice(&data[input.pos..input.pos + len]);
+                input.pos += len;
+            },
+            kind @ 1 ..= 2 => {
+                let (literal, distance) = if kind == 1 {
+                    fixed()
+                } else {
+                    dynamic(&mut input)?
+                };
+
+                loop {
+                    let symbol = literal.decode(&mut input)? as usize;
+                    if symbol < 256 {
+                        output.push(symbol as u8);
+                    } else if symbol == 256 {
+                        break;
+                    } else if symbol - 257 < LENGTH_BASE.len() {
+                        let symbol = symbol - 257;
+                        let length = LENGTH_BASE[symbol] as usize + input.bits(LENGTH_EXTRA[symbol] as u32)? as usize;
+                        let symbol = distance.decode(&mut input)? as usize;
+                        if symbol >= DIST_BASE.len() {
+                            return Err(corrupt());
+                        }
+                        let dist = DIST_BASE[symbol] as usize + input.bits(DIST_EXTRA[symbol] as u32)? as usize;
+                        if dist > output.len() {
+                            return Err(corrupt());
+                        }
+                        for _ in 0..length {
+                            let b = output[output.len() - dist];
+                            output.push(b);
+                        }
+                    } else {
+                        return Err(corrupt());
+                    }
+
+                    if output.len() >= limit {
+                        break;
+                    }
+                }
+            },
+            _ => return Err(corrupt())
+        }
+
+        if last || output.len() >= limit {
+            break;
+        }
+    }
+
+    output.truncate(limit);
+    Ok(output)
+}
+
+#[test]
+fn inflate_test() {
+    // Stored block
+    let stored = [0x01, 0x07, 0x00, 0xf8, 0xff, 0x52, 0x65, 0x64, 0x6f, 0x78, 0x46, 0x53];
+    assert_eq!(inflate(&stored, 4096).unwrap(), b"RedoxFS");
+
+    // Fixed Huffman codes with back references
+    let fixed = [
+        0x2b, 0xc9, 0x48, 0x55, 0x28, 0x2c, 0xcd, 0x4c, 0xce, 0x56, 0x48, 0x2a, 0xca, 0x2f, 0xcf, 0x53,
+        0x48, 0xcb, 0xaf, 0x50, 0xc8, 0x2a, 0xcd, 0x2d, 0x28, 0x56, 0xc8, 0x2f, 0x4b, 0x2d, 0x52, 0x28,
+        0x01, 0x4a, 0xe7, 0x24, 0x56, 0x55, 0x2a, 0xa4, 0xe4, 0xa7, 0xeb, 0x80, 0x79, 0xa3, 0x8a, 0x47,
+        0x15, 0x8f, 0x2a, 0x1e, 0x55, 0x3c, 0xaa, 0x78, 0x54, 0xf1, 0x30, 0x52, 0x0c, 0x00
+    ];
+    let text = b"the quick brown fox jumps over the lazy dog, ";
+    let output = inflate(&fixed, 4096).unwrap();
+    assert_eq!(output.len(), text.len() * 40);
+    for chunk in output.chunks(text.len()) {
+        assert_eq!(chunk, &text[..]);
+    }
+
+    // Output stops at the limit, and truncated input is an error
+    assert_eq!(inflate(&fixed, 10).unwrap(), &text[..10]);
+    assert!(inflate(&fixed[..20], 4096).is_err());
+}
@@ -0,0 +1,625 @@
+use std::cmp;
+use std::sync::Mutex;
+use syscall::error::{Error, Result, EINVAL, EIO, ENOSPC, EOPNOTSUPP, EROFS};
+
+use disk::Disk;
+
+use self::inflate::inflate;
+
+mod inflate;
+
+const QCOW2_MAGIC: u64 = 0x514649fb;
+
+/// Bits 9 to 55 of table entries hold the offset of a cluster
+const OFFSET_MASK: u64 = 0x00ff_ffff_ffff_fe00;
+/// The cluster is used only by this table entry, and can be written in place
+const COPIED: u64 = 1 << 63;
+const COMPRESSED: u64 = 1 << 62;
+/// The cluster reads as zeros, version 3 only
+const ZERO: u64 = 1;
+/// Incompatible feature of version 3: the image was not closed cleanly, and its refcounts may be
+/// wrong
+const INCOMPAT_DIRTY: u64 = 1;
+
+fn read_be(buf: &[u8]) -> u64 {
+    buf.iter().fold(0, |value, &b| value << 8 | b as u64)
+}
+
+fn write_be(buf: &mut [u8], value: u64) {
+    let len = buf.len();
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = (value >> ((len - 1 - i) * 8)) as u8;
+    }
+}
+
+/// Where the data of a cluster is stored
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Cluster {
+    /// Reads as zeros, possibly with a cluster already allocated for it
+    Zero(Option<u64>),
+    /// Stored at the given offset
+    Normal(u64),
+    /// Compressed, at the given offset and with at most the given length in bytes
+    Compressed(u64, usize),
+}
+
+/// The state of an open qcow2 image
+struct Qcow2<T: Disk> {
+    inner: T,
+    version: u64,
+    cluster_bits: u32,
+    /// Size of the virtual disk, in bytes
+    size: u64,
+    l1_offset: u64,
+    l1: Vec<u64>,
+    /// The most recently used L2 table, and its offset
+    l2: Option<(u64, Vec<u64>)>,
+    refcount_order: u32,
+    refcount_table_offset: u64,
+    refcount_table: Vec<u64>,
+    /// The most recently decompressed cluster, and its offset
+    compressed: Option<(u64, Vec<u8>)>,
+    /// Offset of the next cluster to allocate, at the end of the image
+    end: u64,
+    writable: bool,
+    /// The dirty bit is set in the header, which happens before the first change
+    dirty: bool,
+}
+
+impl<T: Disk> Qcow2<T> {
+    fn open(inner: T) -> Result<Qcow2<T>> {
+        let mut header = [0; 512];
+        if inner.read_at(0, &mut header)? < 104 || read_be(&header[0..4]) != QCOW2_MAGIC {
+            return Err(Error::new(EINVAL));
+        }
+
+        let version = read_be(&header[4..8]);
+        let backing_file_offset = read_be(&header[8..16]);
+        let cluster_bits = read_be(&header[20..24]) as u32;
+        let size = read_be(&header[24..32]);
+        let crypt_method = read_be(&header[32..36]);
+        let l1_size = read_be(&header[36..40]);
+        let l1_offset = read_be(&header[40..48]);
+        let refcount_table_offset = read_be(&header[48..56]);
+        let refcount_table_clusters = read_be(&header[56..60]);
+        let snapshots = read_be(&header[60..64]);
+        let (incompatible, refcount_order) = match version {
+            2 => (0, 4),
+            3 => (read_be(&header[72..80]), read_be(&header[96..100]) as u32),
+            _ => return Err(Error::new(EOPNOTSUPP))
+        };
+
+        if backing_file_offset != 0 || crypt_method != 0 || incompatible & ! INCOMPAT_DIRTY != 0 {
+            return Err(Error::new(EOPNOTSUPP));
+        }
+
+        if ! (9..=21).contains(&cluster_bits) || refcount_order > 6 {
+            return Err(Error::new(EINVAL));
+        }
+
+        // Every cluster of the virtual disk must have an L1 entry, and the tables are kept in memory
+        let cluster_size = 1 << cluster_bits;
+        let l2_entries = cluster_size/8;
+        let covered = l1_size.checked_mul(l2_entries).and_then(|entries| entries.checked_mul(cluster_size));
+        let refcount_table_size = refcount_table_clusters.checked_mul(cluster_size);
+        match (covered, refcount_table_size) {
+            (Some(covered), Some(refcount_table_size)) if covered >= size && l1_size <= 4 * 1024 * 1024
+                && refcount_table_size <= 32 * 1024 * 1024 => (),
+            _ => return Err(Error::new(EINVAL))
+        }
+
+        let end = (inner.size()? + cluster_size - 1)/cluster_size * cluster_size;
+
+        let mut qcow2 = Qcow2 {
+            inner: inner,
+            version: version,
+            cluster_bits: cluster_bits,
+            size: size,
+            l1_offset: l1_offset,
+            l1: Vec::new(),
+            l2: None,
+            refcount_order: refcount_order,
+            refcount_table_offset: refcount_table_offset,
+            refcount_table: Vec::new(),
+            compressed: None,
+            end: end,
+            // Snapshots share clusters, and refcounts smaller than a byte are not worth handling. An
+            // image that was not closed cleanly needs its refcounts repaired, by qemu-img check -r
+            writable: snapshots == 0 && refcount_order >= 3 && incompatible & INCOMPAT_DIRTY == 0,
+            dirty: false,
+        };
+
+        qcow2.l1 = qcow2.read_table(l1_offset, l1_size as usize)?;
+        qcow2.refcount_table = qcow2.read_table(refcount_table_offset, (refcount_table_clusters * l2_entries) as usize)?;
+
+        Ok(qcow2)
+    }
+
+    fn cluster_size(&self) -> u64 {
+        1 << self.cluster_bits
+    }
+
+    /// Read bytes at any offset of the image, returning how many were read before its end
+    fn read_bytes(&mut self, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if offset % 512 == 0 {
+            return self.inner.read_at(offset/512, buf);
+        }
+
+        let skip = (offset % 512) as usize;
+        let mut sectors = vec![0; (skip + buf.len() + 511)/512 * 512];
+        let count = self.inner.read_at(offset/512, &mut sectors)?;
+        let count = cmp::min(count.saturating_sub(skip), buf.len());
+        buf[..count].copy_from_slice(&sectors[skip..skip + count]);
+        Ok(count)
+    }
+
+    fn read_exact_bytes(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        if self.read_bytes(offset, buf)? == buf.len() {
+            Ok(())
+        } else {
+            Err(Error::new(EIO))
+        }
+    }
+
+    /// Write bytes at any offset of the image, merging partial sectors with their old contents
+    fn write_bytes(&mut self, offset: u64, buf: &[u8]) -> Result<()> {
+        let count = if offset % 512 == 0 && buf.len() % 512 == 0 {
+            self.inner.write_at(offset/512, buf)?
+        } else {
+            let skip = (offset % 512) as usize;
+            let mut sectors = vec![0; (skip + buf.len() + 511)/512 * 512];
+            // Past the end of the image, the old contents are zeros
+            self.inner.read_at(offset/512, &mut sectors)?;
+            sectors[skip..skip + buf.len()].copy_from_slice(buf);
+            self.inner.write_at(offset/512, &sectors)?.saturating_sub(skip)
+        };
+
+        if count >= buf.len() {
+            Ok(())
+        } else {
+            Err(Error::new(EIO))
+        }
+    }
+
+    /// Set or clear the dirty bit of a version 3 image, with everything before it on the disk
+    fn set_dirty(&mut self, dirty: bool) -> Result<()> {
+        if self.version < 3 || self.dirty == dirty {
+            return Ok(());
+        }
+
+        self.inner.sync()?;
+        self.write_entry(72, if dirty { INCOMPAT_DIRTY } else { 0 })?;
+        self.inner.sync()?;
+        self.dirty = dirty;
+        Ok(())
+    }
+
+    fn read_table(&mut self, offset: u64, entries: usize) -> Result<Vec<u64>> {
+        let mut data = vec![0; entries * 8];
+        self.read_exact_bytes(offset, &mut data)?;
+        Ok(data.chunks(8).map(read_be).collect())
+    }
+
+    fn write_entry(&mut self, offset: u64, entry: u64) -> Result<()> {
+        let mut buf = [0; 8];
+        write_be(&mut buf, entry);
+        self.write_bytes(offset, &buf)
+    }
+
+    /// Take a cluster at the end of the image, without writing to it
+    fn allocate(&mut self) -> Result<u64> {
+        let offset = self.end;
+        self.end += self.cluster_size();
+        self.add_refcount(offset, 1)?;
+        Ok(offset)
+    }
+
+    fn add_refcount(&mut self, offset: u64, delta: i64) -> Result<()> {
+        let index = offset >> self.cluster_bits;
+        let per_block = (self.cluster_size() * 8) >> self.refcount_order;
+        let table_index = (index/per_block) as usize;
+        if table_index >= self.refcount_table.len() {
+            // Growing the refcount table would mean moving it
+            return Err(Error::new(ENOSPC));
+        }
+
+        if self.refcount_table[table_index] & OFFSET_MASK == 0 {
+            let block = self.end;
+            self.end += self.cluster_size();
+            let zeros = vec![0; self.cluster_size() as usize];
+            self.write_bytes(block, &zeros)?;
+
+            self.refcount_table[table_index] = block;
+            let entry_offset = self.refcount_table_offset + table_index as u64 * 8;
+            self.write_entry(entry_offset, block)?;
+
+            // The new block counts itself, or lands in another block that is allocated the same way
+            self.add_refcount(block, 1)?;
+        }
+
+        let block = self.refcount_table[table_index] & OFFSET_MASK;
+        let bytes = 1 << (self.refcount_order - 3);
+        let entry_offset = block + (index % per_block) * bytes;
+
+        let mut buf = [0; 8];
+        self.read_exact_bytes(entry_offset, &mut buf[..bytes as usize])?;
+        let refcount = read_be(&buf[..bytes as usize]) as i64 + delta;
+        if refcount < 0 || (bytes < 8 && refcount >= 1 << (bytes * 8)) {
+            return Err(Error::new(EIO));
+        }
+        write_be(&mut buf[..bytes as usize], refcount as u64);
+        self.write_bytes(entry_offset, &buf[..bytes as usize])
+    }
+
+    /// Load the L2 table at `offset` into the cache
+    fn load_l2(&mut self, offset: u64) -> Result<()> {
+        let cached = match self.l2 {
+            Some((cached_offset, _)) => cached_offset == offset,
+            None => false
+        };
+
+        if ! cached {
+            let entries = (self.cluster_size()/8) as usize;
+            let table = self.read_table(offset, entries)?;
+            self.l2 = Some((offset, table));
+        }
+
+        Ok(())
+    }
+
+    /// Find where a cluster of the virtual disk is stored
+    fn cluster(&mut self, cluster: u64) -> Result<Cluster> {
+        let l2_entries = self.cluster_size()/8;
+        let l1_index = (cluster/l2_entries) as usize;
+        let l2_index = (cluster % l2_entries) as usize;
+
+        let l2_offset = match self.l1.get(l1_index) {
+            Some(&entry) => entry & OFFSET_MASK,
+            None => return Err(Error::new(EIO))
+        };
+        if l2_offset == 0 {
+            return Ok(Cluster::Zero(None));
+        }
+
+        self.load_l2(l2_offset)?;
+        let entry = match self.l2 {
+            Some((_, ref table)) => table[l2_index],
+            None => return Err(Error::new(EIO))
+        };
+
+        if entry & COMPRESSED != 0 {
+            // The offset takes the low bits, and the number of additional sectors the rest
+            let offset_bits = 62 - (self.cluster_bits - 8);
+            let offset = entry & ((1 << offset_bits) - 1);
+            let sectors = (entry >> offset_bits) & ((1 << (self.cluster_bits - 8)) - 1);
+            Ok(Cluster::Compressed(offset, ((sectors + 1) * 512 - (offset % 512)) as usize))
+        } else {
+            let offset = entry & OFFSET_MASK;
+            if entry & ZERO != 0 {
+                Ok(Cluster::Zero(if offset != 0 { Some(offset) } else { None }))
+            } else if offset == 0 {
+                Ok(Cluster::Zero(None))
+            } else {
+                Ok(Cluster::Normal(offset))
+            }
+        }
+    }
+
+    /// Point a cluster of the virtual disk at a new entry, allocating an L2 table if needed
+    fn set_cluster(&mut self, cluster: u64, entry: u64) -> Result<()> {
+        let l2_entries = self.cluster_size()/8;
+        let l1_index = (cluster/l2_entries) as usize;
+        let l2_index = (cluster % l2_entries) as usize;
+
+        let mut l2_offset = self.l1[l1_index] & OFFSET_MASK;
+        if l2_offset == 0 {
+            l2_offset = self.allocate()?;
+            let zeros = vec![0; self.cluster_size() as usize];
+            self.write_bytes(l2_offset, &zeros)?;
+
+            self.l1[l1_index] = l2_offset | COPIED;
+            let l1_entry = self.l1_offset + l1_index as u64 * 8;
+            self.write_entry(l1_entry, l2_offset | COPIED)?;
+        }
+
+        self.load_l2(l2_offset)?;
+        if let Some((_, ref mut table)) = self.l2 {
+            table[l2_index] = entry;
+        }
+        self.write_entry(l2_offset + l2_index as u64 * 8, entry)
+    }
+
+    /// Read part of a cluster of the virtual disk
+    fn read_cluster(&mut self, cluster: Cluster, offset: usize, buf: &mut [u8]) -> Result<()> {
+        match cluster {
+            Cluster::Zero(_) => {
+                for b in buf.iter_mut() {
+                    *b = 0;
+                }
+                Ok(())
+            },
+            Cluster::Normal(host) => self.read_exact_bytes(host + offset as u64, buf),
+            Cluster::Compressed(host, len) => {
+                let cached = match self.compressed {
+                    Some((cached_host, _)) => cached_host == host,
+                    None => false
+                };
+
+                if ! cached {
+                    // Compressed data may end before its last sector, at the end of the image
+                    let mut data = vec![0; len];
+                    let count = self.read_bytes(host, &mut data)?;
+                    let cluster_size = self.cluster_size() as usize;
+                    let decompressed = inflate(&data[..count], cluster_size)?;
+                    if decompressed.len() != cluster_size {
+                        return Err(Error::new(EIO));
+                    }
+                    self.compressed = Some((host, decompressed));
+                }
+
+                if let Some((_, ref data)) = self.compressed {
+                    buf.copy_from_slice(&data[offset..offset + buf.len()]);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Write part of a cluster of the virtual disk
+    fn write_cluster(&mut self, cluster: u64, offset: usize, buf: &[u8]) -> Result<()> {
+        if ! self.writable {
+            return Err(Error::new(EROFS));
+        }
+        self.set_dirty(true)?;
+
+        let old = self.cluster(cluster)?;
+        if let Cluster::Normal(host) = old {
+            return self.write_bytes(host + offset as u64, buf);
+        }
+
+        // Anything else gets a whole uncompressed cluster, written before the table points at it
+        let mut data = vec![0; self.cluster_size() as usize];
+        if buf.len() < data.len() {
+            self.read_cluster(old, 0, &mut data)?;
+        }
+        data[offset..offset + buf.len()].copy_from_slice(buf);
+
+        let host = match old {
+            Cluster::Zero(Some(host)) => host,
+            _ => self.allocate()?
+        };
+        self.write_bytes(host, &data)?;
+        self.set_cluster(cluster, host | COPIED)?;
+
+        if let Cluster::Compressed(old_host, len) = old {
+            let cluster_size = self.cluster_size();
+            let first = old_host/cluster_size;
+            let last = (old_host + len as u64 - 1)/cluster_size;
+            for i in first..last + 1 {
+                self.add_refcount(i * cluster_size, -1)?;
+            }
+            self.compressed = None;
+        }
+
+        Ok(())
+    }
+
+    /// Length of an access at `block` after clipping it to the end of the virtual disk
+    fn clip(&self, block: u64, len: usize) -> usize {
+        let offset = block * 512;
+        if offset >= self.size {
+            0
+        } else {
+            cmp::min(len as u64, self.size - offset) as usize
+        }
+    }
+
+    fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let len = self.clip(block, buffer.len());
+        let cluster_size = self.cluster_size();
+
+        let mut done = 0;
+        while done < len {
+            let position = block * 512 + done as u64;
+            let offset = (position % cluster_size) as usize;
+            let count = cmp::min(cluster_size as usize - offset, len - done);
+            let cluster = self.cluster(position/cluster_size)?;
+            self.read_cluster(cluster, offset, &mut buffer[done..done + count])?;
+            done += count;
+        }
+
+        Ok(len)
+    }
+
+    fn write_at(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
+        let len = self.clip(block, buffer.len());
+        let cluster_size = self.cluster_size();
+
+        let mut done = 0;
+        while done < len {
+            let position = block * 512 + done as u64;
+            let offset = (position % cluster_size) as usize;
+            let count = cmp::min(cluster_size as usize - offset, len - done);
+            self.write_cluster(position/cluster_size, offset, &buffer[done..done + count])?;
+            done += count;
+        }
+
+        Ok(len)
+    }
+}
+
+/// A disk stored in a qcow2 image
+///
+/// Images with backing files, encryption, or incompatible features are not supported. Images with
+/// snapshots can only be read. Compressed clusters are decompressed on read, and stored
+/// uncompressed in a new cluster when written.
+///
+/// The dirty bit of a version 3 image is set before it is first changed, and cleared when it is
+/// dropped.
+pub struct DiskQcow2<T: Disk> {
+    /// Locked for every access, as reads load tables and decompress clusters, and only taken by
+    /// `into_inner`
+    image: Mutex<Option<Qcow2<T>>>,
+}
+
+impl<T: Disk> DiskQcow2<T> {
+    /// Check if a disk holds a qcow2 image
+    pub fn probe(disk: &T) -> Result<bool> {
+        let mut sector = [0; 512];
+        let count = disk.read_at(0, &mut sector)?;
+        Ok(count >= 4 && read_be(&sector[..4]) == QCOW2_MAGIC)
+    }
+
+    /// Open the qcow2 image stored on `inner`
+    pub fn open(inner: T) -> Result<DiskQcow2<T>> {
+        Qcow2::open(inner).map(|image| DiskQcow2 {
+            image: Mutex::new(Some(image))
+        })
+    }
+
+    /// Create an empty qcow2 image of `size` bytes on `inner`, with 64 KB clusters
+    pub fn create(inner: T, size: u64) -> Result<DiskQcow2<T>> {
+        let cluster_bits = 16;
+        let cluster_size = 1 << cluster_bits;
+        let l1_size = (size + cluster_size * cluster_size/8 - 1)/(cluster_size * cluster_size/8);
+        let l1_clusters = cmp::max(1, (l1_size * 8 + cluster_size - 1)/cluster_size);
+
+        // Header, refcount table, refcount block, then the L1 table
+        let clusters = 3 + l1_clusters;
+        let mut data = vec![0; (clusters * cluster_size) as usize];
+
+        let mut header = [0; 104];
+        write_be(&mut header[0..4], QCOW2_MAGIC);
+        write_be(&mut header[4..8], 3);
+        write_be(&mut header[20..24], cluster_bits);
+        write_be(&mut header[24..32], size);
+        write_be(&mut header[36..40], l1_size);
+        write_be(&mut header[40..48], 3 * cluster_size);
+        write_be(&mut header[48..56], cluster_size);
+        write_be(&mut header[56..60], 1);
+        write_be(&mut header[96..100], 4);
+        write_be(&mut header[100..104], 104);
+        data[..104].copy_from_slice(&header);
+
+        write_be(&mut data[cluster_size as usize .. cluster_size as usize + 8], 2 * cluster_size);
+        for i in 0..clusters as usize {
+            let entry = 2 * cluster_size as usize + i * 2;
+            write_be(&mut data[entry .. entry + 2], 1);
+        }
+
+        if inner.write_at(0, &data)? != data.len() {
+            return Err(Error::new(EIO));
+        }
+
+        DiskQcow2::open(inner)
+    }
+
+    pub fn into_inner(mut self) -> T {
+        let mut image = self.image.get_mut().unwrap().take().unwrap();
+        let _ = image.set_dirty(false);
+        image.inner
+    }
+
+    fn with<R, F: FnOnce(&mut Qcow2<T>) -> R>(&self, f: F) -> R {
+        f(self.image.lock().unwrap().as_mut().unwrap())
+    }
+}
+
+impl<T: Disk> Drop for DiskQcow2<T> {
+    fn drop(&mut self) {
+        if let Some(ref mut image) = *self.image.get_mut().unwrap() {
+            let _ = image.set_dirty(false);
+        }
+    }
+}
+
+impl<T: Disk> Disk for DiskQcow2<T> {
+    fn read_at(&self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        self.with(|image| image.read_at(block, buffer))
+    }
+
+    fn write_at(&self, block: u64, buffer: &[u8]) -> Result<usize> {
+        self.with(|image| image.write_at(block, buffer))
+    }
+
+    fn size(&self) -> Result<u64> {
+        Ok(self.with(|image| image.size))
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.with(|image| image.inner.sync())
+    }
+}
+
+#[test]
+fn qcow2_test() {
+    use disk::DiskMemory;
+    use filesystem::FileSystem;
+    use node::Node;
+
+    let qcow2 = DiskQcow2::create(DiskMemory::new(0), 64 * 1024 * 1024).unwrap();
+    let fs = FileSystem::create(qcow2, 0, 0).unwrap();
+    let root = fs.header().1.root;
+    let node = fs.create_node(Node::MODE_FILE | 0o644, "test", root, 0, 0).unwrap();
+    fs.write_node(node.0, 0, &[1; 100000], 0, 0).unwrap();
+
+    // The image is marked dirty while it is changed, and clean once it is closed
+    let mut features = [0; 8];
+    fs.disk.with(|image| image.read_exact_bytes(72, &mut features)).unwrap();
+    assert_eq!(read_be(&features), INCOMPAT_DIRTY);
+
+    // Only the clusters that were written take space
+    let mut inner = fs.disk.into_inner();
+    assert_eq!(read_be(&inner.as_slice()[72..80]), 0);
+    assert!(DiskQcow2::probe(&inner).unwrap());
+    assert!(inner.as_slice().len() < 1024 * 1024);
+
+    let fs = FileSystem::open(DiskQcow2::open(inner).unwrap()).unwrap();
+    let node = fs.find_node("test", root).unwrap();
+    let mut data = [0; 100000];
+    assert_eq!(fs.read_node(node.0, 0, &mut data).unwrap(), data.len());
+    assert!(data.iter().all(|&b| b == 1));
+
+    // "RedoxFS " repeated to fill a 64 KB cluster, as a raw deflate stream
+    let compressed = [
+        0xed, 0xc5, 0x31, 0x01, 0x00, 0x20, 0x08, 0x00, 0xb0, 0x2a, 0xa4, 0x31, 0x00, 0x54, 0xc0, 0x9b,
+        0x97, 0xf8, 0x06, 0x71, 0x7b, 0x96, 0xb7, 0x67, 0x4f, 0x45, 0xda, 0xb6, 0x6d, 0xdb, 0xb6, 0x6d,
+        0xdb, 0xb6, 0x6d, 0xdb, 0xb6, 0x6d, 0xdb, 0xb6, 0x6d, 0xdb, 0xb6, 0x6d, 0xdb, 0xb6, 0x6d, 0xdb,
+        0xb6, 0x6d, 0xdb, 0xb6, 0x6d, 0xdb, 0xb6, 0x6d, 0xdb, 0xb6, 0x6d, 0xdb, 0xb6, 0x6d, 0xdb, 0xb6,
+        0x6d, 0xdb, 0xb6, 0x6d, 0xdb, 0xb6, 0x6d, 0xdb, 0xb6, 0x6d, 0xdb, 0xb6, 0x6d, 0xdb, 0xb6, 0x6d,
+        0xdb, 0xb6, 0x6d, 0xdb, 0xb6, 0x6d, 0xdb, 0xb6, 0x6d, 0xdb, 0xb6, 0x6d, 0xdb, 0xb6, 0x6d, 0xdb,
+        0xb6, 0x6d, 0xdb, 0xb6, 0x6d, 0xdb, 0xb6, 0x6d, 0xdb, 0xb6, 0x6d, 0xdb, 0xb6, 0x6d, 0xdb, 0xb6,
+        0x6d, 0xdb, 0xb6, 0x6d, 0xdb, 0xb6, 0x6d, 0xdb, 0xb6, 0xbf, 0xfe, 0x01
+    ];
+
+    // Store it as cluster 100, starting partway into a sector
+    let mut qcow2 = fs.disk.image.lock().unwrap().take().unwrap();
+    let host = qcow2.allocate().unwrap() + 100;
+    qcow2.write_bytes(host, &compressed).unwrap();
+    let sectors = (host % 512 + compressed.len() as u64 + 511)/512 - 1;
+    qcow2.set_cluster(100, COMPRESSED | host | sectors << (62 - 8)).unwrap();
+
+    let cluster_block = 100 * 65536/512;
+    let mut block = [0; 512];
+    qcow2.read_at(cluster_block + 1, &mut block).unwrap();
+    for chunk in block.chunks(8) {
+        assert_eq!(chunk, b"RedoxFS ");
+    }
+
+    // Writing replaces it with an uncompressed cluster that keeps the rest of the data
+    qcow2.write_at(cluster_block, &[0; 512]).unwrap();
+    assert!(matches!(qcow2.cluster(100).unwrap(), Cluster::Normal(_)));
+    qcow2.read_at(cluster_block, &mut block).unwrap();
+    assert!(block.iter().all(|&b| b == 0));
+    qcow2.read_at(cluster_block + 127, &mut block).unwrap();
+    assert_eq!(&block[504..], b"RedoxFS ");
+
+    // Table sizes that overflow are rejected
+    let inner = qcow2.inner;
+    let mut header = [0; 512];
+    inner.read_at(0, &mut header).unwrap();
+    write_be(&mut header[20..24], 21);
+    write_be(&mut header[36..40], 0xffff_ffff);
+    inner.write_at(0, &header).unwrap();
+    assert_eq!(DiskQcow2::open(inner).err().map(|err| err.errno), Some(EINVAL));
+}
@@ -0,0 +1,340 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::sync::Mutex;
+use std::time::Instant;
+use syscall::error::{Error, Result, EINVAL};
+
+use disk::Disk;
+use super::file::io_error;
+
+/// Trace records include a hash of the data transferred
+pub const TRACE_HASH: u8 = 1;
+/// Trace records of writes include the data written
+pub const TRACE_DATA: u8 = 2;
+
+const TRACE_SIGNATURE: &'static [u8; 8] = b"RFSTRACE";
+
+/// Longest transfer a trace may hold, so a damaged trace cannot make replay allocate without bound
+const TRACE_MAX_LENGTH: u64 = 1 << 30;
+
+/// 64-bit FNV-1a hash
+fn hash(data: &[u8]) -> u64 {
+    let mut hash = 0xcbf29ce484222325;
+    for &b in data.iter() {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn write_u64<W: Write>(writer: &mut W, value: u64) -> io::Result<()> {
+    let mut bytes = [0; 8];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = (value >> (i * 8)) as u8;
+    }
+    writer.write_all(&bytes)
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes.iter().enumerate().fold(0, |value, (i, &b)| value | (b as u64) << (i * 8)))
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TraceOp {
+    Read,
+    Write,
+    Sync,
+}
+
+/// A single disk operation in a trace
+#[derive(Clone, Debug)]
+pub struct TraceRecord {
+    pub op: TraceOp,
+    pub block: u64,
+    /// Length of the transfer, in bytes
+    pub length: u64,
+    /// Time since the trace started, in nanoseconds
+    pub time: u64,
+    /// Hash of the data transferred, if the trace has `TRACE_HASH`
+    pub hash: Option<u64>,
+    /// Data written, if the trace has `TRACE_DATA`
+    pub data: Option<Vec<u8>>,
+}
+
+/// A disk that records every operation on another disk to a trace
+///
+/// The trace starts with the signature, the flags byte, and the disk size. Each record is then
+/// the operation byte, block, length and time, followed by the hash and data if enabled by the
+/// flags. All integers are 64-bit little endian. Each record is flushed to the writer once it is
+/// complete, so a trace keeps the operations leading up to a crash.
+pub struct DiskTrace<T: Disk, W: Write> {
+    inner: T,
+    writer: Mutex<W>,
+    flags: u8,
+    start: Instant,
+}
+
+impl<T: Disk> DiskTrace<T, BufWriter<File>> {
+    /// Record a trace of `inner` to a new file at `path`
+    pub fn create(inner: T, path: &str, flags: u8) -> Result<Self> {
+        let file = File::create(path).map_err(io_error)?;
+        DiskTrace::new(inner, BufWriter::new(file), flags)
+    }
+}
+
+impl<T: Disk, W: Write> DiskTrace<T, W> {
+    pub fn new(inner: T, mut writer: W, flags: u8) -> Result<Self> {
+        let size = inner.size()?;
+        writer.write_all(TRACE_SIGNATURE).map_err(io_error)?;
+        writer.write_all(&[flags]).map_err(io_error)?;
+        write_u64(&mut writer, size).map_err(io_error)?;
+
+        Ok(DiskTrace {
+            inner: inner,
+            writer: Mutex::new(writer),
+            flags: flags,
+            start: Instant::now(),
+        })
+    }
+
+    pub fn into_inner(self) -> (T, W) {
+        (self.inner, self.writer.into_inner().unwrap())
+    }
+
+    fn record(&self, op: TraceOp, block: u64, data: &[u8]) -> io::Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        let elapsed = self.start.elapsed();
+        let time = elapsed.as_secs() * 1000000000 + elapsed.subsec_nanos() as u64;
+
+        let op_byte = match op {
+            TraceOp::Read => 0,
+            TraceOp::Write => 1,
+            TraceOp::Sync => 2,
+        };
+
+        writer.write_all(&[op_byte])?;
+        write_u64(&mut *writer, block)?;
+        write_u64(&mut *writer, data.len() as u64)?;
+        write_u64(&mut *writer, time)?;
+        if self.flags & TRACE_HASH == TRACE_HASH {
+            write_u64(&mut *writer, hash(data))?;
+        }
+        if self.flags & TRACE_DATA == TRACE_DATA && op == TraceOp::Write {
+            writer.write_all(data)?;
+        }
+        writer.flush()
+    }
+}
+
+impl<T: Disk, W: Write> Disk for DiskTrace<T, W> {
+    fn read_at(&self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let count = self.inner.read_at(block, buffer)?;
+        self.record(TraceOp::Read, block, &buffer[..count]).map_err(io_error)?;
+        Ok(count)
+    }
+
+    fn write_at(&self, block: u64, buffer: &[u8]) -> Result<usize> {
+        let count = self.inner.write_at(block, buffer)?;
+        self.record(TraceOp::Write, block, &buffer[..count]).map_err(io_error)?;
+        Ok(count)
+    }
+
+    fn size(&self) -> Result<u64> {
+        self.inner.size()
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.inner.sync()?;
+        self.record(TraceOp::Sync, 0, &[]).map_err(io_error)
+    }
+}
+
+/// Reads the records of a trace written by `DiskTrace`
+pub struct TraceReader<R: Read> {
+    reader: R,
+    /// Flags the trace was recorded with
+    pub flags: u8,
+    /// Size of the traced disk, in bytes
+    pub size: u64,
+}
+
+impl TraceReader<BufReader<File>> {
+    pub fn open(path: &str) -> Result<Self> {
+        let file = File::open(path).map_err(io_error)?;
+        TraceReader::new(BufReader::new(file))
+    }
+}
+
+impl<R: Read> TraceReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut signature = [0; 8];
+        reader.read_exact(&mut signature).map_err(io_error)?;
+        if &signature != TRACE_SIGNATURE {
+            return Err(Error::new(EINVAL));
+        }
+
+        let mut flags = [0];
+        reader.read_exact(&mut flags).map_err(io_error)?;
+        let size = read_u64(&mut reader).map_err(io_error)?;
+
+        Ok(TraceReader {
+            reader: reader,
+            flags: flags[0],
+            size: size
+        })
+    }
+
+    /// Read the next record, or `None` at the end of the trace
+    pub fn next_record(&mut self) -> Result<Option<TraceRecord>> {
+        let mut op_byte = [0];
+        match self.reader.read(&mut op_byte) {
+            Ok(0) => return Ok(None),
+            Ok(_) => (),
+            Err(err) => return Err(io_error(err))
+        }
+
+        let op = match op_byte[0] {
+            0 => TraceOp::Read,
+            1 => TraceOp::Write,
+            2 => TraceOp::Sync,
+            _ => return Err(Error::new(EINVAL))
+        };
+
+        let block = read_u64(&mut self.reader).map_err(io_error)?;
+        let length = read_u64(&mut self.reader).map_err(io_error)?;
+        if length > self.size || length > TRACE_MAX_LENGTH {
+            return Err(Error::new(EINVAL));
+        }
+        let time = read_u64(&mut self.reader).map_err(io_error)?;
+
+        let hash = if self.flags & TRACE_HASH == TRACE_HASH {
+            Some(read_u64(&mut self.reader).map_err(io_error)?)
+        } else {
+            None
+        };
+
+        let data = if self.flags & TRACE_DATA == TRACE_DATA && op == TraceOp::Write {
+            // Grown as the data is read, a truncated trace fails before allocating all of it
+            let mut data = Vec::new();
+            if (&mut self.reader).take(length).read_to_end(&mut data).map_err(io_error)? as u64 != length {
+                return Err(Error::new(EINVAL));
+            }
+            Some(data)
+        } else {
+            None
+        };
+
+        Ok(Some(TraceRecord {
+            op: op,
+            block: block,
+            length: length,
+            time: time,
+            hash: hash,
+            data: data
+        }))
+    }
+}
+
+/// Results of replaying a trace
+#[derive(Clone, Debug, Default)]
+pub struct ReplayStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub syncs: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Duration of the original trace, in nanoseconds
+    pub trace_time: u64,
+    /// Blocks of reads that returned different data than when the trace was recorded
+    pub mismatches: Vec<u64>,
+}
+
+/// Replay every operation of a trace against a disk
+///
+/// Writes use the recorded data if the trace has `TRACE_DATA`, and zeros otherwise. If the trace
+/// has both `TRACE_DATA` and `TRACE_HASH`, and the disk started out with the same contents as the
+/// traced disk, the data of every read is checked against the recorded hash.
+pub fn replay<R: Read, D: Disk>(trace: &mut TraceReader<R>, disk: &D) -> Result<ReplayStats> {
+    let verify = trace.flags & (TRACE_HASH | TRACE_DATA) == TRACE_HASH | TRACE_DATA;
+
+    let mut stats = ReplayStats::default();
+    let mut buffer = Vec::new();
+    while let Some(record) = trace.next_record()? {
+        stats.trace_time = record.time;
+        match record.op {
+            TraceOp::Read => {
+                buffer.resize(record.length as usize, 0);
+                let count = disk.read_at(record.block, &mut buffer)?;
+                if verify && record.hash != Some(hash(&buffer[..count])) {
+                    stats.mismatches.push(record.block);
+                }
+                stats.reads += 1;
+                stats.bytes_read += count as u64;
+            },
+            TraceOp::Write => {
+                let count = match record.data {
+                    Some(ref data) => disk.write_at(record.block, data)?,
+                    None => {
+                        buffer.clear();
+                        buffer.resize(record.length as usize, 0);
+                        disk.write_at(record.block, &buffer)?
+                    }
+                };
+                stats.writes += 1;
+                stats.bytes_written += count as u64;
+            },
+            TraceOp::Sync => {
+                disk.sync()?;
+                stats.syncs += 1;
+            }
+        }
+    }
+
+    Ok(stats)
+}
+
+#[test]
+fn trace_replay_test() {
+    use disk::DiskMemory;
+    use filesystem::FileSystem;
+    use node::Node;
+
+    let disk = DiskTrace::new(DiskMemory::new(1024 * 1024), Vec::new(), TRACE_HASH | TRACE_DATA).unwrap();
+    let fs = FileSystem::create(disk, 0, 0).unwrap();
+    let root = fs.header().1.root;
+    let node = fs.create_node(Node::MODE_FILE | 0o644, "test", root, 0, 0).unwrap();
+    fs.write_node(node.0, 0, &[1; 1000], 0, 0).unwrap();
+    fs.sync().unwrap();
+
+    let (mut original, trace) = fs.disk.into_inner();
+
+    let mut reader = TraceReader::new(&trace[..]).unwrap();
+    assert_eq!(reader.size, 1024 * 1024);
+
+    // Every recorded operation is replayed
+    let mut recorded = [0; 3];
+    let mut records = TraceReader::new(&trace[..]).unwrap();
+    while let Some(record) = records.next_record().unwrap() {
+        recorded[record.op as usize] += 1;
+    }
+
+    let mut replayed = DiskMemory::new(reader.size);
+    let stats = replay(&mut reader, &replayed).unwrap();
+    assert!(stats.reads > 0);
+    assert!(stats.writes > 0);
+    assert!(stats.syncs > 1);
+    assert_eq!([stats.reads, stats.writes, stats.syncs], recorded);
+    assert!(stats.mismatches.is_empty());
+    assert!(original.as_slice() == replayed.as_slice());
+
+    // A record longer than the disk is rejected before anything is allocated for it
+    let mut damaged = trace[..17].to_vec();
+    damaged.push(1);
+    damaged.extend_from_slice(&[0; 8]);
+    damaged.extend_from_slice(&[0xff; 8]);
+    damaged.extend_from_slice(&[0; 8]);
+    let mut reader = TraceReader::new(&damaged[..]).unwrap();
+    assert_eq!(reader.next_record().err().map(|err| err.errno), Some(EINVAL));
+}
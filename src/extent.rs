@@ -3,14 +3,15 @@ use std::cmp::min;
 pub struct BlockIter {
     block: u64,
     length: u64,
+    block_size: u64,
     i: u64
 }
 
 impl Iterator<> for BlockIter {
     type Item = (u64, usize);
     fn next(&mut self) -> Option<Self::Item> {
-        if self.i < (self.length + 511)/512 {
-            let ret = Some((self.block + self.i, min(512, self.length - self.i * 512) as usize));
+        if self.i < (self.length + self.block_size - 1)/self.block_size {
+            let ret = Some((self.block + self.i, min(self.block_size, self.length - self.i * self.block_size) as usize));
             self.i += 1;
             ret
         } else {
@@ -19,7 +20,7 @@ impl Iterator<> for BlockIter {
     }
 }
 
-/// A disk extent
+/// A disk extent, starting at a block and with a length in bytes
 #[derive(Copy, Clone, Debug, Default)]
 #[repr(packed)]
 pub struct Extent {
@@ -42,10 +43,12 @@ impl Extent {
         }
     }
 
-    pub fn blocks(&self) -> BlockIter {
+    /// The blocks of the extent, with the number of bytes used in each
+    pub fn blocks(&self, block_size: u64) -> BlockIter {
         BlockIter {
             block: self.block,
             length: self.length,
+            block_size: block_size,
             i: 0
         }
     }
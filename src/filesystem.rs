@@ -1,138 +1,907 @@
-use std::cmp::min;
+use std::cmp::{self, min};
+use std::collections::BTreeMap;
+use std::sync::{Condvar, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use syscall::error::{Result, Error, EEXIST, EISDIR, ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY};
+use syscall::error::{Result, Error, EEXIST, EINVAL, EIO, EISDIR, ENAMETOOLONG, ENOENT, ENOSPC, ENOTDIR, ENOTEMPTY, EOPNOTSUPP, EROFS};
+
+use cow::{self, Cow};
+use disk::random;
+use journal::{self, Journal, JOURNAL_SECTORS};
 
 use super::{Disk, ExNode, Extent, Header, Node};
 
+/// Extents freed, or continuation nodes removed, by each transaction that shrinks or removes a
+/// node, so that the changes of a fragmented file fit in the journal
+const TRUNCATE_STEP: usize = 8;
+
+/// The nodes whose data is being read or written, by any number of readers or a single writer
+struct NodeLocks {
+    /// The number of readers of each node, or -1 if it is being written
+    locked: Mutex<BTreeMap<u64, isize>>,
+    unlocked: Condvar,
+}
+
+impl NodeLocks {
+    fn new() -> NodeLocks {
+        NodeLocks {
+            locked: Mutex::new(BTreeMap::new()),
+            unlocked: Condvar::new()
+        }
+    }
+
+    /// Wait until no other operation uses the node at `block`, then take it
+    fn lock<'a>(&'a self, block: u64) -> NodeGuard<'a> {
+        let mut locked = self.locked.lock().unwrap();
+        while locked.contains_key(&block) {
+            locked = self.unlocked.wait(locked).unwrap();
+        }
+        locked.insert(block, -1);
+
+        NodeGuard {
+            locks: self,
+            block: block
+        }
+    }
+
+    /// Wait until the node at `block` is not being written, then take it along with other readers
+    fn lock_shared<'a>(&'a self, block: u64) -> NodeGuard<'a> {
+        let mut locked = self.locked.lock().unwrap();
+        while locked.get(&block).map_or(false, |&readers| readers < 0) {
+            locked = self.unlocked.wait(locked).unwrap();
+        }
+        *locked.entry(block).or_insert(0) += 1;
+
+        NodeGuard {
+            locks: self,
+            block: block
+        }
+    }
+}
+
+struct NodeGuard<'a> {
+    locks: &'a NodeLocks,
+    block: u64,
+}
+
+impl<'a> Drop for NodeGuard<'a> {
+    fn drop(&mut self) {
+        let mut locked = self.locks.locked.lock().unwrap();
+        let last = match locked.get_mut(&self.block) {
+            Some(readers) if *readers > 1 => {
+                *readers -= 1;
+                false
+            },
+            _ => true
+        };
+        if last {
+            locked.remove(&self.block);
+            self.locks.unlocked.notify_all();
+        }
+    }
+}
+
 /// A file system
+///
+/// Every operation takes `&self`, so a file system can be shared by several threads. Lookups run
+/// in parallel, changes to directories and to the free list are serialized, reading the data of
+/// a node only waits for writes to the same node, and writing it for any other operation on it. Methods ending
+/// in `_locked` expect the tree lock to be held already.
+///
+/// When the file system has a journal, each change to the metadata is a transaction: its writes
+/// are kept in memory, written to the journal, and only then to their own blocks. A transaction
+/// interrupted by a crash is finished when the file system is opened again, and one that fails
+/// to be written in place refuses further changes until then. In copy-on-write
+/// mode, the changed nodes are written to free blocks instead, and the transaction is committed
+/// by writing the header.
 pub struct FileSystem<D: Disk> {
     pub disk: D,
     pub block: u64,
-    pub header: (u64, Header)
+    /// The block of the header and its contents, refreshed by every copy-on-write commit
+    header: RwLock<(u64, Header)>,
+    /// Refuse all changes to the file system with EROFS
+    pub read_only: bool,
+    /// Held shared while walking nodes, and exclusively while changing them or the free list
+    tree: RwLock<()>,
+    nodes: NodeLocks,
+    journal: Mutex<Journal>,
+    /// State of the copy-on-write mode, if it is used
+    cow: Mutex<Option<Cow>>,
+    /// Set when a committed transaction could not be written in place, refusing all changes
+    /// until the journal is replayed by opening the file system again
+    failed: AtomicBool,
 }
 
 impl<D: Disk> FileSystem<D> {
     /// Open a file system on a disk
-    pub fn open(mut disk: D) -> Result<Self> {
-        for block in 0..65536 {
-            let mut header = (0, Header::default());
-            disk.read_at(block + header.0, &mut header.1)?;
+    pub fn open(disk: D) -> Result<Self> {
+        FileSystem::open_with(disk, false)
+    }
 
-            if header.1.valid() {
-                let mut root = (header.1.root, Node::default());
-                disk.read_at(block + root.0, &mut root.1)?;
+    /// Open a file system on a disk, without allowing any changes to it
+    pub fn open_read_only(disk: D) -> Result<Self> {
+        FileSystem::open_with(disk, true)
+    }
 
-                let mut free = (header.1.free, Node::default());
-                disk.read_at(block + free.0, &mut free.1)?;
+    fn open_with(disk: D, read_only: bool) -> Result<Self> {
+        let mut primary = (0, Header::default());
+        disk.read_at(0, &mut primary.1)?;
+        if primary.1.valid() && primary.1.copy == 0 {
+            return FileSystem::open_header(disk, 0, primary, read_only);
+        }
+
+        // The backups are tried before scanning, so a damaged primary header is not passed over
+        // for a valid one further on, which could belong to an image stored in a file
+        if let Some(header) = FileSystem::find_backup(&disk)? {
+            return FileSystem::open_header(disk, 0, header, read_only);
+        }
 
-                return Ok(FileSystem {
-                    disk: disk,
-                    block: block,
-                    header: header
-                });
+        if &primary.1.signature == Header::SIGNATURE {
+            return Err(Error::new(ENOENT));
+        }
+
+        for block in 1..65536 {
+            let mut header = (0, Header::default());
+            disk.read_at(block, &mut header.1)?;
+
+            if header.1.valid() && header.1.copy == 0 {
+                return FileSystem::open_header(disk, block, header, read_only);
             }
         }
 
         Err(Error::new(ENOENT))
     }
 
-    /// Create a file system on a disk
-    pub fn create(mut disk: D, ctime: u64, ctime_nsec: u32) -> Result<Self> {
+    /// Find a backup of the header where it would be for the size of the disk
+    fn find_backup(disk: &D) -> Result<Option<(u64, Header)>> {
+        let size = disk.size()?;
+        let mut block_size = 512;
+        while Header::valid_block_size(block_size) {
+            let blocks = size/block_size;
+            for (i, &block) in [blocks/2, blocks.saturating_sub(1)].iter().enumerate() {
+                // The backup stands in for the primary header, so it is kept as block 0
+                let mut header = (0, Header::default());
+                disk.read_at(block * (block_size/512), &mut header.1)?;
+
+                let checksum = header.1.checksum;
+                if header.1.valid() && checksum != 0 && header.1.copy as usize == i + 1
+                    && header.1.block_size() == block_size && header.1.size/block_size == blocks {
+                    eprintln!("redoxfs: primary header is damaged, using backup copy {} at block {}", i + 1, block);
+                    return Ok(Some(header));
+                }
+            }
+            block_size *= 2;
+        }
+
+        Ok(None)
+    }
+
+    fn open_header(disk: D, block: u64, header: (u64, Header), mut read_only: bool) -> Result<Self> {
+        // Unknown compat features are ignored
+        let incompat = header.1.incompat & ! Header::INCOMPAT_SUPPORTED;
+        if incompat != 0 {
+            eprintln!("redoxfs: filesystem uses unsupported features {:#x}", incompat);
+            return Err(Error::new(EOPNOTSUPP));
+        }
+
+        let ro_compat = header.1.ro_compat & ! Header::RO_COMPAT_SUPPORTED;
+        if ro_compat != 0 && ! read_only {
+            eprintln!("redoxfs: filesystem uses unsupported features {:#x}, opening it read-only", ro_compat);
+            read_only = true;
+        }
+
+        let sectors = header.1.block_size()/512;
+
+        let mut root = (header.1.root, Node::default());
+        disk.read_at(block + root.0 * sectors, &mut root.1)?;
+
+        let mut free = (header.1.free, Node::default());
+        disk.read_at(block + free.0 * sectors, &mut free.1)?;
+
+        let fs = FileSystem {
+            disk: disk,
+            block: block,
+            header: RwLock::new(header),
+            read_only: read_only,
+            tree: RwLock::new(()),
+            nodes: NodeLocks::new(),
+            journal: Mutex::new(Journal::new()),
+            cow: Mutex::new(None),
+            failed: AtomicBool::new(false),
+        };
+        if fs.header().1.incompat & Header::INCOMPAT_COW != 0 {
+            fs.load_cow()?;
+        }
+        fs.replay()?;
+        Ok(fs)
+    }
+
+    /// Read the copy-on-write map and the list of pending extents of the last commit
+    fn load_cow(&self) -> Result<()> {
+        let block_size = self.block_size();
+        let header = self.header().1;
+        let mut cow = Cow::new(header.generation);
+
+        if header.map != 0 {
+            let levels = cow::map_levels(header.size/block_size, block_size/8);
+            self.load_map(&mut cow, (levels - 1, 0), header.map)?;
+        }
+
+        let mut block = header.pending;
+        while block != 0 {
+            let mut data = vec![0; block_size as usize];
+            self.read_physical(block, &mut data)?;
+            let (next, extents) = cow::decode_pending(&data);
+            cow.pending.extend(extents);
+            cow.pending_blocks.push(block);
+            block = next;
+        }
+
+        *self.cow.lock().unwrap() = Some(cow);
+        Ok(())
+    }
+
+    fn load_map(&self, cow: &mut Cow, part: (u32, u64), block: u64) -> Result<()> {
+        let block_size = self.block_size();
+        let mut data = vec![0; block_size as usize];
+        self.read_physical(block, &mut data)?;
+        cow.map_blocks.insert(part, block);
+
+        let (level, index) = part;
+        for (j, &entry) in cow::decode_map(&data).iter().enumerate() {
+            let child = index * (block_size/8) + j as u64;
+            if entry == 0 {
+                continue;
+            } else if level == 0 {
+                cow.map.insert(child, entry);
+            } else {
+                self.load_map(cow, (level - 1, child), entry)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Finish the transaction left in the journal by a crash, or discard it if it was incomplete
+    fn replay(&self) -> Result<()> {
+        let journal = self.header.read().unwrap().1.journal;
+        if journal == 0 {
+            return Ok(());
+        }
+
+        let start = self.sector(journal);
+        let mut data = vec![0; 512];
+        self.disk.read_at(start, &mut data)?;
+        let count = match journal::count(&data) {
+            Some(count) => count,
+            None => return Ok(())
+        };
+
+        data.resize(512 * (1 + count), 0);
+        self.disk.read_at(start, &mut data)?;
+        match journal::decode(&data) {
+            Some(sectors) => if self.read_only {
+                eprintln!("redoxfs: journal holds a transaction of {} sectors, using it without writing it", sectors.len());
+                self.journal.lock().unwrap().recover(sectors);
+                Ok(())
+            } else {
+                eprintln!("redoxfs: replaying a transaction of {} sectors from the journal", sectors.len());
+                self.checkpoint(&sectors, true)
+            },
+            None => if self.read_only {
+                Ok(())
+            } else {
+                eprintln!("redoxfs: discarding an incomplete transaction from the journal");
+                self.clear_journal(true)
+            }
+        }
+    }
+
+    /// Write the sectors of a committed transaction in place, then clear the journal
+    ///
+    /// The sectors may be reused for data, which replaying the transaction again would overwrite.
+    /// Reusing them takes another transaction, whose sync makes the clear durable before any data
+    /// is written, so a transaction costs two syncs and the clear is only synced when `sync` is set.
+    fn checkpoint(&self, sectors: &BTreeMap<u64, Vec<u8>>, sync: bool) -> Result<()> {
+        for (&number, data) in sectors.iter() {
+            if self.disk.write_at(self.block + number, data)? != data.len() {
+                return Err(Error::new(EIO));
+            }
+        }
+        self.disk.sync()?;
+
+        self.clear_journal(sync)
+    }
+
+    fn clear_journal(&self, sync: bool) -> Result<()> {
+        let journal = self.header.read().unwrap().1.journal;
+        if self.disk.write_at(self.sector(journal), &[0; 512])? != 512 {
+            return Err(Error::new(EIO));
+        }
+        if sync {
+            self.disk.sync()?;
+        }
+        Ok(())
+    }
+
+    /// Whether transactions go through the journal, which limits the sectors each can change
+    fn journaled(&self) -> bool {
+        self.header.read().unwrap().1.journal != 0 && self.cow.lock().unwrap().is_none()
+    }
+
+    /// Run a change to the metadata as a transaction, with the tree lock held
+    ///
+    /// Nothing is written if the change fails.
+    fn transaction<T, F: FnOnce() -> Result<T>>(&self, f: F) -> Result<T> {
+        self.check_writable()?;
+
+        let cow = match *self.cow.lock().unwrap() {
+            Some(ref mut cow) => {
+                cow.txn = Default::default();
+                true
+            },
+            None => false
+        };
+        let journal = self.header.read().unwrap().1.journal;
+        if ! cow && journal == 0 {
+            return f();
+        }
+
+        self.journal.lock().unwrap().begin();
+        let result = if cow {
+            f().and_then(|value| self.cow_prepare().map(|changed| (value, changed)))
+        } else {
+            f().map(|value| (value, false))
+        };
+        let sectors = self.journal.lock().unwrap().take();
+        let (value, changed) = result?;
+
+        if cow {
+            if changed {
+                self.cow_commit(&sectors)?;
+            }
+            return Ok(value);
+        }
+
+        // A single sector is written at once, and needs no journal
+        if sectors.len() <= 1 {
+            for (&number, data) in sectors.iter() {
+                if self.disk.write_at(self.block + number, data)? != data.len() {
+                    return Err(Error::new(EIO));
+                }
+            }
+            return Ok(value);
+        }
+
+        let data = match journal::encode(&sectors) {
+            Some(data) => data,
+            None => {
+                eprintln!("redoxfs: transaction of {} sectors does not fit in the journal", sectors.len());
+                return Err(Error::new(ENOSPC));
+            }
+        };
+        if self.disk.write_at(self.sector(journal), &data)? != data.len() {
+            return Err(Error::new(EIO));
+        }
+        self.disk.sync()?;
+
+        // Once committed, the transaction is kept visible and the journal is left for the next
+        // open to replay, as the blocks in place may be half written
+        if let Err(err) = self.checkpoint(&sectors, false) {
+            eprintln!("redoxfs: failed to write a committed transaction in place, refusing changes: {}", err);
+            self.failed.store(true, Ordering::SeqCst);
+            self.journal.lock().unwrap().recover(sectors);
+            return Err(err);
+        }
+        Ok(value)
+    }
+
+    /// Move the nodes changed by the transaction to free blocks, and allocate the blocks of the
+    /// changed parts of the map and of the list of pending extents, returning false if the
+    /// transaction changed nothing
+    ///
+    /// Allocating changes the free node, so this is repeated until no more blocks are needed.
+    fn cow_prepare(&self) -> Result<bool> {
+        let block_size = self.block_size();
+        let sectors = block_size/512;
+        let size = self.header.read().unwrap().1.size;
+        let levels = cow::map_levels(size/block_size, block_size/8);
+
+        let pending = {
+            let guard = self.cow.lock().unwrap();
+            let cow = guard.as_ref().unwrap();
+            if cow.txn.freed.is_empty() && self.journal.lock().unwrap().written().is_empty() {
+                return Ok(false);
+            }
+            cow.pending.clone()
+        };
+
+        // The extents that the last commit stopped using can be reused now
+        let free_block = self.header.read().unwrap().1.free;
+        for extent in pending {
+            self.insert_blocks(extent.block, extent.length, free_block)?;
+        }
+
+        loop {
+            let written = self.journal.lock().unwrap().written();
+            let (moved, parts, pending_blocks) = {
+                let guard = self.cow.lock().unwrap();
+                let cow = guard.as_ref().unwrap();
+
+                let mut moved: Vec<u64> = written.iter().map(|sector| sector/sectors).filter(|&block| {
+                    ! cow.txn.moved.contains_key(&block) && ! cow.txn.allocated(block, block_size) && ! cow.txn.freed(block, block_size)
+                }).collect();
+                moved.dedup();
+
+                let parts: Vec<(u32, u64)> = cow.dirty_map(block_size, levels).into_iter().filter(|part| {
+                    ! cow.txn.map_blocks.contains_key(part)
+                }).collect();
+
+                let entries = cow::pending_entries(block_size);
+                let needed = (cow.next_pending(block_size, levels).len() + entries - 1)/entries;
+                (moved, parts, needed.saturating_sub(cow.txn.pending_blocks.len()))
+            };
+
+            if moved.is_empty() && parts.is_empty() && pending_blocks == 0 {
+                return Ok(true);
+            }
+
+            for block in moved {
+                let new = self.allocate_locked(1)?;
+                self.cow.lock().unwrap().as_mut().unwrap().txn.moved.insert(block, new);
+            }
+            for part in parts {
+                let new = self.allocate_locked(1)?;
+                self.cow.lock().unwrap().as_mut().unwrap().txn.map_blocks.insert(part, new);
+            }
+            for _ in 0..pending_blocks {
+                let new = self.allocate_locked(1)?;
+                self.cow.lock().unwrap().as_mut().unwrap().txn.pending_blocks.push(new);
+            }
+        }
+    }
+
+    /// Write the blocks of a transaction prepared by `cow_prepare`, then commit it by writing the
+    /// header with the next generation
+    ///
+    /// The blocks of the last commit are not changed, so a crash before the primary header is
+    /// written leaves the file system as it was.
+    fn cow_commit(&self, sectors: &BTreeMap<u64, Vec<u8>>) -> Result<()> {
+        let block_size = self.block_size();
+        let blocks = self.header.read().unwrap().1.size/block_size;
+        let levels = cow::map_levels(blocks, block_size/8);
+
+        let (moved, parts, map, map_blocks, pending, pending_blocks, generation) = {
+            let guard = self.cow.lock().unwrap();
+            let cow = guard.as_ref().unwrap();
+            let (map, map_blocks) = cow.next_map(block_size);
+            (cow.txn.moved.clone(), cow.dirty_map(block_size, levels), map, map_blocks,
+             cow.next_pending(block_size, levels), cow.txn.pending_blocks.clone(), cow.generation)
+        };
+
+        // Moved nodes are written whole to their new blocks, and new ones in place
+        for (&block, &new) in moved.iter() {
+            let mut data = vec![0; block_size as usize];
+            self.read_at(block, &mut data)?;
+            for (i, chunk) in data.chunks_mut(512).enumerate() {
+                if let Some(sector) = sectors.get(&(block * (block_size/512) + i as u64)) {
+                    chunk.copy_from_slice(sector);
+                }
+            }
+            self.write_physical(new, &data)?;
+        }
+        for (&number, data) in sectors.iter() {
+            let block = number/(block_size/512);
+            let allocated = {
+                let guard = self.cow.lock().unwrap();
+                let txn = &guard.as_ref().unwrap().txn;
+                txn.allocated(block, block_size) && ! txn.freed(block, block_size)
+            };
+            if allocated && ! moved.contains_key(&block) && self.disk.write_at(self.block + number, data)? != data.len() {
+                return Err(Error::new(EIO));
+            }
+        }
+
+        for part in parts.iter() {
+            self.write_physical(map_blocks[part], &Cow::encode_map(&map, &map_blocks, *part, block_size))?;
+        }
+
+        let entries = cow::pending_entries(block_size);
+        for (i, &block) in pending_blocks.iter().enumerate() {
+            let extents = pending.chunks(entries).nth(i).unwrap_or(&[]);
+            let next = pending_blocks.get(i + 1).cloned().unwrap_or(0);
+            self.write_physical(block, &cow::encode_pending(next, extents, block_size))?;
+        }
+        self.disk.sync()?;
+
+        let mut header = self.header().1;
+        header.generation = generation + 1;
+        header.map = map_blocks.get(&(levels - 1, 0)).cloned().unwrap_or(0);
+        header.pending = pending_blocks.first().cloned().unwrap_or(0);
+        self.write_header_copy(&mut header, 0, 0)?;
+        self.disk.sync()?;
+
+        // The header is refreshed with the cow lock held, so it matches the generation
+        {
+            let mut cow = self.cow.lock().unwrap();
+            cow.as_mut().unwrap().finish(map, map_blocks, pending);
+            let mut current = self.header.write().unwrap();
+            current.0 = 0;
+            current.1.copy_from_slice(&header);
+        }
+
+        // The backups follow, a crash before they are written leaves a valid primary
+        for (i, block) in header.backup_blocks().into_iter().enumerate() {
+            self.write_header_copy(&mut header, i + 1, block)?;
+        }
+        self.disk.sync()
+    }
+
+    /// Switch to copy-on-write updates, which replace the journal
+    ///
+    /// Versions that do not know the feature can no longer open the file system.
+    pub fn set_copy_on_write(&mut self) -> Result<()> {
+        self.check_writable()?;
+        if self.cow.get_mut().unwrap().is_some() {
+            return Ok(());
+        }
+
+        let journal = {
+            let header = &mut self.header.get_mut().unwrap().1;
+            let journal = (header.journal, header.journal_len);
+            header.incompat |= Header::INCOMPAT_COW;
+            header.ro_compat &= ! Header::RO_COMPAT_JOURNAL;
+            header.journal = 0;
+            header.journal_len = 0;
+            header.generation = 0;
+            header.map = 0;
+            header.pending = 0;
+            journal
+        };
+        *self.cow.get_mut().unwrap() = Some(Cow::new(0));
+        self.write_header()?;
+
+        if journal.0 != 0 {
+            let block_size = self.block_size();
+            self.deallocate(journal.0, journal.1 * block_size)?;
+        }
+        Ok(())
+    }
+
+    /// Number of copy-on-write commits, 0 if the mode is not used
+    pub fn generation(&self) -> u64 {
+        self.cow.lock().unwrap().as_ref().map_or(0, |cow| cow.generation)
+    }
+
+    /// Create a file system on a disk, with 512 byte blocks
+    pub fn create(disk: D, ctime: u64, ctime_nsec: u32) -> Result<Self> {
+        FileSystem::create_with_block_size(disk, 512, ctime, ctime_nsec)
+    }
+
+    /// Create a file system on a disk, with blocks of `block_size` bytes and a random UUID
+    pub fn create_with_block_size(disk: D, block_size: u64, ctime: u64, ctime_nsec: u32) -> Result<Self> {
+        if ! Header::valid_block_size(block_size) {
+            return Err(Error::new(EINVAL));
+        }
+
         let size = disk.size()?;
+        let blocks = size/block_size;
+        let sectors = block_size/512;
 
-        if size >= 4 * 512 {
-            let mut free = (2, Node::new(Node::MODE_FILE, "free", 0, ctime, ctime_nsec));
-            free.1.extents[0] = Extent::new(4, size - 4 * 512);
-            disk.write_at(free.0, &free.1)?;
+        if blocks >= 4 {
+            // A random, version 4 UUID
+            let mut uuid = [0; 16];
+            random(&mut uuid)?;
+            uuid[6] = uuid[6] & 0x0f | 0x40;
+            uuid[8] = uuid[8] & 0x3f | 0x80;
 
             let root = (1, Node::new(Node::MODE_DIR | 0o755, "root", 0, ctime, ctime_nsec));
-            disk.write_at(root.0, &root.1)?;
+            let mut header = (0, Header::new(uuid, size, block_size, root.0, 2));
+            if blocks >= 8 {
+                header.1.backups = 2;
+                header.1.compat |= Header::COMPAT_BACKUPS;
+            }
+            if block_size != 512 {
+                header.1.incompat |= Header::INCOMPAT_BLOCK_SIZE;
+            }
 
-            let header = (0, Header::new(size, root.0, free.0));
-            disk.write_at(header.0, &header.1)?;
+            // The journal follows the root and free nodes, when it is small next to the disk
+            let journal_len = (JOURNAL_SECTORS + sectors - 1)/sectors;
+            let mut start = 4;
+            if blocks >= 16 * journal_len {
+                header.1.journal = start;
+                header.1.journal_len = journal_len;
+                header.1.ro_compat |= Header::RO_COMPAT_JOURNAL;
+                disk.write_at(start * sectors, &[0; 512])?;
+                start += journal_len;
+            }
+
+            // Everything after the metadata is free, except for the backups of the header
+            let mut free = (header.1.free, Node::new(Node::MODE_FILE, "free", 0, ctime, ctime_nsec));
+            let mut i = 0;
+            for block in header.1.backup_blocks().into_iter().chain(Some(blocks)) {
+                if block > start {
+                    free.1.extents[i] = Extent::new(start, (block - start) * block_size);
+                    i += 1;
+                }
+                start = block + 1;
+            }
+            disk.write_at(free.0 * sectors, &free.1)?;
 
-            Ok(FileSystem {
+            disk.write_at(root.0 * sectors, &root.1)?;
+
+            let mut fs = FileSystem {
                 disk: disk,
                 block: 0,
-                header: header
-            })
+                header: RwLock::new(header),
+                read_only: false,
+                tree: RwLock::new(()),
+                nodes: NodeLocks::new(),
+                journal: Mutex::new(Journal::new()),
+                cow: Mutex::new(None),
+                failed: AtomicBool::new(false),
+            };
+            fs.write_header()?;
+            Ok(fs)
         } else {
             Err(Error::new(ENOSPC))
         }
     }
 
-    pub fn read_at(&mut self, block: u64, buffer: &mut [u8]) -> Result<usize> {
-        self.disk.read_at(self.block + block, buffer)
+    /// Size of a block in bytes, node and extent block numbers count in these
+    pub fn block_size(&self) -> u64 {
+        self.header.read().unwrap().1.block_size()
+    }
+
+    /// The block of the header and a copy of its contents, as of the last commit
+    pub fn header(&self) -> (u64, Header) {
+        let current = self.header.read().unwrap();
+        let mut header = Header::default();
+        header.copy_from_slice(&current.1);
+        (current.0, header)
+    }
+
+    /// Change the UUID, which identifies the file system when mounting by UUID
+    pub fn set_uuid(&mut self, uuid: [u8; 16]) -> Result<()> {
+        self.header.get_mut().unwrap().1.uuid = uuid;
+        self.write_header()
+    }
+
+    /// Change the volume label, at most 64 bytes of UTF-8
+    pub fn set_label(&mut self, label: &str) -> Result<()> {
+        {
+            let header = &mut self.header.get_mut().unwrap().1;
+            if label.len() > header.label.len() {
+                return Err(Error::new(ENAMETOOLONG));
+            }
+
+            header.label = [0; 64];
+            header.label[..label.len()].copy_from_slice(label.as_bytes());
+        }
+        self.write_header()
+    }
+
+    /// Write every copy of the header, after changing it
+    ///
+    /// This also repairs a damaged primary header, when the file system was opened from a backup.
+    pub fn write_header(&mut self) -> Result<()> {
+        self.check_writable()?;
+
+        let block_size = self.block_size();
+        let mut header = {
+            let current = &mut self.header.get_mut().unwrap().1;
+            let blocks = current.size/block_size;
+            if let Some(ref cow) = *self.cow.get_mut().unwrap() {
+                current.generation = cow.generation;
+                current.map = cow.map_root(block_size, blocks);
+                current.pending = cow.pending_root();
+            }
+
+            let mut header = Header::default();
+            header.copy_from_slice(current);
+            header
+        };
+
+        let mut blocks = vec![0];
+        blocks.extend(header.backup_blocks());
+        for (copy, &block) in blocks.iter().enumerate() {
+            self.write_header_copy(&mut header, copy, block)?;
+        }
+
+        let current = self.header.get_mut().unwrap();
+        current.0 = 0;
+        current.1.copy = 0;
+        current.1.update_checksum();
+        Ok(())
+    }
+
+    fn write_header_copy(&self, header: &mut Header, copy: usize, block: u64) -> Result<()> {
+        header.copy = copy as u16;
+        header.update_checksum();
+        self.write_physical(block, header)?;
+        Ok(())
+    }
+
+    /// The sector of the disk where a block starts
+    fn sector(&self, block: u64) -> u64 {
+        self.block + block * (self.block_size()/512)
+    }
+
+    /// The block holding the node created at `block`, which was moved if it changed in
+    /// copy-on-write mode
+    fn physical(&self, block: u64) -> u64 {
+        match *self.cow.lock().unwrap() {
+            Some(ref cow) => cow.map.get(&block).cloned().unwrap_or(block),
+            None => block
+        }
     }
 
-    pub fn write_at(&mut self, block: u64, buffer: &[u8]) -> Result<usize> {
-        self.disk.write_at(self.block + block, buffer)
+    fn read_physical(&self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let count = self.disk.read_at(self.sector(block), buffer)?;
+        if count < buffer.len() {
+            // Short reads mean the block is past the end of the disk
+            return Err(Error::new(EIO));
+        }
+        Ok(count)
+    }
+
+    fn write_physical(&self, block: u64, buffer: &[u8]) -> Result<usize> {
+        let count = self.disk.write_at(self.sector(block), buffer)?;
+        if count < buffer.len() {
+            return Err(Error::new(EIO));
+        }
+        Ok(count)
     }
 
-    pub fn allocate(&mut self, length: u64) -> Result<u64> {
+    /// Read blocks, as changed by the transaction of the current thread
+    ///
+    /// Nodes are read one at a time, only the first block is looked up in the copy-on-write map.
+    pub fn read_at(&self, block: u64, buffer: &mut [u8]) -> Result<usize> {
+        let count = self.read_physical(self.physical(block), buffer)?;
+        self.journal.lock().unwrap().read(block * (self.block_size()/512), buffer);
+        Ok(count)
+    }
+
+    /// Write blocks, or keep the write in the transaction of the current thread
+    pub fn write_at(&self, block: u64, buffer: &[u8]) -> Result<usize> {
+        self.check_writable()?;
+        if self.journal.lock().unwrap().write(block * (self.block_size()/512), buffer) {
+            return Ok(buffer.len());
+        }
+
+        self.write_physical(self.physical(block), buffer)
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only || self.failed.load(Ordering::SeqCst) {
+            Err(Error::new(EROFS))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Ensure all changes to the file system have reached stable storage
+    pub fn sync(&self) -> Result<()> {
+        self.disk.sync()
+    }
+
+    pub fn allocate(&self, length: u64) -> Result<u64> {
+        self.check_writable()?;
+
+        let _tree = self.tree.write().unwrap();
+        self.transaction(|| self.allocate_locked(length))
+    }
+
+    fn allocate_locked(&self, length: u64) -> Result<u64> {
+        let block_size = self.block_size();
         //TODO: traverse next pointer
-        let free_block = self.header.1.free;
+        let free_block = self.header.read().unwrap().1.free;
         let mut free = self.node(free_block)?;
         let mut block_option = None;
         for extent in free.1.extents.iter_mut() {
-            if extent.length/512 >= length {
+            if extent.length/block_size >= length {
                 block_option = Some(extent.block);
-                extent.length -= length * 512;
+                extent.length -= length * block_size;
                 extent.block += length;
                 break;
             }
         }
         if let Some(block) = block_option {
             self.write_at(free.0, &free.1)?;
+            if let Some(ref mut cow) = *self.cow.lock().unwrap() {
+                cow.txn.allocated.push(Extent::new(block, length * block_size));
+            }
             Ok(block)
         } else {
             Err(Error::new(ENOSPC))
         }
     }
 
-    pub fn deallocate(&mut self, block: u64, length: u64) -> Result<()> {
-        let free_block = self.header.1.free;
+    pub fn deallocate(&self, block: u64, length: u64) -> Result<()> {
+        self.check_writable()?;
+
+        let _tree = self.tree.write().unwrap();
+        self.transaction(|| self.deallocate_locked(block, length))
+    }
+
+    fn deallocate_locked(&self, block: u64, length: u64) -> Result<()> {
+        // In copy-on-write mode the last commit may still use the blocks, they are freed by the next
+        if let Some(ref mut cow) = *self.cow.lock().unwrap() {
+            cow.txn.freed.push(Extent::new(block, length));
+            return Ok(());
+        }
+
+        let free_block = self.header.read().unwrap().1.free;
         self.insert_blocks(block, length, free_block)
     }
 
-    pub fn node(&mut self, block: u64) -> Result<(u64, Node)> {
+    /// All extents of the free list, with lengths in bytes
+    pub fn free_extents(&self) -> Result<Vec<Extent>> {
+        let _tree = self.tree.read().unwrap();
+
+        let mut extents = Vec::new();
+        let mut block = self.header.read().unwrap().1.free;
+        while block != 0 {
+            let free = self.node(block)?;
+            for extent in free.1.extents.iter() {
+                if extent.length > 0 {
+                    extents.push(*extent);
+                }
+            }
+            block = free.1.next;
+        }
+        Ok(extents)
+    }
+
+    pub fn node(&self, block: u64) -> Result<(u64, Node)> {
         let mut node = Node::default();
         self.read_at(block, &mut node)?;
         Ok((block, node))
     }
 
-    pub fn ex_node(&mut self, block: u64) -> Result<(u64, ExNode)> {
+    pub fn ex_node(&self, block: u64) -> Result<(u64, ExNode)> {
         let mut node = ExNode::default();
         self.read_at(block, &mut node)?;
         Ok((block, node))
     }
 
-    pub fn child_nodes(&mut self, children: &mut Vec<(u64, Node)>, parent_block: u64) -> Result<()> {
+    pub fn child_nodes(&self, children: &mut Vec<(u64, Node)>, parent_block: u64) -> Result<()> {
+        let _tree = self.tree.read().unwrap();
+        self.child_nodes_locked(children, parent_block)
+    }
+
+    fn child_nodes_locked(&self, children: &mut Vec<(u64, Node)>, parent_block: u64) -> Result<()> {
+        let block_size = self.block_size();
         if parent_block == 0 {
             return Ok(());
         }
 
         let parent = self.node(parent_block)?;
         for extent in parent.1.extents.iter() {
-            for (block, size) in extent.blocks() {
-                if size >= 512 {
+            for (block, size) in extent.blocks(block_size) {
+                if size as u64 >= block_size {
                     children.push(self.node(block)?);
                 }
             }
         }
 
-        self.child_nodes(children, parent.1.next)
+        self.child_nodes_locked(children, parent.1.next)
+    }
+
+    pub fn find_node(&self, name: &str, parent_block: u64) -> Result<(u64, Node)> {
+        let _tree = self.tree.read().unwrap();
+        self.find_node_locked(name, parent_block)
     }
 
-    pub fn find_node(&mut self, name: &str, parent_block: u64) -> Result<(u64, Node)> {
+    fn find_node_locked(&self, name: &str, parent_block: u64) -> Result<(u64, Node)> {
+        let block_size = self.block_size();
         if parent_block == 0 {
             return Err(Error::new(ENOENT));
         }
 
         let parent = self.node(parent_block)?;
         for extent in parent.1.extents.iter() {
-            for (block, size) in extent.blocks() {
-                if size >= 512 {
+            for (block, size) in extent.blocks(block_size) {
+                if size as u64 >= block_size {
                     let child = self.node(block)?;
 
                     let mut matches = false;
@@ -149,10 +918,11 @@ impl<D: Disk> FileSystem<D> {
             }
         }
 
-        self.find_node(name, parent.1.next)
+        self.find_node_locked(name, parent.1.next)
     }
 
-    fn insert_blocks(&mut self, block: u64, length: u64, parent_block: u64) -> Result<()> {
+    fn insert_blocks(&self, block: u64, length: u64, parent_block: u64) -> Result<()> {
+        let block_size = self.block_size();
         if parent_block == 0 {
             return Err(Error::new(ENOSPC));
         }
@@ -166,13 +936,13 @@ impl<D: Disk> FileSystem<D> {
                 extent.block = block;
                 extent.length = length;
                 break;
-            } else if length % 512 == 0 && extent.block == block + length/512 {
+            } else if length % block_size == 0 && extent.block == block + length/block_size {
                 //At beginning
                 inserted = true;
                 extent.block = block;
                 extent.length += length;
                 break;
-            } else if extent.length % 512 == 0 && extent.block + extent.length/512 == block {
+            } else if extent.length % block_size == 0 && extent.block + extent.length/block_size == block {
                 //At end
                 inserted = true;
                 extent.length += length;
@@ -185,9 +955,9 @@ impl<D: Disk> FileSystem<D> {
             Ok(())
         } else {
             if parent.1.next == 0 {
-                let next = self.allocate(1)?;
+                let next = self.allocate_locked(1)?;
                 // Could be mutated by self.allocate if free block
-                if parent.0 == self.header.1.free {
+                if parent.0 == self.header.read().unwrap().1.free {
                     self.read_at(parent.0, &mut parent.1)?;
                 }
                 parent.1.next = next;
@@ -199,20 +969,25 @@ impl<D: Disk> FileSystem<D> {
         }
     }
 
-    pub fn create_node(&mut self, mode: u16, name: &str, parent_block: u64, ctime: u64, ctime_nsec: u32) -> Result<(u64, Node)> {
-        if self.find_node(name, parent_block).is_ok() {
+    pub fn create_node(&self, mode: u16, name: &str, parent_block: u64, ctime: u64, ctime_nsec: u32) -> Result<(u64, Node)> {
+        let block_size = self.block_size();
+        self.check_writable()?;
+
+        let _tree = self.tree.write().unwrap();
+        self.transaction(|| if self.find_node_locked(name, parent_block).is_ok() {
             Err(Error::new(EEXIST))
         } else {
-            let node = (self.allocate(1)?, Node::new(mode, name, parent_block, ctime, ctime_nsec));
+            let node = (self.allocate_locked(1)?, Node::new(mode, name, parent_block, ctime, ctime_nsec));
             self.write_at(node.0, &node.1)?;
 
-            self.insert_blocks(node.0, 512, parent_block)?;
+            self.insert_blocks(node.0, block_size, parent_block)?;
 
             Ok(node)
-        }
+        })
     }
 
-    fn remove_blocks(&mut self, block: u64, length: u64, parent_block: u64) -> Result<()> {
+    fn remove_blocks(&self, block: u64, length: u64, parent_block: u64) -> Result<()> {
+        let block_size = self.block_size();
         if parent_block == 0 {
             return Err(Error::new(ENOENT));
         }
@@ -221,12 +996,12 @@ impl<D: Disk> FileSystem<D> {
         let mut replace_option = None;
         let mut parent = self.node(parent_block)?;
         for extent in parent.1.extents.iter_mut() {
-            if block >= extent.block && block + length <= extent.block + extent.length/512 {
+            if block >= extent.block && block + length <= extent.block + extent.length/block_size {
                 //Inside
                 removed = true;
 
-                let left = Extent::new(extent.block, (block - extent.block) * 512);
-                let right = Extent::new(block + length, ((extent.block + extent.length/512) - (block + length)) * 512);
+                let left = Extent::new(extent.block, (block - extent.block) * block_size);
+                let right = Extent::new(block + length, ((extent.block + extent.length/block_size) - (block + length)) * block_size);
 
                 if left.length > 0 {
                     *extent = left;
@@ -251,7 +1026,7 @@ impl<D: Disk> FileSystem<D> {
                 self.insert_blocks(replace.block, replace.length, parent_block)?;
             }
 
-            self.deallocate(block, 512)?;
+            self.deallocate_locked(block, block_size)?;
 
             Ok(())
         } else {
@@ -259,22 +1034,57 @@ impl<D: Disk> FileSystem<D> {
         }
     }
 
-    pub fn remove_node(&mut self, mode: u16, name: &str, parent_block: u64) -> Result<()> {
-        let node = self.find_node(name, parent_block)?;
+    pub fn remove_node(&self, mode: u16, name: &str, parent_block: u64) -> Result<()> {
+        self.check_writable()?;
+
+        // The node is locked first, so its data is not in use while it is freed
+        let mut block = self.find_node(name, parent_block)?.0;
+        let (_node, _tree, node) = loop {
+            let node_guard = self.nodes.lock(block);
+            let tree_guard = self.tree.write().unwrap();
+            let node = self.find_node_locked(name, parent_block)?;
+            if node.0 == block {
+                break (node_guard, tree_guard, node);
+            }
+
+            // Replaced while waiting for the lock
+            block = node.0;
+        };
+
         if node.1.mode & Node::MODE_TYPE == mode {
             if node.1.is_dir() {
                 let mut children = Vec::new();
-                self.child_nodes(&mut children, node.0)?;
+                self.child_nodes_locked(&mut children, node.0)?;
                 if ! children.is_empty() {
                     return Err(Error::new(ENOTEMPTY));
                 }
             }
 
-            self.node_set_len(node.0, 0)?;
-            self.remove_blocks(node.0, 1, parent_block)?;
-            self.write_at(node.0, &Node::default())?;
+            // With a journal, the data and the continuation nodes are freed a few at a time, and a
+            // crash leaves the node in place with some of them gone
+            if self.journaled() {
+                self.node_truncate(node.0, 0)?;
+                loop {
+                    let chain = self.node_chain(node.0)?;
+                    if chain.len() <= 1 {
+                        break;
+                    }
 
-            Ok(())
+                    let keep = cmp::max(chain.len().saturating_sub(TRUNCATE_STEP), 1);
+                    self.transaction(|| self.node_free_chain(&chain, keep))?;
+                }
+            }
+
+            self.transaction(|| {
+                self.node_set_len_locked(node.0, 0)?;
+                let chain = self.node_chain(node.0)?;
+                if chain.len() > 1 {
+                    self.node_free_chain(&chain, 1)?;
+                }
+                self.remove_blocks(node.0, 1, parent_block)?;
+                self.write_at(node.0, &Node::default())?;
+                Ok(())
+            })
         } else if node.1.is_dir() {
             Err(Error::new(EISDIR))
         } else {
@@ -283,7 +1093,8 @@ impl<D: Disk> FileSystem<D> {
     }
 
     // TODO: modification time
-    fn node_ensure_len(&mut self, block: u64, mut length: u64) -> Result<()> {
+    fn node_ensure_len(&self, block: u64, mut length: u64) -> Result<()> {
+        let block_size = self.block_size();
         if block == 0 {
             return Err(Error::new(ENOENT));
         }
@@ -296,13 +1107,15 @@ impl<D: Disk> FileSystem<D> {
                 length = 0;
                 break;
             } else {
-                changed = true;
-                let allocated = ((extent.length + 511)/512) * 512;
+                // Only the nodes that change are written, so growing a fragmented file stays small
+                let allocated = ((extent.length + block_size - 1)/block_size) * block_size;
                 if allocated >= length {
                     extent.length = length;
                     length = 0;
+                    changed = true;
                     break;
                 } else {
+                    changed |= extent.length != allocated;
                     extent.length = allocated;
                     length -= allocated;
                 }
@@ -317,7 +1130,9 @@ impl<D: Disk> FileSystem<D> {
             if node.1.next > 0 {
                 self.node_ensure_len(node.1.next, length)
             } else {
-                let new_block = self.allocate((length + 511)/512)?;
+                let count = (length + block_size - 1)/block_size;
+                let new_block = self.allocate_locked(count)?;
+                self.zero_blocks(new_block, count)?;
                 self.insert_blocks(new_block, length, block)?;
                 Ok(())
             }
@@ -326,8 +1141,90 @@ impl<D: Disk> FileSystem<D> {
         }
     }
 
+    /// Zero newly allocated data blocks, which may still hold the contents of a removed file
+    ///
+    /// They are written directly, and synced before a journal commits the node pointing at them,
+    /// so a crash before the data is written never exposes the old contents. Copy-on-write commits
+    /// sync before writing the header anyway.
+    fn zero_blocks(&self, block: u64, count: u64) -> Result<()> {
+        let block_size = self.block_size();
+        let chunk = min(count, 128);
+        let zeroes = vec![0; (chunk * block_size) as usize];
+        let mut i = 0;
+        while i < count {
+            let len = min(chunk, count - i);
+            self.write_physical(block + i, &zeroes[..(len * block_size) as usize])?;
+            i += len;
+        }
+
+        if self.header.read().unwrap().1.journal != 0 {
+            self.disk.sync()?;
+        }
+        Ok(())
+    }
+
     //TODO: modification time
-    pub fn node_set_len(&mut self, block: u64, mut length: u64) -> Result<()> {
+    pub fn node_set_len(&self, block: u64, length: u64) -> Result<()> {
+        self.check_writable()?;
+
+        let _node = self.nodes.lock(block);
+        let _tree = self.tree.write().unwrap();
+        self.node_truncate(block, length)
+    }
+
+    /// Shrink a node to `length` in transactions that each free at most `TRUNCATE_STEP` extents,
+    /// starting from the end
+    fn node_truncate(&self, block: u64, length: u64) -> Result<()> {
+        if ! self.journaled() {
+            return self.transaction(|| self.node_set_len_locked(block, length));
+        }
+
+        // The offset of every extent that ends past the new length
+        let mut starts = Vec::new();
+        let mut offset = 0;
+        for &next in self.node_chain(block)?.iter() {
+            for extent in self.node(next)?.1.extents.iter() {
+                if offset + extent.length > length {
+                    starts.push(cmp::max(offset, length));
+                }
+                offset += extent.length;
+            }
+        }
+
+        let mut steps: Vec<u64> = starts.iter().rev().skip(TRUNCATE_STEP - 1).step_by(TRUNCATE_STEP).cloned().collect();
+        steps.push(length);
+        steps.dedup();
+        for &step in steps.iter() {
+            self.transaction(|| self.node_set_len_locked(block, step))?;
+        }
+        Ok(())
+    }
+
+    /// Free the nodes of a chain from `node_chain` after the first `keep`
+    fn node_free_chain(&self, chain: &[u64], keep: usize) -> Result<()> {
+        let block_size = self.block_size();
+        let mut last = self.node(chain[keep - 1])?;
+        last.1.next = 0;
+        self.write_at(last.0, &last.1)?;
+        for &block in chain[keep..].iter() {
+            self.deallocate_locked(block, block_size)?;
+        }
+        Ok(())
+    }
+
+    /// The block of a node followed by those of the nodes it continues in
+    fn node_chain(&self, block: u64) -> Result<Vec<u64>> {
+        let mut chain = Vec::new();
+        let mut next = block;
+        while next != 0 {
+            chain.push(next);
+            next = self.node(next)?.1.next;
+        }
+        Ok(chain)
+    }
+
+    fn node_set_len_locked(&self, block: u64, mut length: u64) -> Result<()> {
+        let block_size = self.block_size();
         if block == 0 {
             return Err(Error::new(ENOENT));
         }
@@ -337,10 +1234,10 @@ impl<D: Disk> FileSystem<D> {
         let mut node = self.node(block)?;
         for extent in node.1.extents.iter_mut() {
             if extent.length > length {
-                let start = (length + 511)/512;
-                let end = (extent.length + 511)/512;
+                let start = (length + block_size - 1)/block_size;
+                let end = (extent.length + block_size - 1)/block_size;
                 if end > start {
-                    self.deallocate(extent.block + start, (end - start) * 512)?;
+                    self.deallocate_locked(extent.block + start, (end - start) * block_size)?;
                 }
                 extent.length = length;
                 changed = true;
@@ -355,13 +1252,14 @@ impl<D: Disk> FileSystem<D> {
         }
 
         if node.1.next > 0 {
-            self.node_set_len(node.1.next, length)
+            self.node_set_len_locked(node.1.next, length)
         } else {
             Ok(())
         }
     }
 
-    fn node_extents(&mut self, block: u64, mut offset: u64, mut len: usize, extents: &mut Vec<Extent>) -> Result<()> {
+    fn node_extents(&self, block: u64, mut offset: u64, mut len: usize, extents: &mut Vec<Extent>) -> Result<()> {
+        let block_size = self.block_size();
         if block == 0 {
             return Ok(());
         }
@@ -369,7 +1267,7 @@ impl<D: Disk> FileSystem<D> {
         let node = self.node(block)?;
         for extent in node.1.extents.iter() {
             let mut push_extent = Extent::default();
-            for (block, size) in extent.blocks() {
+            for (block, size) in extent.blocks(block_size) {
                 if offset == 0 {
                     if push_extent.block == 0 {
                         push_extent.block = block;
@@ -403,12 +1301,17 @@ impl<D: Disk> FileSystem<D> {
         }
     }
 
-    pub fn read_node(&mut self, block: u64, offset: u64, buf: &mut [u8]) -> Result<usize> {
-        let block_offset = offset / 512;
-        let mut byte_offset = (offset % 512) as usize;
+    pub fn read_node(&self, block: u64, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        let block_size = self.block_size();
+        let block_offset = offset / block_size;
+        let mut byte_offset = (offset % block_size) as usize;
 
+        let _node = self.nodes.lock_shared(block);
         let mut extents = Vec::new();
-        self.node_extents(block, block_offset, byte_offset + buf.len(), &mut extents)?;
+        {
+            let _tree = self.tree.read().unwrap();
+            self.node_extents(block, block_offset, byte_offset + buf.len(), &mut extents)?;
+        }
 
         let mut i = 0;
         for extent in extents.iter() {
@@ -416,7 +1319,7 @@ impl<D: Disk> FileSystem<D> {
             let mut length = extent.length;
 
             if byte_offset > 0 && length > 0 {
-                let mut sector = [0; 512];
+                let mut sector = vec![0; block_size as usize];
                 self.read_at(block, &mut sector)?;
 
                 let sector_size = min(sector.len() as u64, length) as usize;
@@ -431,18 +1334,18 @@ impl<D: Disk> FileSystem<D> {
                 byte_offset = 0;
             }
 
-            let length_aligned = ((min(length, (buf.len() - i) as u64)/512) * 512) as usize;
+            let length_aligned = ((min(length, (buf.len() - i) as u64)/block_size) * block_size) as usize;
 
             if length_aligned > 0 {
                 let extent_buf = &mut buf[i..i + length_aligned];
                 self.read_at(block, extent_buf)?;
                 i += length_aligned;
-                block += (length_aligned as u64)/512;
+                block += (length_aligned as u64)/block_size;
                 length -= length_aligned as u64;
             }
 
             if length > 0 {
-                let mut sector = [0; 512];
+                let mut sector = vec![0; block_size as usize];
                 self.read_at(block, &mut sector)?;
 
                 let sector_size = min(sector.len() as u64, length) as usize;
@@ -456,20 +1359,27 @@ impl<D: Disk> FileSystem<D> {
             }
 
             assert_eq!(length, 0);
-            assert_eq!(block, extent.block + (extent.length + 511)/512);
+            assert_eq!(block, extent.block + (extent.length + block_size - 1)/block_size);
         }
 
         Ok(i)
     }
 
-    pub fn write_node(&mut self, block: u64, offset: u64, buf: &[u8], mtime: u64, mtime_nsec: u32) -> Result<usize> {
-        let block_offset = offset / 512;
-        let mut byte_offset = (offset % 512) as usize;
+    pub fn write_node(&self, block: u64, offset: u64, buf: &[u8], mtime: u64, mtime_nsec: u32) -> Result<usize> {
+        let block_size = self.block_size();
+        self.check_writable()?;
 
-        self.node_ensure_len(block, block_offset as u64 * 512 + (byte_offset + buf.len()) as u64)?;
+        let block_offset = offset / block_size;
+        let mut byte_offset = (offset % block_size) as usize;
 
+        // The data is written without the tree lock, the node lock keeps the extents in place
+        let _node = self.nodes.lock(block);
         let mut extents = Vec::new();
-        self.node_extents(block, block_offset, byte_offset + buf.len(), &mut extents)?;
+        {
+            let _tree = self.tree.write().unwrap();
+            self.transaction(|| self.node_ensure_len(block, block_offset * block_size + (byte_offset + buf.len()) as u64))?;
+            self.node_extents(block, block_offset, byte_offset + buf.len(), &mut extents)?;
+        }
 
         let mut i = 0;
         for extent in extents.iter() {
@@ -477,7 +1387,7 @@ impl<D: Disk> FileSystem<D> {
             let mut length = extent.length;
 
             if byte_offset > 0 && length > 0 {
-                let mut sector = [0; 512];
+                let mut sector = vec![0; block_size as usize];
                 self.read_at(block, &mut sector)?;
 
                 let sector_size = min(sector.len() as u64, length) as usize;
@@ -494,18 +1404,18 @@ impl<D: Disk> FileSystem<D> {
                 byte_offset = 0;
             }
 
-            let length_aligned = ((min(length, (buf.len() - i) as u64)/512) * 512) as usize;
+            let length_aligned = ((min(length, (buf.len() - i) as u64)/block_size) * block_size) as usize;
 
             if length_aligned > 0 {
                 let extent_buf = &buf[i..i + length_aligned];
                 self.write_at(block, extent_buf)?;
                 i += length_aligned;
-                block += (length_aligned as u64)/512;
+                block += (length_aligned as u64)/block_size;
                 length -= length_aligned as u64;
             }
 
             if length > 0 {
-                let mut sector = [0; 512];
+                let mut sector = vec![0; block_size as usize];
                 self.read_at(block, &mut sector)?;
 
                 let sector_size = min(sector.len() as u64, length) as usize;
@@ -521,22 +1431,50 @@ impl<D: Disk> FileSystem<D> {
             }
 
             assert_eq!(length, 0);
-            assert_eq!(block, extent.block + (extent.length + 511)/512);
+            assert_eq!(block, extent.block + (extent.length + block_size - 1)/block_size);
         }
 
         if i > 0 {
+            let _tree = self.tree.write().unwrap();
+            self.transaction(|| {
+                let mut node = self.node(block)?;
+                if mtime > node.1.mtime || (mtime == node.1.mtime && mtime_nsec > node.1.mtime_nsec) {
+                    node.1.mtime = mtime;
+                    node.1.mtime_nsec = mtime_nsec;
+                    self.write_at(node.0, &node.1)?;
+                }
+                Ok(())
+            })?;
+        }
+
+        Ok(i)
+    }
+
+    /// Change the mode, owner or times of a node with `f`, which may refuse with an error
+    ///
+    /// The node is locked, so the change does not race with `write_node` updating the mtime.
+    pub fn set_node_attrs<F: FnOnce(&mut Node) -> Result<()>>(&self, block: u64, f: F) -> Result<(u64, Node)> {
+        self.check_writable()?;
+
+        let _node = self.nodes.lock(block);
+        let _tree = self.tree.write().unwrap();
+        self.transaction(|| {
             let mut node = self.node(block)?;
-            if mtime > node.1.mtime || (mtime == node.1.mtime && mtime_nsec > node.1.mtime_nsec) {
-                node.1.mtime = mtime;
-                node.1.mtime_nsec = mtime_nsec;
+            let old = node.1.to_vec();
+            f(&mut node.1)?;
+            if node.1[..] != old[..] {
                 self.write_at(node.0, &node.1)?;
             }
-        }
+            Ok(node)
+        })
+    }
 
-        Ok(i)
+    pub fn node_len(&self, block: u64) -> Result<u64> {
+        let _tree = self.tree.read().unwrap();
+        self.node_len_locked(block)
     }
 
-    pub fn node_len(&mut self, block: u64) -> Result<u64> {
+    fn node_len_locked(&self, block: u64) -> Result<u64> {
         if block == 0 {
             return Err(Error::new(ENOENT));
         }
@@ -549,10 +1487,381 @@ impl<D: Disk> FileSystem<D> {
         }
 
         if node.1.next > 0 {
-            size += self.node_len(node.1.next)?;
+            size += self.node_len_locked(node.1.next)?;
             Ok(size)
         } else {
             Ok(size)
         }
     }
 }
+
+#[test]
+fn read_only_test() {
+    use disk::DiskMemory;
+
+    let fs = FileSystem::create(DiskMemory::new(1024 * 1024), 0, 0).unwrap();
+    let fs = FileSystem::open_read_only(fs.disk).unwrap();
+    let root = fs.header().1.root;
+
+    assert_eq!(fs.create_node(Node::MODE_FILE | 0o644, "test", root, 0, 0).unwrap_err().errno, EROFS);
+    assert_eq!(fs.node_set_len(root, 0).unwrap_err().errno, EROFS);
+    assert_eq!(fs.write_at(root, &Node::default()).unwrap_err().errno, EROFS);
+    assert!(fs.find_node("test", root).is_err());
+}
+
+#[test]
+fn block_size_test() {
+    use disk::DiskMemory;
+
+    assert_eq!(FileSystem::create_with_block_size(DiskMemory::new(1024 * 1024), 1000, 0, 0).err().map(|err| err.errno), Some(EINVAL));
+
+    let fs = FileSystem::create_with_block_size(DiskMemory::new(1024 * 1024), 4096, 0, 0).unwrap();
+    let root = fs.header().1.root;
+    let node = fs.create_node(Node::MODE_FILE | 0o644, "test", root, 0, 0).unwrap();
+    let data: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+    assert_eq!(fs.write_node(node.0, 100, &data, 0, 0).unwrap(), data.len());
+
+    let fs = FileSystem::open(fs.disk).unwrap();
+    assert_eq!(fs.block_size(), 4096);
+    let node = fs.find_node("test", root).unwrap();
+    assert_eq!(fs.node_len(node.0).unwrap(), 10100);
+
+    let mut buf = vec![0; 10000];
+    assert_eq!(fs.read_node(node.0, 100, &mut buf).unwrap(), buf.len());
+    assert_eq!(buf, data);
+
+    // Whole blocks go back to the free list
+    let free = fs.node_len(fs.header().1.free).unwrap();
+    fs.remove_node(Node::MODE_FILE, "test", root).unwrap();
+    assert_eq!(fs.node_len(fs.header().1.free).unwrap(), free + 4 * 4096);
+}
+
+#[test]
+fn label_test() {
+    use disk::DiskMemory;
+
+    let mut fs = FileSystem::create(DiskMemory::new(1024 * 1024), 0, 0).unwrap();
+    assert_eq!(fs.header().1.label(), Ok(""));
+    assert_eq!(fs.set_label(&"x".repeat(65)).unwrap_err().errno, ENAMETOOLONG);
+    fs.set_label("Redox root").unwrap();
+
+    let fs = FileSystem::open(fs.disk).unwrap();
+    assert_eq!(fs.header().1.label(), Ok("Redox root"));
+}
+
+#[test]
+fn header_backup_test() {
+    use disk::DiskMemory;
+
+    let fs = FileSystem::create_with_block_size(DiskMemory::new(1024 * 1024), 1024, 0, 0).unwrap();
+    let root = fs.header().1.root;
+    fs.create_node(Node::MODE_FILE | 0o644, "test", root, 0, 0).unwrap();
+    assert_eq!(fs.header().1.backup_blocks(), vec![512, 1023]);
+    assert!(fs.free_extents().unwrap().iter().all(|extent| extent.block + extent.length/1024 <= 512 || extent.block > 512));
+
+    // Damage the primary header, the first backup is used instead
+    let mut header = [0; 512];
+    fs.disk.read_at(0, &mut header).unwrap();
+    header[40] ^= 1;
+    fs.disk.write_at(0, &header).unwrap();
+
+    let mut fs = FileSystem::open(fs.disk).unwrap();
+    assert_eq!(fs.header().0, 0);
+    assert!(fs.header().1.copy == 1);
+    assert!(fs.find_node("test", root).is_ok());
+    let mut children = Vec::new();
+    fs.child_nodes(&mut children, fs.header().1.root).unwrap();
+    assert_eq!(children.len(), 1);
+    assert!(children[0].0 > fs.header().0);
+
+    // Rewriting the header repairs the primary
+    fs.set_label("repaired").unwrap();
+    let fs = FileSystem::open(fs.disk).unwrap();
+    assert_eq!(fs.header().0, 0);
+    assert_eq!(fs.header().1.label(), Ok("repaired"));
+
+    // A valid header in the data, such as that of an image stored in a file, is not used instead
+    // of the backups
+    let mut image = FileSystem::create(DiskMemory::new(64 * 1024), 0, 0).unwrap();
+    image.set_label("image").unwrap();
+    let node = fs.create_node(Node::MODE_FILE | 0o644, "image", root, 0, 0).unwrap();
+    fs.write_node(node.0, 0, image.disk.as_slice(), 0, 0).unwrap();
+    fs.disk.read_at(0, &mut header).unwrap();
+    header[40] ^= 1;
+    fs.disk.write_at(0, &header).unwrap();
+
+    let fs = FileSystem::open(fs.disk).unwrap();
+    assert!(fs.header().1.copy == 1);
+    assert_eq!(fs.header().1.label(), Ok("repaired"));
+
+    // The same holds when the signature itself is damaged
+    header[0] ^= 1;
+    fs.disk.write_at(0, &header).unwrap();
+    let fs = FileSystem::open(fs.disk).unwrap();
+    assert!(fs.header().1.copy == 1);
+    assert_eq!(fs.header().1.label(), Ok("repaired"));
+
+    // Headers with feature flags need a checksum
+    let mut header = Header::default();
+    header.copy_from_slice(&fs.header().1);
+    header.checksum = 0;
+    assert!(! header.valid());
+    header.compat = 0;
+    header.ro_compat = 0;
+    header.incompat = 0;
+    assert!(header.valid());
+}
+
+#[test]
+fn features_test() {
+    use disk::DiskMemory;
+
+    let mut fs = FileSystem::create(DiskMemory::new(1024 * 1024), 0, 0).unwrap();
+    fs.header.get_mut().unwrap().1.compat |= 1 << 63;
+    fs.write_header().unwrap();
+    let mut fs = FileSystem::open(fs.disk).unwrap();
+    assert!(! fs.read_only);
+
+    fs.header.get_mut().unwrap().1.ro_compat |= 1 << 63;
+    fs.write_header().unwrap();
+    let fs = FileSystem::open(fs.disk).unwrap();
+    assert!(fs.read_only);
+
+    // A read-only filesystem refuses to write its header, so it is written directly
+    let mut header = fs.header().1;
+    header.incompat |= 1 << 63;
+    header.update_checksum();
+    fs.disk.write_at(0, &header).unwrap();
+    assert_eq!(FileSystem::open(fs.disk).err().map(|err| err.errno), Some(EOPNOTSUPP));
+}
+
+#[test]
+fn concurrent_test() {
+    use std::sync::Arc;
+    use std::thread;
+    use disk::DiskMemory;
+
+    let fs = Arc::new(FileSystem::create(DiskMemory::new(4 * 1024 * 1024), 0, 0).unwrap());
+    let root = fs.header().1.root;
+
+    let threads: Vec<_> = (0..4u8).map(|i| {
+        let fs = fs.clone();
+        thread::spawn(move || {
+            let node = fs.create_node(Node::MODE_FILE | 0o644, &format!("file{}", i), root, 0, 0).unwrap();
+            for j in 0..16 {
+                fs.write_node(node.0, j * 1000, &[i; 1000], 0, 0).unwrap();
+            }
+
+            let mut data = [0; 16000];
+            assert_eq!(fs.read_node(node.0, 0, &mut data).unwrap(), data.len());
+            assert!(data.iter().all(|&b| b == i));
+        })
+    }).collect();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    let mut children = Vec::new();
+    fs.child_nodes(&mut children, root).unwrap();
+    assert_eq!(children.len(), 4);
+}
+
+#[test]
+fn shared_read_test() {
+    use std::sync::Arc;
+    use std::thread;
+    use disk::{Disk, DiskCache, DiskMemory};
+
+    fn send_sync<T: Send + Sync>(_: &T) {}
+
+    let fs = Arc::new(FileSystem::create(DiskCache::new(Box::new(DiskMemory::new(4 * 1024 * 1024)) as Box<dyn Disk + Send + Sync>), 0, 0).unwrap());
+    send_sync(&fs);
+    let root = fs.header().1.root;
+    let block = fs.create_node(Node::MODE_FILE | 0o644, "file", root, 0, 0).unwrap().0;
+    fs.write_node(block, 0, &[1; 16000], 0, 0).unwrap();
+
+    // Readers share the node, but never see a write half done
+    let threads: Vec<_> = (0..4u8).map(|i| {
+        let fs = fs.clone();
+        thread::spawn(move || for _ in 0..16 {
+            if i == 0 {
+                fs.write_node(block, 0, &[2; 16000], 0, 0).unwrap();
+            } else {
+                let mut data = [0; 16000];
+                assert_eq!(fs.read_node(block, 0, &mut data).unwrap(), data.len());
+                assert!(data.iter().all(|&b| b == data[0]));
+            }
+        })
+    }).collect();
+
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+#[test]
+fn journal_test() {
+    use disk::{DiskFault, DiskMemory, Fault};
+
+    let mut fs = FileSystem::create(DiskFault::new(DiskMemory::new(1024 * 1024)), 0, 0).unwrap();
+    let root = fs.header().1.root;
+    let free_block = fs.header().1.free;
+    assert!(fs.header().1.journal != 0);
+    let free = fs.node_len(free_block).unwrap();
+
+    // Crash after the transaction is committed, before any of it is written in place
+    let writes = fs.disk.writes();
+    fs.disk.fail_write(writes + 1, Fault::Error(EIO));
+    assert!(fs.create_node(Node::MODE_FILE | 0o644, "committed", root, 0, 0).is_err());
+
+    // The committed transaction stays visible, but nothing else can change
+    assert!(fs.find_node("committed", root).is_ok());
+    assert_eq!(fs.create_node(Node::MODE_FILE | 0o644, "refused", root, 0, 0).err().map(|err| err.errno), Some(EROFS));
+
+    let fs = FileSystem::open(fs.disk.into_inner()).unwrap();
+    assert!(fs.find_node("committed", root).is_ok());
+    assert_eq!(fs.node_len(free_block).unwrap(), free - 512);
+
+    // Crash while writing the journal, the transaction is discarded
+    let mut fs = FileSystem::open(DiskFault::new(fs.disk)).unwrap();
+    let writes = fs.disk.writes();
+    fs.disk.fail_write(writes, Fault::Torn(700));
+    assert!(fs.create_node(Node::MODE_FILE | 0o644, "torn", root, 0, 0).is_err());
+
+    let fs = FileSystem::open(fs.disk.into_inner()).unwrap();
+    assert!(fs.find_node("torn", root).is_err());
+    assert_eq!(fs.node_len(free_block).unwrap(), free - 512);
+    fs.create_node(Node::MODE_FILE | 0o644, "torn", root, 0, 0).unwrap();
+}
+
+#[test]
+fn cow_test() {
+    use disk::{DiskFault, DiskMemory, Fault};
+
+    let mut fs = FileSystem::create(DiskMemory::new(1024 * 1024), 0, 0).unwrap();
+    fs.set_copy_on_write().unwrap();
+    let root = fs.header().1.root;
+    let data: Vec<u8> = (0..3000).map(|i| i as u8).collect();
+    let node = fs.create_node(Node::MODE_FILE | 0o644, "a", root, 0, 0).unwrap();
+    fs.write_node(node.0, 0, &data, 0, 0).unwrap();
+    let generation = fs.generation();
+    assert!(generation > 1);
+
+    // The header in memory follows the commits
+    let header = fs.header();
+    let mut primary = Header::default();
+    fs.disk.read_at(0, &mut primary).unwrap();
+    assert_eq!(header.0, 0);
+    assert_eq!({ header.1.generation }, generation);
+    assert_eq!({ header.1.map }, { primary.map });
+    assert_eq!({ header.1.pending }, { primary.pending });
+
+    // Changed nodes are found through the map, under the block they were created at
+    let fs = FileSystem::open(fs.disk).unwrap();
+    assert_eq!(fs.generation(), generation);
+    assert!(fs.header().1.journal == 0);
+    let mut buf = vec![0; 3000];
+    assert_eq!(fs.read_node(fs.find_node("a", root).unwrap().0, 0, &mut buf).unwrap(), buf.len());
+    assert_eq!(buf, data);
+
+    // A crash at any write of a commit leaves the file system as it was before or after it, a
+    // torn primary header is replaced by a backup
+    for &fault in [Fault::Error(EIO), Fault::Torn(150)].iter() {
+        let mut n = 0;
+        loop {
+            let mut fs = FileSystem::open(DiskFault::new(DiskMemory::load(&fs.disk).unwrap())).unwrap();
+            let writes = fs.disk.writes();
+            fs.disk.fail_write(writes + n, fault);
+            let result = fs.create_node(Node::MODE_FILE | 0o644, "b", root, 0, 0)
+                .and_then(|_| fs.remove_node(Node::MODE_FILE, "a", root));
+            if fs.disk.writes() <= writes + n {
+                result.unwrap();
+                break;
+            }
+            assert!(result.is_err());
+
+            let fs = FileSystem::open(fs.disk.into_inner()).unwrap();
+            let a = fs.find_node("a", root);
+            let b = fs.find_node("b", root);
+            assert!(a.is_ok() || b.is_ok());
+            if let Ok(a) = a {
+                assert_eq!(fs.read_node(a.0, 0, &mut buf).unwrap(), buf.len());
+                assert_eq!(buf, data);
+            }
+            let c = fs.create_node(Node::MODE_FILE | 0o644, "c", root, 0, 0).unwrap();
+            assert_eq!(fs.write_node(c.0, 0, &data, 0, 0).unwrap(), data.len());
+            let fs = FileSystem::open(fs.disk).unwrap();
+            assert!(fs.find_node("c", root).is_ok());
+
+            n += 1;
+        }
+        assert!(n > 0);
+    }
+
+    // Removed files return to the free list after the next commit
+    let free_block = fs.header().1.free;
+    let free = fs.node_len(free_block).unwrap();
+    fs.remove_node(Node::MODE_FILE, "a", root).unwrap();
+    fs.create_node(Node::MODE_DIR | 0o755, "d", root, 0, 0).unwrap();
+    assert!(fs.node_len(free_block).unwrap() >= free + 3072);
+}
+
+#[test]
+fn set_node_attrs_test() {
+    use disk::DiskMemory;
+    use syscall::error::EPERM;
+
+    let mut fs = FileSystem::create(DiskMemory::new(1024 * 1024), 0, 0).unwrap();
+    fs.set_copy_on_write().unwrap();
+    let root = fs.header().1.root;
+    let node = fs.create_node(Node::MODE_FILE | 0o644, "a", root, 0, 0).unwrap();
+    let generation = fs.generation();
+
+    // A refused change writes nothing
+    assert_eq!(fs.set_node_attrs(node.0, |_| Err(Error::new(EPERM))).unwrap_err().errno, EPERM);
+    assert_eq!(fs.generation(), generation);
+
+    // The change is committed like any other, instead of overwriting the node of the last commit
+    let changed = fs.set_node_attrs(node.0, |node| {
+        node.mode = Node::MODE_FILE | 0o600;
+        node.uid = 1000;
+        Ok(())
+    }).unwrap();
+    assert_eq!({ changed.1.uid }, 1000);
+    assert_eq!(fs.generation(), generation + 1);
+
+    let fs = FileSystem::open(fs.disk).unwrap();
+    let node = fs.find_node("a", root).unwrap();
+    assert_eq!({ node.1.mode }, Node::MODE_FILE | 0o600);
+    assert_eq!({ node.1.uid }, 1000);
+}
+
+#[test]
+fn write_node_zero_test() {
+    use disk::{DiskFault, DiskMemory, Fault};
+
+    // A crash at any write while a file grows never shows the contents of a removed one
+    for n in 0.. {
+        let mut fs = FileSystem::create(DiskFault::new(DiskMemory::new(1024 * 1024)), 0, 0).unwrap();
+        let root = fs.header().1.root;
+        let old = fs.create_node(Node::MODE_FILE | 0o644, "old", root, 0, 0).unwrap();
+        fs.write_node(old.0, 0, &[7; 4096], 0, 0).unwrap();
+        fs.remove_node(Node::MODE_FILE, "old", root).unwrap();
+        let new = fs.create_node(Node::MODE_FILE | 0o644, "new", root, 0, 0).unwrap();
+
+        let writes = fs.disk.writes();
+        fs.disk.fail_write(writes + n, Fault::Error(EIO));
+        let written = fs.write_node(new.0, 1024, &[1; 3072], 0, 0).is_ok();
+
+        let fs = FileSystem::open(fs.disk.into_inner()).unwrap();
+        let mut data = [0; 4096];
+        let count = fs.read_node(new.0, 0, &mut data).unwrap();
+        assert!(data[..count].iter().all(|&b| b != 7));
+        if written {
+            assert_eq!(count, data.len());
+            assert!(data[1024..].iter().all(|&b| b == 1));
+            break;
+        }
+    }
+}
@@ -1,7 +1,12 @@
-use std::{fmt, mem, slice};
+use std::{cmp, fmt, mem, slice, str};
 use std::ops::{Deref, DerefMut};
 
+use disk::Crc32c;
+
 /// The header of the filesystem
+///
+/// The primary copy is block 0. Unless the filesystem is tiny, backup copies are kept in the
+/// middle block, number `size/block_size/2`, and in the last block, number `size/block_size - 1`.
 #[repr(packed)]
 pub struct Header {
     /// Signature, should be b"RedoxFS\0"
@@ -16,13 +21,57 @@ pub struct Header {
     pub root: u64,
     /// Block of free space node
     pub free: u64,
+    /// Block size in bytes, a power of two from 512 to 65536, or 0 for 512
+    pub block_size: u64,
+    /// Volume label, UTF-8 padded with zeros
+    pub label: [u8; 64],
+    /// CRC-32C of the header with this field set to zero, or 0 if not recorded, which is only
+    /// allowed without feature flags
+    pub checksum: u32,
+    /// Number of backup copies
+    pub backups: u16,
+    /// 0 for the primary copy, or the number of the backup copy
+    pub copy: u16,
+    /// Features that can be ignored by implementations that do not know them
+    pub compat: u64,
+    /// Features that must be known to change the filesystem, but not to read it
+    pub ro_compat: u64,
+    /// Features that must be known to use the filesystem at all
+    pub incompat: u64,
+    /// Block of the metadata journal, or 0 if there is none
+    pub journal: u64,
+    /// Number of blocks in the metadata journal
+    pub journal_len: u64,
+    /// Number of copy-on-write commits
+    pub generation: u64,
+    /// Block of the root of the copy-on-write map, or 0 if no node was moved
+    pub map: u64,
+    /// First block of the list of extents freed by the next copy-on-write commit, or 0
+    pub pending: u64,
     /// Padding
-    pub padding: [u8; 456]
+    pub padding: [u8; 312]
 }
 
 impl Header {
     pub const SIGNATURE: &'static [u8; 8] = b"RedoxFS\0";
     pub const VERSION: u64 = 2;
+    /// Backup copies of the header are kept
+    pub const COMPAT_BACKUPS: u64 = 1 << 0;
+    pub const COMPAT_SUPPORTED: u64 = Header::COMPAT_BACKUPS;
+
+    /// Metadata changes go through the journal, which versions that do not know it would not
+    /// replay after a crash, so they may only read the file system
+    pub const RO_COMPAT_JOURNAL: u64 = 1 << 0;
+    pub const RO_COMPAT_SUPPORTED: u64 = Header::RO_COMPAT_JOURNAL;
+
+    /// Blocks are not 512 bytes
+    pub const INCOMPAT_BLOCK_SIZE: u64 = 1 << 0;
+    /// Nodes are moved when they change, and found through the copy-on-write map
+    pub const INCOMPAT_COW: u64 = 1 << 1;
+    pub const INCOMPAT_SUPPORTED: u64 = Header::INCOMPAT_BLOCK_SIZE | Header::INCOMPAT_COW;
+
+    /// Offset of the checksum field
+    const CHECKSUM_OFFSET: usize = 8 + 8 + 16 + 8 + 8 + 8 + 8 + 64;
 
     pub fn default() -> Header {
         Header {
@@ -32,36 +81,162 @@ impl Header {
             size: 0,
             root: 0,
             free: 0,
-            padding: [0; 456]
+            block_size: 0,
+            label: [0; 64],
+            checksum: 0,
+            backups: 0,
+            copy: 0,
+            compat: 0,
+            ro_compat: 0,
+            incompat: 0,
+            journal: 0,
+            journal_len: 0,
+            generation: 0,
+            map: 0,
+            pending: 0,
+            padding: [0; 312]
         }
     }
 
-    pub fn new(size: u64, root: u64, free: u64) -> Header {
+    pub fn new(uuid: [u8; 16], size: u64, block_size: u64, root: u64, free: u64) -> Header {
         Header {
             signature: *Header::SIGNATURE,
             version: Header::VERSION,
-            uuid: [0; 16],
+            uuid: uuid,
             size: size,
             root: root,
             free: free,
-            padding: [0; 456]
+            block_size: block_size,
+            label: [0; 64],
+            checksum: 0,
+            backups: 0,
+            copy: 0,
+            compat: 0,
+            ro_compat: 0,
+            incompat: 0,
+            journal: 0,
+            journal_len: 0,
+            generation: 0,
+            map: 0,
+            pending: 0,
+            padding: [0; 312]
         }
     }
 
+    /// Check if a block size can be used by a file system
+    pub fn valid_block_size(block_size: u64) -> bool {
+        (512..=65536).contains(&block_size) && block_size.is_power_of_two()
+    }
+
     pub fn valid(&self) -> bool {
+        // Only headers written before the checksum and the feature flags were added may lack one
+        let checksum = self.checksum;
+        let features = self.compat | self.ro_compat | self.incompat;
         &self.signature == Header::SIGNATURE && self.version == Header::VERSION
+            && Header::valid_block_size(self.block_size())
+            && (checksum == self.compute_checksum() || (checksum == 0 && features == 0))
+    }
+
+    /// CRC-32C of the header, as if the checksum field was zero
+    pub fn compute_checksum(&self) -> u32 {
+        let mut data = [0; 512];
+        data.copy_from_slice(self);
+        let offset = Header::CHECKSUM_OFFSET;
+        for b in data[offset..offset + 4].iter_mut() {
+            *b = 0;
+        }
+        Crc32c::new().checksum(&data)
+    }
+
+    pub fn update_checksum(&mut self) {
+        self.checksum = self.compute_checksum();
+    }
+
+    /// Blocks holding the backup copies, the middle and the last block
+    pub fn backup_blocks(&self) -> Vec<u64> {
+        let blocks = self.size/self.block_size();
+        let all = [blocks/2, blocks - 1];
+        all[..cmp::min(self.backups as usize, all.len())].to_vec()
+    }
+
+    pub fn label(&self) -> Result<&str, str::Utf8Error> {
+        let len = self.label.iter().position(|&b| b == 0).unwrap_or(self.label.len());
+        str::from_utf8(&self.label[..len])
+    }
+
+    /// The UUID folded to 64 bits, used as the device number of files
+    pub fn fsid(&self) -> u64 {
+        let uuid = self.uuid;
+        uuid.iter().enumerate().fold(0, |fsid, (i, &b)| fsid ^ ((b as u64) << (i % 8 * 8)))
     }
+
+    /// Block size in bytes, file systems created before it was recorded use 512
+    pub fn block_size(&self) -> u64 {
+        if self.block_size == 0 {
+            512
+        } else {
+            self.block_size
+        }
+    }
+}
+
+/// Format a UUID as 32 hex digits in groups of 8-4-4-4-12
+pub fn format_uuid(uuid: &[u8; 16]) -> String {
+    let mut string = String::new();
+    for (i, b) in uuid.iter().enumerate() {
+        if i == 4 || i == 6 || i == 8 || i == 10 {
+            string.push('-');
+        }
+        string.push_str(&format!("{:02x}", b));
+    }
+    string
+}
+
+/// Parse a UUID formatted by `format_uuid`, in either case
+pub fn parse_uuid(string: &str) -> Option<[u8; 16]> {
+    // Slicing below counts bytes, which are only characters for ASCII
+    if ! string.bytes().all(|b| b == b'-' || b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let groups: Vec<&str> = string.split('-').collect();
+    if groups.iter().map(|group| group.len()).collect::<Vec<usize>>() != [8, 4, 4, 4, 12] {
+        return None;
+    }
+
+    let digits: String = groups.concat();
+    let mut uuid = [0; 16];
+    for (i, b) in uuid.iter_mut().enumerate() {
+        match u8::from_str_radix(&digits[i * 2..i * 2 + 2], 16) {
+            Ok(value) => *b = value,
+            Err(_) => return None
+        }
+    }
+    Some(uuid)
 }
 
 impl fmt::Debug for Header {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_struct("Header")
             .field("signature", &self.signature)
-            .field("version", &self.version)
-            .field("uuid", &self.uuid)
-            .field("size", &self.size)
-            .field("root", &self.root)
-            .field("free", &self.free)
+            .field("version", &{ self.version })
+            .field("uuid", &format_uuid(&self.uuid))
+            .field("size", &{ self.size })
+            .field("root", &{ self.root })
+            .field("free", &{ self.free })
+            .field("block_size", &self.block_size())
+            .field("label", &self.label())
+            .field("checksum", &{ self.checksum })
+            .field("backups", &{ self.backups })
+            .field("copy", &{ self.copy })
+            .field("compat", &{ self.compat })
+            .field("ro_compat", &{ self.ro_compat })
+            .field("incompat", &{ self.incompat })
+            .field("journal", &{ self.journal })
+            .field("journal_len", &{ self.journal_len })
+            .field("generation", &{ self.generation })
+            .field("map", &{ self.map })
+            .field("pending", &{ self.pending })
             .finish()
     }
 }
@@ -87,3 +262,13 @@ impl DerefMut for Header {
 fn header_size_test() {
     assert_eq!(mem::size_of::<Header>(), 512);
 }
+
+#[test]
+fn uuid_test() {
+    let uuid = [0x12, 0x3e, 0x45, 0x67, 0xe8, 0x9b, 0x12, 0xd3, 0xa4, 0x56, 0x42, 0x66, 0x14, 0x17, 0x40, 0x00];
+    assert_eq!(format_uuid(&uuid), "123e4567-e89b-12d3-a456-426614174000");
+    assert_eq!(parse_uuid("123E4567-E89B-12D3-A456-426614174000"), Some(uuid));
+    assert_eq!(parse_uuid("123e4567e89b12d3a456426614174000"), None);
+    assert_eq!(parse_uuid("123e4567-e89b-12d3-a456-42661417400g"), None);
+    assert_eq!(parse_uuid("123e4567-e89b-12d3-a456-4266141740é"), None);
+}
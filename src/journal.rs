@@ -0,0 +1,159 @@
+use std::cmp;
+use std::collections::BTreeMap;
+use std::mem;
+use std::thread::{self, ThreadId};
+
+use disk::Crc32c;
+
+const SIGNATURE: &'static [u8; 8] = b"RFSJRNL\0";
+
+/// Number of sectors a transaction can change, limited by the entries of the descriptor
+const ENTRIES: usize = (512 - 32)/8;
+
+/// Sectors of the journal used by a file system, whatever its block size
+pub const JOURNAL_SECTORS: u64 = 1 + ENTRIES as u64;
+
+fn read_le(buf: &[u8]) -> u64 {
+    buf.iter().rev().fold(0, |value, &b| value << 8 | b as u64)
+}
+
+fn write_le(buf: &mut [u8], value: u64) {
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = (value >> (i * 8)) as u8;
+    }
+}
+
+/// The metadata changes of one operation, written to the journal before their own blocks
+///
+/// The journal starts with a descriptor sector: the signature, the number of sectors, a CRC-32C
+/// of the descriptor and the sectors, then the number of each sector relative to the header. The
+/// sectors follow the descriptor. A transaction is committed once the descriptor and its sectors
+/// are on the disk, and the descriptor is cleared after they have been written in place.
+pub struct Journal {
+    /// Thread running the current transaction
+    owner: Option<ThreadId>,
+    /// Sectors changed by the current transaction, or recovered from the journal of a read-only
+    /// file system
+    sectors: BTreeMap<u64, Vec<u8>>,
+}
+
+impl Journal {
+    pub fn new() -> Journal {
+        Journal {
+            owner: None,
+            sectors: BTreeMap::new()
+        }
+    }
+
+    /// Start a transaction on the current thread, whose writes are kept until `take`
+    pub fn begin(&mut self) {
+        self.owner = Some(thread::current().id());
+        self.sectors.clear();
+    }
+
+    /// End the transaction, returning the sectors it changed
+    pub fn take(&mut self) -> BTreeMap<u64, Vec<u8>> {
+        self.owner = None;
+        mem::take(&mut self.sectors)
+    }
+
+    /// Sectors written by the transaction
+    pub fn written(&self) -> Vec<u64> {
+        self.sectors.keys().cloned().collect()
+    }
+
+    /// Keep sectors recovered from the journal, when they cannot be written in place
+    pub fn recover(&mut self, sectors: BTreeMap<u64, Vec<u8>>) {
+        self.sectors = sectors;
+    }
+
+    /// Whether the sectors are seen by the current thread
+    fn visible(&self) -> bool {
+        match self.owner {
+            Some(owner) => owner == thread::current().id(),
+            None => true
+        }
+    }
+
+    /// Replace the parts of a buffer read from `sector` that were changed by the transaction
+    pub fn read(&self, sector: u64, buffer: &mut [u8]) {
+        if self.sectors.is_empty() || ! self.visible() {
+            return;
+        }
+
+        let end = sector + (buffer.len() as u64 + 511)/512;
+        for (&number, data) in self.sectors.range(sector..end) {
+            let offset = ((number - sector) * 512) as usize;
+            let len = cmp::min(512, buffer.len() - offset);
+            buffer[offset..offset + len].copy_from_slice(&data[..len]);
+        }
+    }
+
+    /// Keep a write to `sector` in the transaction, if the current thread is running one
+    ///
+    /// Metadata is written in whole sectors.
+    pub fn write(&mut self, sector: u64, buffer: &[u8]) -> bool {
+        if self.owner != Some(thread::current().id()) {
+            return false;
+        }
+
+        for (i, chunk) in buffer.chunks(512).enumerate() {
+            let data = self.sectors.entry(sector + i as u64).or_insert_with(|| vec![0; 512]);
+            data[..chunk.len()].copy_from_slice(chunk);
+        }
+        true
+    }
+}
+
+/// The contents of the journal for a transaction: the descriptor followed by the sectors
+///
+/// Returns `None` if there are too many sectors for the descriptor.
+pub fn encode(sectors: &BTreeMap<u64, Vec<u8>>) -> Option<Vec<u8>> {
+    if sectors.len() > ENTRIES {
+        return None;
+    }
+
+    let mut data = vec![0; 512 * (1 + sectors.len())];
+    data[..8].copy_from_slice(SIGNATURE);
+    write_le(&mut data[8..16], sectors.len() as u64);
+    for (i, (&number, sector)) in sectors.iter().enumerate() {
+        write_le(&mut data[32 + i * 8..40 + i * 8], number);
+        data[512 * (i + 1)..512 * (i + 2)].copy_from_slice(sector);
+    }
+
+    let checksum = Crc32c::new().checksum(&data) as u64;
+    write_le(&mut data[16..20], checksum);
+    Some(data)
+}
+
+/// Number of sectors following a descriptor, or `None` if the journal is empty
+pub fn count(descriptor: &[u8]) -> Option<usize> {
+    if &descriptor[..8] == SIGNATURE {
+        Some(cmp::min(read_le(&descriptor[8..16]), ENTRIES as u64) as usize)
+    } else {
+        None
+    }
+}
+
+/// The sectors of a committed transaction, read from the journal with `count` sectors after the
+/// descriptor, or `None` if it is incomplete
+pub fn decode(data: &[u8]) -> Option<BTreeMap<u64, Vec<u8>>> {
+    let count = match count(data) {
+        Some(count) if data.len() == 512 * (1 + count) => count,
+        _ => return None
+    };
+
+    let checksum = read_le(&data[16..20]) as u32;
+    let mut copy = data.to_vec();
+    write_le(&mut copy[16..20], 0);
+    if Crc32c::new().checksum(&copy) != checksum {
+        return None;
+    }
+
+    let mut sectors = BTreeMap::new();
+    for i in 0..count {
+        let number = read_le(&data[32 + i * 8..40 + i * 8]);
+        sectors.insert(number, data[512 * (i + 1)..512 * (i + 2)].to_vec());
+    }
+    Some(sectors)
+}
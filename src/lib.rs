@@ -2,21 +2,34 @@
 #![crate_type="lib"]
 
 #![deny(warnings)]
+// Struct fields are initialized as `field: field` and constants are declared `&'static`, and
+// the on-disk types have `default` constructors
+#![allow(clippy::redundant_field_names, clippy::redundant_static_lifetimes, clippy::should_implement_trait)]
 
+#[cfg(unix)]
+extern crate libc;
 extern crate syscall;
 
-pub use self::disk::{Disk, DiskCache, DiskFile};
+pub use self::disk::{Disk, DiskCache, DiskCacheStats, DiskChecksum, DiskCrypt, DiskFault, DiskFile, DiskMemory, DiskMirror, DiskOverlay, DiskPartition, DiskQcow2, DiskTrace, Fault, Partition, PartitionKind};
+pub use self::disk::{ReplayStats, TraceOp, TraceReader, TraceRecord, TRACE_DATA, TRACE_HASH, read_passphrase, replay};
+pub use self::disk::{mounted, open_crypt, open_disk, open_mirror, open_partition};
 pub use self::ex_node::ExNode;
 pub use self::extent::Extent;
 pub use self::filesystem::FileSystem;
-pub use self::header::Header;
+pub use self::header::{Header, format_uuid, parse_uuid};
 pub use self::mount::mount;
 pub use self::node::Node;
+pub use self::sparse::{SparseReader, SparseStats, write_sparse};
+pub use self::upgrade::{Upgrade, UpgradeBlock, detect_version, plan_upgrade, restore_backup};
 
+mod cow;
 mod disk;
 mod ex_node;
 mod extent;
 mod filesystem;
 mod header;
+mod journal;
 mod mount;
 mod node;
+mod sparse;
+mod upgrade;
@@ -9,6 +9,7 @@ use std::time::{SystemTime, UNIX_EPOCH};
 
 use disk::Disk;
 use filesystem;
+use header::format_uuid;
 use node::Node;
 
 use self::fuse::{FileType, FileAttr, Filesystem, Request, ReplyData, ReplyEntry, ReplyAttr, ReplyCreate, ReplyDirectory, ReplyEmpty, ReplyStatfs, ReplyWrite, Session};
@@ -19,9 +20,22 @@ const TTL: Timespec = Timespec { sec: 1, nsec: 0 };                 // 1 second
 const NULL_TIME: Timespec = Timespec { sec: 0, nsec: 0 };
 
 pub fn mount<D: Disk, P: AsRef<Path>, F: FnMut()>(filesystem: filesystem::FileSystem<D>, mountpoint: &P, mut callback: F, options: &[&OsStr]) -> io::Result<()> {
+    // Shown as the source of the mount by df and file managers, as FUSE has no device of its own
+    let fsname = match filesystem.header().1.label() {
+        Ok(label) if ! label.is_empty() => format!("fsname={}", label.replace(',', "\\,")),
+        _ => format!("fsname=UUID={}", format_uuid(&filesystem.header().1.uuid))
+    };
+    let mut options = options.to_vec();
+    options.push(OsStr::new("-o"));
+    options.push(OsStr::new(&fsname));
+    if filesystem.read_only {
+        options.push(OsStr::new("-o"));
+        options.push(OsStr::new("ro"));
+    }
+
     let mut session = Session::new(Fuse {
         fs: filesystem
-    }, mountpoint.as_ref(), options)?;
+    }, mountpoint.as_ref(), &options)?;
 
     callback();
 
@@ -70,7 +84,7 @@ impl<D: Disk> Filesystem for Fuse<D> {
                 reply.entry(&TTL, &node_attr(&node), 0);
             },
             Err(err) => {
-                reply.error(err.errno as i32);
+                reply.error(err.errno);
             }
         }
     }
@@ -81,7 +95,7 @@ impl<D: Disk> Filesystem for Fuse<D> {
                 reply.attr(&TTL, &node_attr(&node));
             },
             Err(err) => {
-                reply.error(err.errno as i32);
+                reply.error(err.errno);
             }
         }
     }
@@ -91,85 +105,37 @@ impl<D: Disk> Filesystem for Fuse<D> {
                 _atime: Option<Timespec>, mtime: Option<Timespec>, _fh: Option<u64>,
                 _crtime: Option<Timespec>, _chgtime: Option<Timespec>, _bkuptime: Option<Timespec>,
                 _flags: Option<u32>, reply: ReplyAttr) {
-        if let Some(mode) = mode {
-            match self.fs.node(block) {
-                Ok(mut node) => if node.1.mode & Node::MODE_PERM != mode as u16 & Node::MODE_PERM {
-                    // println!("Chmod {:?}:{:o}:{:o}", node.1.name(), node.1.mode, mode);
-                    node.1.mode = (node.1.mode & Node::MODE_TYPE) | (mode as u16 & Node::MODE_PERM);
-                    if let Err(err) = self.fs.write_at(node.0, &node.1) {
-                        reply.error(err.errno as i32);
-                        return;
-                    }
-                },
-                Err(err) => {
-                    reply.error(err.errno as i32);
-                    return;
-                }
-            }
-        }
-
-        if let Some(uid) = uid {
-            match self.fs.node(block) {
-                Ok(mut node) => if node.1.uid != uid {
-                    node.1.uid = uid;
-                    if let Err(err) = self.fs.write_at(node.0, &node.1) {
-                        reply.error(err.errno as i32);
-                        return;
-                    }
-                },
-                Err(err) => {
-                    reply.error(err.errno as i32);
-                    return;
-                }
-            }
-        }
-
-        if let Some(gid) = gid {
-            match self.fs.node(block) {
-                Ok(mut node) => if node.1.gid != gid {
-                    node.1.gid = gid;
-                    if let Err(err) = self.fs.write_at(node.0, &node.1) {
-                        reply.error(err.errno as i32);
-                        return;
-                    }
-                },
-                Err(err) => {
-                    reply.error(err.errno as i32);
-                    return;
-                }
-            }
-        }
-
         if let Some(size) = size {
             if let Err(err) = self.fs.node_set_len(block, size) {
-                reply.error(err.errno as i32);
+                reply.error(err.errno);
                 return;
             }
         }
 
-        if let Some(mtime) = mtime {
-            match self.fs.node(block) {
-                Ok(mut node) => {
-                    node.1.mtime = mtime.sec as u64;
-                    node.1.mtime_nsec = mtime.nsec as u32;
-                    if let Err(err) = self.fs.write_at(node.0, &node.1) {
-                        reply.error(err.errno as i32);
-                        return;
-                    }
-                },
-                Err(err) => {
-                    reply.error(err.errno as i32);
-                    return;
-                }
+        let result = self.fs.set_node_attrs(block, |node| {
+            if let Some(mode) = mode {
+                // println!("Chmod {:?}:{:o}:{:o}", node.name(), node.mode, mode);
+                node.mode = (node.mode & Node::MODE_TYPE) | (mode as u16 & Node::MODE_PERM);
             }
-        }
+            if let Some(uid) = uid {
+                node.uid = uid;
+            }
+            if let Some(gid) = gid {
+                node.gid = gid;
+            }
+            if let Some(mtime) = mtime {
+                node.mtime = mtime.sec as u64;
+                node.mtime_nsec = mtime.nsec as u32;
+            }
+            Ok(())
+        });
 
-        match self.fs.node(block) {
+        match result {
             Ok(node) => {
                 reply.attr(&TTL, &node_attr(&node));
             },
             Err(err) => {
-                reply.error(err.errno as i32);
+                reply.error(err.errno);
             }
         }
     }
@@ -181,29 +147,48 @@ impl<D: Disk> Filesystem for Fuse<D> {
                 reply.data(&data[..count]);
             },
             Err(err) => {
-                reply.error(err.errno as i32);
+                reply.error(err.errno);
             }
         }
     }
 
     fn write(&mut self, _req: &Request, block: u64, _fh: u64, offset: u64, data: &[u8], _flags: u32, reply: ReplyWrite) {
         let mtime = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-        match self.fs.write_node(block, offset, &data, mtime.as_secs(), mtime.subsec_nanos()) {
+        match self.fs.write_node(block, offset, data, mtime.as_secs(), mtime.subsec_nanos()) {
             Ok(count) => {
                 reply.written(count as u32);
             },
             Err(err) => {
-                reply.error(err.errno as i32);
+                reply.error(err.errno);
             }
         }
     }
 
+    // Called on every close, which does not ask for the data to reach the disk
     fn flush(&mut self, _req: &Request, _ino: u64, _fh: u64, _lock_owner: u64, reply: ReplyEmpty) {
         reply.ok();
     }
 
     fn fsync(&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
-        reply.ok();
+        match self.fs.sync() {
+            Ok(()) => {
+                reply.ok();
+            },
+            Err(err) => {
+                reply.error(err.errno);
+            }
+        }
+    }
+
+    fn fsyncdir(&mut self, _req: &Request, _ino: u64, _fh: u64, _datasync: bool, reply: ReplyEmpty) {
+        match self.fs.sync() {
+            Ok(()) => {
+                reply.ok();
+            },
+            Err(err) => {
+                reply.error(err.errno);
+            }
+        }
     }
 
     fn readdir(&mut self, _req: &Request, parent_block: u64, _fh: u64, offset: u64, mut reply: ReplyDirectory) {
@@ -215,9 +200,9 @@ impl<D: Disk> Filesystem for Fuse<D> {
                 if offset == 0 {
                     skip = 0;
                     i = 0;
-                    reply.add(parent_block - self.fs.header.0, i, FileType::Directory, ".");
+                    reply.add(parent_block - self.fs.header().0, i, FileType::Directory, ".");
                     i += 1;
-                    reply.add(parent_block - self.fs.header.0, i, FileType::Directory, "..");
+                    reply.add(parent_block - self.fs.header().0, i, FileType::Directory, "..");
                     i += 1;
                 } else {
                     i = offset + 1;
@@ -225,7 +210,7 @@ impl<D: Disk> Filesystem for Fuse<D> {
                 }
 
                 for child in children.iter().skip(skip) {
-                    let full = reply.add(child.0 - self.fs.header.0, i, if child.1.is_dir() {
+                    let full = reply.add(child.0 - self.fs.header().0, i, if child.1.is_dir() {
                         FileType::Directory
                     } else {
                         FileType::RegularFile
@@ -240,7 +225,7 @@ impl<D: Disk> Filesystem for Fuse<D> {
                 reply.ok();
             },
             Err(err) => {
-                reply.error(err.errno as i32);
+                reply.error(err.errno);
             }
         }
     }
@@ -253,7 +238,7 @@ impl<D: Disk> Filesystem for Fuse<D> {
                 reply.created(&TTL, &node_attr(&node), 0, 0, flags);
             },
             Err(error) => {
-                reply.error(error.errno as i32);
+                reply.error(error.errno);
             }
         }
     }
@@ -266,7 +251,7 @@ impl<D: Disk> Filesystem for Fuse<D> {
                 reply.entry(&TTL, &node_attr(&node), 0);
             },
             Err(error) => {
-                reply.error(error.errno as i32);
+                reply.error(error.errno);
             }
         }
     }
@@ -277,7 +262,7 @@ impl<D: Disk> Filesystem for Fuse<D> {
                 reply.ok();
             },
             Err(err) => {
-                reply.error(err.errno as i32);
+                reply.error(err.errno);
             }
         }
     }
@@ -288,22 +273,22 @@ impl<D: Disk> Filesystem for Fuse<D> {
                 reply.ok();
             },
             Err(err) => {
-                reply.error(err.errno as i32);
+                reply.error(err.errno);
             }
         }
     }
 
     fn statfs(&mut self, _req: &Request, _ino: u64, reply: ReplyStatfs) {
-        let free = self.fs.header.1.free;
+        let free = self.fs.header().1.free;
         match self.fs.node_len(free) {
             Ok(free_size) => {
-                let bsize = 512;
-                let blocks = self.fs.header.1.size/bsize;
+                let bsize = self.fs.block_size();
+                let blocks = self.fs.header().1.size/bsize;
                 let bfree = free_size/bsize;
                 reply.statfs(blocks, bfree, bfree, 0, 0, bsize as u32, 256, 0);
             },
             Err(err) => {
-                reply.error(err.errno as i32);
+                reply.error(err.errno);
             }
         }
     }
@@ -318,12 +303,12 @@ impl<D: Disk> Filesystem for Fuse<D> {
                         reply.entry(&TTL, &node_attr(&node), 0);
                     },
                     Err(err) => {
-                        reply.error(err.errno as i32);
+                        reply.error(err.errno);
                     }
                 }
             },
             Err(error) => {
-                reply.error(error.errno as i32);
+                reply.error(error.errno);
             }
         }
     }
@@ -335,7 +320,7 @@ impl<D: Disk> Filesystem for Fuse<D> {
                 reply.data(&data[..count]);
             },
             Err(err) => {
-                reply.error(err.errno as i32);
+                reply.error(err.errno);
             }
         }
     }
@@ -4,6 +4,8 @@ use syscall::{Packet, Scheme};
 use std::fs::File;
 use std::io::{self, Read, Write};
 use std::path::Path;
+use std::sync::Arc;
+use std::thread;
 
 use disk::Disk;
 use filesystem::FileSystem;
@@ -13,17 +15,30 @@ use self::scheme::FileScheme;
 pub mod resource;
 pub mod scheme;
 
-pub fn mount<D: Disk, P: AsRef<Path>, F: FnMut()>(filesystem: FileSystem<D>, mountpoint: &P, mut callback: F) -> io::Result<()> {
+/// Threads handling packets, so a request waiting for the disk does not hold up the others
+const WORKERS: usize = 4;
+
+pub fn mount<D: Disk + Send + Sync + 'static, P: AsRef<Path>, F: FnMut()>(filesystem: FileSystem<D>, mountpoint: &P, mut callback: F) -> io::Result<()> {
     let mountpoint = mountpoint.as_ref();
-    let mut socket = File::create(format!(":{}", mountpoint.display()))?;
+    let socket = Arc::new(File::create(format!(":{}", mountpoint.display()))?);
 
     callback();
 
-    let scheme = FileScheme::new(format!("{}", mountpoint.display()), filesystem);
-    loop {
-        let mut packet = Packet::default();
-        socket.read(&mut packet).unwrap();
-        scheme.handle(&mut packet);
-        socket.write(&packet).unwrap();
+    // Each worker reads a packet, handles it and writes the reply, which carries the packet id
+    let scheme = Arc::new(FileScheme::new(format!("{}", mountpoint.display()), filesystem));
+    let workers: Vec<thread::JoinHandle<io::Result<()>>> = (0..WORKERS).map(|_| {
+        let socket = socket.clone();
+        let scheme = scheme.clone();
+        thread::spawn(move || loop {
+            let mut packet = Packet::default();
+            (&*socket).read(&mut packet)?;
+            scheme.handle(&mut packet);
+            (&*socket).write(&packet)?;
+        })
+    }).collect();
+
+    for worker in workers {
+        worker.join().unwrap()?;
     }
+    Ok(())
 }
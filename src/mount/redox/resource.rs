@@ -9,17 +9,18 @@ use syscall::{Stat, SEEK_SET, SEEK_CUR, SEEK_END};
 use disk::Disk;
 use filesystem::FileSystem;
 
-pub trait Resource<D: Disk> {
+/// An open file or directory, used by one worker thread at a time
+pub trait Resource<D: Disk>: Send {
     fn dup(&self) -> Result<Box<Resource<D>>>;
-    fn read(&mut self, buf: &mut [u8], fs: &mut FileSystem<D>) -> Result<usize>;
-    fn write(&mut self, buf: &[u8], fs: &mut FileSystem<D>) -> Result<usize>;
-    fn seek(&mut self, offset: usize, whence: usize, fs: &mut FileSystem<D>) -> Result<usize>;
+    fn read(&mut self, buf: &mut [u8], fs: &FileSystem<D>) -> Result<usize>;
+    fn write(&mut self, buf: &[u8], fs: &FileSystem<D>) -> Result<usize>;
+    fn seek(&mut self, offset: usize, whence: usize, fs: &FileSystem<D>) -> Result<usize>;
     fn fcntl(&mut self, cmd: usize, arg: usize) -> Result<usize>;
     fn path(&self, buf: &mut [u8]) -> Result<usize>;
-    fn stat(&self, _stat: &mut Stat, fs: &mut FileSystem<D>) -> Result<usize>;
-    fn sync(&mut self) -> Result<usize>;
-    fn truncate(&mut self, len: usize, fs: &mut FileSystem<D>) -> Result<usize>;
-    fn utimens(&mut self, times: &[TimeSpec], fs: &mut FileSystem<D>) -> Result<usize>;
+    fn stat(&self, _stat: &mut Stat, fs: &FileSystem<D>) -> Result<usize>;
+    fn sync(&mut self, fs: &FileSystem<D>) -> Result<usize>;
+    fn truncate(&mut self, len: usize, fs: &FileSystem<D>) -> Result<usize>;
+    fn utimens(&mut self, times: &[TimeSpec], fs: &FileSystem<D>) -> Result<usize>;
 }
 
 pub struct DirResource {
@@ -50,7 +51,7 @@ impl<D: Disk> Resource<D> for DirResource {
         }))
     }
 
-    fn read(&mut self, buf: &mut [u8], _fs: &mut FileSystem<D>) -> Result<usize> {
+    fn read(&mut self, buf: &mut [u8], _fs: &FileSystem<D>) -> Result<usize> {
         let data = self.data.as_ref().ok_or(Error::new(EISDIR))?;
         let mut i = 0;
         while i < buf.len() && self.seek < data.len() {
@@ -61,11 +62,11 @@ impl<D: Disk> Resource<D> for DirResource {
         Ok(i)
     }
 
-    fn write(&mut self, _buf: &[u8], _fs: &mut FileSystem<D>) -> Result<usize> {
+    fn write(&mut self, _buf: &[u8], _fs: &FileSystem<D>) -> Result<usize> {
         Err(Error::new(EBADF))
     }
 
-    fn seek(&mut self, offset: usize, whence: usize, _fs: &mut FileSystem<D>) -> Result<usize> {
+    fn seek(&mut self, offset: usize, whence: usize, _fs: &FileSystem<D>) -> Result<usize> {
         let data = self.data.as_ref().ok_or(Error::new(EBADF))?;
         self.seek = match whence {
             SEEK_SET => max(0, min(data.len() as isize, offset as isize)) as usize,
@@ -93,11 +94,11 @@ impl<D: Disk> Resource<D> for DirResource {
         Ok(i)
     }
 
-    fn stat(&self, stat: &mut Stat, fs: &mut FileSystem<D>) -> Result<usize> {
+    fn stat(&self, stat: &mut Stat, fs: &FileSystem<D>) -> Result<usize> {
         let node = fs.node(self.block)?;
 
         *stat = Stat {
-            st_dev: 0, // TODO
+            st_dev: fs.header().1.fsid(),
             st_ino: node.0,
             st_mode: node.1.mode,
             st_nlink: 1,
@@ -114,15 +115,15 @@ impl<D: Disk> Resource<D> for DirResource {
         Ok(0)
     }
 
-    fn sync(&mut self) -> Result<usize> {
+    fn sync(&mut self, _fs: &FileSystem<D>) -> Result<usize> {
         Err(Error::new(EBADF))
     }
 
-    fn truncate(&mut self, _len: usize, _fs: &mut FileSystem<D>) -> Result<usize> {
+    fn truncate(&mut self, _len: usize, _fs: &FileSystem<D>) -> Result<usize> {
         Err(Error::new(EBADF))
     }
 
-    fn utimens(&mut self, _times: &[TimeSpec], _fs: &mut FileSystem<D>) -> Result<usize> {
+    fn utimens(&mut self, _times: &[TimeSpec], _fs: &FileSystem<D>) -> Result<usize> {
         Err(Error::new(EBADF))
     }
 }
@@ -158,7 +159,7 @@ impl<D: Disk> Resource<D> for FileResource {
         }))
     }
 
-    fn read(&mut self, buf: &mut [u8], fs: &mut FileSystem<D>) -> Result<usize> {
+    fn read(&mut self, buf: &mut [u8], fs: &FileSystem<D>) -> Result<usize> {
         if self.flags & O_ACCMODE == O_RDWR || self.flags & O_ACCMODE == O_RDONLY {
             let count = fs.read_node(self.block, self.seek, buf)?;
             self.seek += count as u64;
@@ -168,7 +169,7 @@ impl<D: Disk> Resource<D> for FileResource {
         }
     }
 
-    fn write(&mut self, buf: &[u8], fs: &mut FileSystem<D>) -> Result<usize> {
+    fn write(&mut self, buf: &[u8], fs: &FileSystem<D>) -> Result<usize> {
         if self.flags & O_ACCMODE == O_RDWR || self.flags & O_ACCMODE == O_WRONLY {
             let mtime = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
             let count = fs.write_node(self.block, self.seek, buf, mtime.as_secs(), mtime.subsec_nanos())?;
@@ -179,7 +180,7 @@ impl<D: Disk> Resource<D> for FileResource {
         }
     }
 
-    fn seek(&mut self, offset: usize, whence: usize, fs: &mut FileSystem<D>) -> Result<usize> {
+    fn seek(&mut self, offset: usize, whence: usize, fs: &FileSystem<D>) -> Result<usize> {
         let size = fs.node_len(self.block)?;
 
         self.seek = match whence {
@@ -215,11 +216,11 @@ impl<D: Disk> Resource<D> for FileResource {
         Ok(i)
     }
 
-    fn stat(&self, stat: &mut Stat, fs: &mut FileSystem<D>) -> Result<usize> {
+    fn stat(&self, stat: &mut Stat, fs: &FileSystem<D>) -> Result<usize> {
         let node = fs.node(self.block)?;
 
         *stat = Stat {
-            st_dev: 0, // TODO
+            st_dev: fs.header().1.fsid(),
             st_ino: node.0,
             st_mode: node.1.mode,
             st_nlink: 1,
@@ -236,11 +237,12 @@ impl<D: Disk> Resource<D> for FileResource {
         Ok(0)
     }
 
-    fn sync(&mut self) -> Result<usize> {
+    fn sync(&mut self, fs: &FileSystem<D>) -> Result<usize> {
+        fs.sync()?;
         Ok(0)
     }
 
-    fn truncate(&mut self, len: usize, fs: &mut FileSystem<D>) -> Result<usize> {
+    fn truncate(&mut self, len: usize, fs: &FileSystem<D>) -> Result<usize> {
         if self.flags & O_ACCMODE == O_RDWR || self.flags & O_ACCMODE == O_WRONLY {
             fs.node_set_len(self.block, len as u64)?;
             Ok(0)
@@ -249,23 +251,17 @@ impl<D: Disk> Resource<D> for FileResource {
         }
     }
 
-    fn utimens(&mut self, times: &[TimeSpec], fs: &mut FileSystem<D>) -> Result<usize> {
-        let mut node = fs.node(self.block)?;
-
-        if node.1.uid == self.uid || self.uid == 0 {
+    fn utimens(&mut self, times: &[TimeSpec], fs: &FileSystem<D>) -> Result<usize> {
+        let uid = self.uid;
+        fs.set_node_attrs(self.block, |node| if node.uid == uid || uid == 0 {
             if let Some(mtime) = times.get(1) {
-
-                node.1.mtime = mtime.tv_sec as u64;
-                node.1.mtime_nsec = mtime.tv_nsec as u32;
-
-                fs.write_at(node.0, &node.1)?;
-
-                Ok(0)
-            } else {
-                Ok(0)
+                node.mtime = mtime.tv_sec as u64;
+                node.mtime_nsec = mtime.tv_nsec as u32;
             }
+            Ok(())
         } else {
             Err(Error::new(EBADF))
-        }
+        })?;
+        Ok(0)
     }
 }
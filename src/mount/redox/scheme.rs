@@ -1,11 +1,11 @@
-use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::str;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use syscall::data::{Stat, StatVfs, TimeSpec};
-use syscall::error::{Error, Result, EACCES, EEXIST, EISDIR, ENOTDIR, EPERM, ENOENT, EBADF, ELOOP, EINVAL};
+use syscall::error::{Error, Result, EACCES, EEXIST, EISDIR, ENOTDIR, EPERM, ENOENT, EBADF, ELOOP, EINVAL, EROFS};
 use syscall::flag::{O_APPEND, O_CREAT, O_DIRECTORY, O_STAT, O_EXCL, O_TRUNC, O_ACCMODE, O_RDONLY, O_WRONLY, O_RDWR, MODE_PERM, O_SYMLINK, O_NOFOLLOW};
 use syscall::scheme::Scheme;
 
@@ -18,22 +18,23 @@ use super::spin::Mutex;
 
 pub struct FileScheme<D: Disk> {
     name: String,
-    fs: RefCell<FileSystem<D>>,
+    fs: FileSystem<D>,
     next_id: AtomicUsize,
-    files: Mutex<BTreeMap<usize, Box<Resource<D>>>>
+    /// Open resources, each locked on its own so that different files are used in parallel
+    files: Mutex<BTreeMap<usize, Arc<Mutex<Box<Resource<D>>>>>>
 }
 
 impl<D: Disk> FileScheme<D> {
     pub fn new(name: String, fs: FileSystem<D>) -> FileScheme<D> {
         FileScheme {
             name: name,
-            fs: RefCell::new(fs),
+            fs: fs,
             next_id: AtomicUsize::new(1),
             files: Mutex::new(BTreeMap::new())
         }
     }
 
-    fn resolve_symlink(&self, fs: &mut FileSystem<D>, uid: u32, gid: u32, url: &[u8], node: (u64, Node), nodes: &mut Vec<(u64, Node)>) -> Result<Vec<u8>> {
+    fn resolve_symlink(&self, fs: &FileSystem<D>, uid: u32, gid: u32, url: &[u8], node: (u64, Node), nodes: &mut Vec<(u64, Node)>) -> Result<Vec<u8>> {
         let mut node = node;
         for _ in 1..10 { // XXX What should the limit be?
             let mut buf = [0; 4096];
@@ -60,10 +61,14 @@ impl<D: Disk> FileScheme<D> {
         Err(Error::new(ELOOP))
     }
 
-    fn path_nodes(&self, fs: &mut FileSystem<D>, path: &str, uid: u32, gid: u32, nodes: &mut Vec<(u64, Node)>) -> Result<Option<(u64, Node)>> {
+    fn file(&self, id: usize) -> Result<Arc<Mutex<Box<Resource<D>>>>> {
+        self.files.lock().get(&id).cloned().ok_or(Error::new(EBADF))
+    }
+
+    fn path_nodes(&self, fs: &FileSystem<D>, path: &str, uid: u32, gid: u32, nodes: &mut Vec<(u64, Node)>) -> Result<Option<(u64, Node)>> {
         let mut parts = path.split('/').filter(|part| ! part.is_empty());
         let mut part_opt = None;
-        let mut block = fs.header.1.root;
+        let mut block = fs.header().1.root;
         loop {
             let node_res = match part_opt {
                 None => fs.node(block),
@@ -182,10 +187,10 @@ impl<D: Disk> Scheme for FileScheme<D> {
 
         // println!("Open '{}' {:X}", path, flags);
 
-        let mut fs = self.fs.borrow_mut();
+        let fs = &self.fs;
 
         let mut nodes = Vec::new();
-        let node_opt = self.path_nodes(&mut fs, path, uid, gid, &mut nodes)?;
+        let node_opt = self.path_nodes(fs, path, uid, gid, &mut nodes)?;
         let resource: Box<Resource<D>> = match node_opt {
             Some(node) => if flags & (O_CREAT | O_EXCL) == O_CREAT | O_EXCL {
                 return Err(Error::new(EEXIST));
@@ -218,7 +223,7 @@ impl<D: Disk> Scheme for FileScheme<D> {
                 }
             } else if node.1.is_symlink() && !(flags & O_STAT == O_STAT && flags  & O_NOFOLLOW == O_NOFOLLOW) && flags & O_SYMLINK != O_SYMLINK {
                 let mut resolve_nodes = Vec::new();
-                let resolved = self.resolve_symlink(&mut fs, uid, gid, url, node, &mut resolve_nodes)?;
+                let resolved = self.resolve_symlink(fs, uid, gid, url, node, &mut resolve_nodes)?;
                 drop(fs);
                 return self.open(&resolved, flags, uid, gid);
             } else if !node.1.is_symlink() && flags & O_SYMLINK == O_SYMLINK {
@@ -239,6 +244,10 @@ impl<D: Disk> Scheme for FileScheme<D> {
                     return Err(Error::new(EACCES));
                 }
 
+                if (flags & O_ACCMODE == O_WRONLY || flags & O_ACCMODE == O_RDWR) && fs.read_only {
+                    return Err(Error::new(EROFS));
+                }
+
                 if flags & O_TRUNC == O_TRUNC {
                     if ! node.1.permission(uid, gid, Node::MODE_WRITE) {
                         // println!("file not writable {:o}", node.1.mode);
@@ -280,10 +289,12 @@ impl<D: Disk> Scheme for FileScheme<D> {
                         };
 
                         let ctime = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
-                        let mut node = fs.create_node(mode_type | (flags as u16 & Node::MODE_PERM), &last_part, parent.0, ctime.as_secs(), ctime.subsec_nanos())?;
-                        node.1.uid = uid;
-                        node.1.gid = gid;
-                        fs.write_at(node.0, &node.1)?;
+                        let node = fs.create_node(mode_type | (flags as u16 & Node::MODE_PERM), &last_part, parent.0, ctime.as_secs(), ctime.subsec_nanos())?;
+                        let node = fs.set_node_attrs(node.0, |node| {
+                            node.uid = uid;
+                            node.gid = gid;
+                            Ok(())
+                        })?;
 
                         if dir {
                             Box::new(DirResource::new(path.to_string(), node.0, None))
@@ -308,7 +319,7 @@ impl<D: Disk> Scheme for FileScheme<D> {
         };
 
         let id = self.next_id.fetch_add(1, Ordering::SeqCst);
-        self.files.lock().insert(id, resource);
+        self.files.lock().insert(id, Arc::new(Mutex::new(resource)));
 
         Ok(id)
     }
@@ -318,17 +329,17 @@ impl<D: Disk> Scheme for FileScheme<D> {
 
         // println!("Chmod '{}'", path);
 
-        let mut fs = self.fs.borrow_mut();
+        let fs = &self.fs;
 
         let mut nodes = Vec::new();
-        if let Some(mut node) = self.path_nodes(&mut fs, path, uid, gid, &mut nodes)? {
-            if node.1.uid == uid || uid == 0 {
-                node.1.mode = (node.1.mode & ! MODE_PERM) | (mode & MODE_PERM);
-                fs.write_at(node.0, &node.1)?;
-                Ok(0)
+        if let Some(node) = self.path_nodes(fs, path, uid, gid, &mut nodes)? {
+            fs.set_node_attrs(node.0, |node| if node.uid == uid || uid == 0 {
+                node.mode = (node.mode & ! MODE_PERM) | (mode & MODE_PERM);
+                Ok(())
             } else {
                 Err(Error::new(EPERM))
-            }
+            })?;
+            Ok(0)
         } else {
             Err(Error::new(ENOENT))
         }
@@ -339,10 +350,10 @@ impl<D: Disk> Scheme for FileScheme<D> {
 
         // println!("Rmdir '{}'", path);
 
-        let mut fs = self.fs.borrow_mut();
+        let fs = &self.fs;
 
         let mut nodes = Vec::new();
-        if let Some(child) = self.path_nodes(&mut fs, path, uid, gid, &mut nodes)? {
+        if let Some(child) = self.path_nodes(fs, path, uid, gid, &mut nodes)? {
             if let Some(parent) = nodes.last() {
                 if ! parent.1.permission(uid, gid, Node::MODE_WRITE) {
                     // println!("dir not writable {:o}", parent.1.mode);
@@ -376,10 +387,10 @@ impl<D: Disk> Scheme for FileScheme<D> {
 
         // println!("Unlink '{}'", path);
 
-        let mut fs = self.fs.borrow_mut();
+        let fs = &self.fs;
 
         let mut nodes = Vec::new();
-        if let Some(child) = self.path_nodes(&mut fs, path, uid, gid, &mut nodes)? {
+        if let Some(child) = self.path_nodes(fs, path, uid, gid, &mut nodes)? {
             if let Some(parent) = nodes.last() {
                 if ! parent.1.permission(uid, gid, Node::MODE_WRITE) {
                     // println!("dir not writable {:o}", parent.1.mode);
@@ -421,15 +432,10 @@ impl<D: Disk> Scheme for FileScheme<D> {
             return Err(Error::new(EINVAL));
         }
 
-        let mut files = self.files.lock();
-        let resource = if let Some(old_resource) = files.get(&old_id) {
-            old_resource.dup()?
-        } else {
-            return Err(Error::new(EBADF));
-        };
+        let resource = self.file(old_id)?.lock().dup()?;
 
         let id = self.next_id.fetch_add(1, Ordering::SeqCst);
-        files.insert(id, resource);
+        self.files.lock().insert(id, Arc::new(Mutex::new(resource)));
 
         Ok(id)
     }
@@ -437,41 +443,29 @@ impl<D: Disk> Scheme for FileScheme<D> {
     #[allow(unused_variables)]
     fn read(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
         // println!("Read {}, {:X} {}", id, buf.as_ptr() as usize, buf.len());
-        let mut files = self.files.lock();
-        if let Some(file) = files.get_mut(&id) {
-            file.read(buf, &mut self.fs.borrow_mut())
-        } else {
-            Err(Error::new(EBADF))
-        }
+        let file = self.file(id)?;
+        let mut file = file.lock();
+        file.read(buf, &self.fs)
     }
 
     fn write(&self, id: usize, buf: &[u8]) -> Result<usize> {
         // println!("Write {}, {:X} {}", id, buf.as_ptr() as usize, buf.len());
-        let mut files = self.files.lock();
-        if let Some(file) = files.get_mut(&id) {
-            file.write(buf, &mut self.fs.borrow_mut())
-        } else {
-            Err(Error::new(EBADF))
-        }
+        let file = self.file(id)?;
+        let mut file = file.lock();
+        file.write(buf, &self.fs)
     }
 
     fn seek(&self, id: usize, pos: usize, whence: usize) -> Result<usize> {
         // println!("Seek {}, {} {}", id, pos, whence);
-        let mut files = self.files.lock();
-        if let Some(file) = files.get_mut(&id) {
-            file.seek(pos, whence, &mut self.fs.borrow_mut())
-        } else {
-            Err(Error::new(EBADF))
-        }
+        let file = self.file(id)?;
+        let mut file = file.lock();
+        file.seek(pos, whence, &self.fs)
     }
 
     fn fcntl(&self, id: usize, cmd: usize, arg: usize) -> Result<usize> {
-        let mut files = self.files.lock();
-        if let Some(file) = files.get_mut(&id) {
-            file.fcntl(cmd, arg)
-        } else {
-            Err(Error::new(EBADF))
-        }
+        let file = self.file(id)?;
+        let mut file = file.lock();
+        file.fcntl(cmd, arg)
     }
 
     fn fpath(&self, id: usize, buf: &mut [u8]) -> Result<usize> {
@@ -494,7 +488,7 @@ impl<D: Disk> Scheme for FileScheme<D> {
                 i += 1;
             }
 
-            file.path(&mut buf[i..]).map(|count| i + count)
+            file.lock().path(&mut buf[i..]).map(|count| i + count)
         } else {
             Err(Error::new(EBADF))
         }
@@ -502,24 +496,21 @@ impl<D: Disk> Scheme for FileScheme<D> {
 
     fn fstat(&self, id: usize, stat: &mut Stat) -> Result<usize> {
         // println!("Fstat {}, {:X}", id, stat as *mut Stat as usize);
-        let files = self.files.lock();
-        if let Some(file) = files.get(&id) {
-            file.stat(stat, &mut self.fs.borrow_mut())
-        } else {
-            Err(Error::new(EBADF))
-        }
+        let file = self.file(id)?;
+        let file = file.lock();
+        file.stat(stat, &self.fs)
     }
 
     fn fstatvfs(&self, id: usize, stat: &mut StatVfs) -> Result<usize> {
         let files = self.files.lock();
         if let Some(_file) = files.get(&id) {
-            let mut fs = self.fs.borrow_mut();
+            let fs = &self.fs;
 
-            let free = fs.header.1.free;
+            let free = fs.header().1.free;
             let free_size = fs.node_len(free)?;
 
-            stat.f_bsize = 512;
-            stat.f_blocks = fs.header.1.size/(stat.f_bsize as u64);
+            stat.f_bsize = fs.block_size() as u32;
+            stat.f_blocks = fs.header().1.size/(stat.f_bsize as u64);
             stat.f_bfree = free_size/(stat.f_bsize as u64);
             stat.f_bavail = stat.f_bfree;
 
@@ -531,32 +522,23 @@ impl<D: Disk> Scheme for FileScheme<D> {
 
     fn fsync(&self, id: usize) -> Result<usize> {
         // println!("Fsync {}", id);
-        let mut files = self.files.lock();
-        if let Some(file) = files.get_mut(&id) {
-            file.sync()
-        } else {
-            Err(Error::new(EBADF))
-        }
+        let file = self.file(id)?;
+        let mut file = file.lock();
+        file.sync(&self.fs)
     }
 
     fn ftruncate(&self, id: usize, len: usize) -> Result<usize> {
         // println!("Ftruncate {}, {}", id, len);
-        let mut files = self.files.lock();
-        if let Some(file) = files.get_mut(&id) {
-            file.truncate(len, &mut self.fs.borrow_mut())
-        } else {
-            Err(Error::new(EBADF))
-        }
+        let file = self.file(id)?;
+        let mut file = file.lock();
+        file.truncate(len, &self.fs)
     }
 
     fn futimens(&self, id: usize, times: &[TimeSpec]) -> Result<usize> {
         // println!("Futimens {}, {}", id, times.len());
-        let mut files = self.files.lock();
-        if let Some(file) = files.get_mut(&id) {
-            file.utimens(times, &mut self.fs.borrow_mut())
-        } else {
-            Err(Error::new(EBADF))
-        }
+        let file = self.file(id)?;
+        let mut file = file.lock();
+        file.utimens(times, &self.fs)
     }
 
     fn close(&self, id: usize) -> Result<usize> {
@@ -0,0 +1,314 @@
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use syscall::error::{Error, Result, EINVAL, EIO};
+
+use disk::{Disk, io_error};
+use filesystem::FileSystem;
+
+const SPARSE_MAGIC: u32 = 0xed26ff3a;
+const FILE_HEADER_SIZE: usize = 28;
+const CHUNK_HEADER_SIZE: usize = 12;
+
+const CHUNK_RAW: u16 = 0xCAC1;
+const CHUNK_FILL: u16 = 0xCAC2;
+const CHUNK_DONT_CARE: u16 = 0xCAC3;
+const CHUNK_CRC32: u16 = 0xCAC4;
+
+fn read_le(buf: &[u8]) -> u64 {
+    buf.iter().rev().fold(0, |value, &b| value << 8 | b as u64)
+}
+
+fn write_le(buf: &mut [u8], value: u64) {
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = (value >> (i * 8)) as u8;
+    }
+}
+
+/// Counts of the chunks and blocks of a sparse image
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SparseStats {
+    pub chunks: u32,
+    /// Blocks stored as data
+    pub raw: u64,
+    /// Blocks stored as a repeated 32-bit value
+    pub fill: u64,
+    /// Blocks not stored at all
+    pub dont_care: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Chunk {
+    Raw,
+    Fill(u32),
+    DontCare,
+}
+
+/// Read block `block` of `block_size` bytes, with zeros past the end of the disk
+fn read_block<D: Disk>(disk: &D, block: u64, buffer: &mut [u8]) -> Result<()> {
+    let count = disk.read_at(block * (buffer.len() as u64/512), buffer)?;
+    for b in buffer[count..].iter_mut() {
+        *b = 0;
+    }
+    Ok(())
+}
+
+fn write_chunk_header<W: Write>(writer: &mut W, kind: u16, blocks: u64, total: u64) -> io::Result<()> {
+    let mut header = [0; CHUNK_HEADER_SIZE];
+    write_le(&mut header[0..2], kind as u64);
+    write_le(&mut header[4..8], blocks);
+    write_le(&mut header[8..12], total);
+    writer.write_all(&header)
+}
+
+/// Write the disk of a file system as an Android sparse image, with blocks of `block_size` bytes
+///
+/// Blocks entirely inside the free list are left out as don't care, blocks repeating one 32-bit
+/// value are stored as fills, and everything else as raw data. If the disk is not a whole number
+/// of blocks, the last block is padded with zeros.
+pub fn write_sparse<D: Disk, W: Write>(fs: &FileSystem<D>, writer: &mut W, block_size: u32) -> Result<SparseStats> {
+    if block_size == 0 || block_size % 512 != 0 {
+        return Err(Error::new(EINVAL));
+    }
+
+    let block_size = block_size as u64;
+    let sectors = block_size/512;
+    let blocks = (fs.disk.size()? + block_size - 1)/block_size;
+    if blocks > u32::max_value() as u64 {
+        return Err(Error::new(EINVAL));
+    }
+
+    let mut free = vec![false; blocks as usize];
+    for extent in fs.free_extents()? {
+        // Free extents are relative to the file system, and may end partway into a block
+        let start = fs.block + extent.block * (fs.block_size()/512);
+        let end = start + extent.length/512;
+        let first = (start + sectors - 1)/sectors;
+        let last = end/sectors;
+        for block in first..last {
+            if let Some(free) = free.get_mut(block as usize) {
+                *free = true;
+            }
+        }
+    }
+
+    // Raw chunks are limited by their total size being a 32-bit value
+    let max_raw = (u32::max_value() as u64 - CHUNK_HEADER_SIZE as u64)/block_size;
+
+    let mut chunks: Vec<(Chunk, u64, u64)> = Vec::new();
+    let mut buffer = vec![0; block_size as usize];
+    for block in 0..blocks {
+        let chunk = if free[block as usize] {
+            Chunk::DontCare
+        } else {
+            read_block(&fs.disk, block, &mut buffer)?;
+            let value = read_le(&buffer[..4]);
+            if buffer.chunks(4).all(|word| read_le(word) == value) {
+                Chunk::Fill(value as u32)
+            } else {
+                Chunk::Raw
+            }
+        };
+
+        if let Some(last) = chunks.last_mut() {
+            if last.0 == chunk && (chunk != Chunk::Raw || last.2 < max_raw) {
+                last.2 += 1;
+                continue;
+            }
+        }
+        chunks.push((chunk, block, 1));
+    }
+
+    let mut header = [0; FILE_HEADER_SIZE];
+    write_le(&mut header[0..4], SPARSE_MAGIC as u64);
+    write_le(&mut header[4..6], 1);
+    write_le(&mut header[8..10], FILE_HEADER_SIZE as u64);
+    write_le(&mut header[10..12], CHUNK_HEADER_SIZE as u64);
+    write_le(&mut header[12..16], block_size);
+    write_le(&mut header[16..20], blocks);
+    write_le(&mut header[20..24], chunks.len() as u64);
+    writer.write_all(&header).map_err(io_error)?;
+
+    let mut stats = SparseStats {
+        chunks: chunks.len() as u32,
+        ..SparseStats::default()
+    };
+    for &(chunk, start, count) in chunks.iter() {
+        match chunk {
+            Chunk::Raw => {
+                write_chunk_header(writer, CHUNK_RAW, count, CHUNK_HEADER_SIZE as u64 + count * block_size).map_err(io_error)?;
+                for block in start..start + count {
+                    read_block(&fs.disk, block, &mut buffer)?;
+                    writer.write_all(&buffer).map_err(io_error)?;
+                }
+                stats.raw += count;
+            },
+            Chunk::Fill(value) => {
+                write_chunk_header(writer, CHUNK_FILL, count, CHUNK_HEADER_SIZE as u64 + 4).map_err(io_error)?;
+                let mut fill = [0; 4];
+                write_le(&mut fill, value as u64);
+                writer.write_all(&fill).map_err(io_error)?;
+                stats.fill += count;
+            },
+            Chunk::DontCare => {
+                write_chunk_header(writer, CHUNK_DONT_CARE, count, CHUNK_HEADER_SIZE as u64).map_err(io_error)?;
+                stats.dont_care += count;
+            }
+        }
+    }
+
+    Ok(stats)
+}
+
+/// Reads an Android sparse image
+pub struct SparseReader<R: Read> {
+    reader: R,
+    chunk_header_size: usize,
+    /// Size of a block, in bytes
+    pub block_size: u32,
+    /// Number of blocks in the expanded image
+    pub blocks: u32,
+    /// Number of chunks in the sparse image
+    pub chunks: u32,
+}
+
+impl SparseReader<BufReader<File>> {
+    pub fn open(path: &str) -> Result<Self> {
+        let file = File::open(path).map_err(io_error)?;
+        SparseReader::new(BufReader::new(file))
+    }
+}
+
+impl<R: Read> SparseReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut header = [0; FILE_HEADER_SIZE];
+        reader.read_exact(&mut header).map_err(io_error)?;
+
+        let magic = read_le(&header[0..4]) as u32;
+        let major_version = read_le(&header[4..6]);
+        let file_header_size = read_le(&header[8..10]) as usize;
+        let chunk_header_size = read_le(&header[10..12]) as usize;
+        let block_size = read_le(&header[12..16]) as u32;
+        if magic != SPARSE_MAGIC || major_version != 1
+            || file_header_size < FILE_HEADER_SIZE || chunk_header_size < CHUNK_HEADER_SIZE
+            || block_size == 0 || block_size % 512 != 0 {
+            return Err(Error::new(EINVAL));
+        }
+
+        // Later versions may have larger headers
+        let mut extra = vec![0; file_header_size - FILE_HEADER_SIZE];
+        reader.read_exact(&mut extra).map_err(io_error)?;
+
+        Ok(SparseReader {
+            reader: reader,
+            chunk_header_size: chunk_header_size,
+            block_size: block_size,
+            blocks: read_le(&header[16..20]) as u32,
+            chunks: read_le(&header[20..24]) as u32,
+        })
+    }
+
+    /// Size of the expanded image, in bytes
+    pub fn size(&self) -> u64 {
+        self.blocks as u64 * self.block_size as u64
+    }
+
+    /// Write the expanded image to a disk, leaving don't care blocks untouched
+    pub fn expand<D: Disk>(&mut self, disk: &D) -> Result<SparseStats> {
+        let block_size = self.block_size as u64;
+        let sectors = block_size/512;
+        let mut buffer = vec![0; block_size as usize];
+        let mut header = vec![0; self.chunk_header_size];
+
+        let mut stats = SparseStats::default();
+        let mut block = 0;
+        for _ in 0..self.chunks {
+            self.reader.read_exact(&mut header).map_err(io_error)?;
+            let kind = read_le(&header[0..2]) as u16;
+            let count = read_le(&header[4..8]);
+            let total = read_le(&header[8..12]);
+
+            if block + count > self.blocks as u64 {
+                return Err(Error::new(EINVAL));
+            }
+
+            let data = total.checked_sub(self.chunk_header_size as u64).ok_or(Error::new(EINVAL))?;
+            match kind {
+                CHUNK_RAW if data == count * block_size => {
+                    for i in block..block + count {
+                        self.reader.read_exact(&mut buffer).map_err(io_error)?;
+                        if disk.write_at(i * sectors, &buffer)? != buffer.len() {
+                            return Err(Error::new(EIO));
+                        }
+                    }
+                    stats.raw += count;
+                },
+                CHUNK_FILL if data == 4 => {
+                    let mut fill = [0; 4];
+                    self.reader.read_exact(&mut fill).map_err(io_error)?;
+                    for word in buffer.chunks_mut(4) {
+                        word.copy_from_slice(&fill);
+                    }
+                    for i in block..block + count {
+                        if disk.write_at(i * sectors, &buffer)? != buffer.len() {
+                            return Err(Error::new(EIO));
+                        }
+                    }
+                    stats.fill += count;
+                },
+                CHUNK_DONT_CARE if data == 0 => {
+                    stats.dont_care += count;
+                },
+                CHUNK_CRC32 if data == 4 && count == 0 => {
+                    let mut crc = [0; 4];
+                    self.reader.read_exact(&mut crc).map_err(io_error)?;
+                },
+                _ => return Err(Error::new(EINVAL))
+            }
+
+            block += count;
+            stats.chunks += 1;
+        }
+
+        if block != self.blocks as u64 {
+            return Err(Error::new(EINVAL));
+        }
+
+        Ok(stats)
+    }
+}
+
+#[test]
+fn sparse_test() {
+    use disk::DiskMemory;
+    use node::Node;
+
+    let fs = FileSystem::create(DiskMemory::new(1024 * 1024), 0, 0).unwrap();
+    let root = fs.header().1.root;
+    let node = fs.create_node(Node::MODE_FILE | 0o644, "test", root, 0, 0).unwrap();
+    let data: Vec<u8> = (0..20000).map(|i| (i % 251) as u8).collect();
+    fs.write_node(node.0, 0, &data, 0, 0).unwrap();
+    let fill = fs.create_node(Node::MODE_FILE | 0o644, "fill", root, 0, 0).unwrap();
+    fs.write_node(fill.0, 0, &[0xAA; 8192], 0, 0).unwrap();
+
+    let mut simg = Vec::new();
+    let stats = write_sparse(&fs, &mut simg, 4096).unwrap();
+    assert!(stats.raw > 0 && stats.fill > 0 && stats.dont_care > 0);
+    assert_eq!(stats.raw + stats.fill + stats.dont_care, 256);
+    assert!(simg.len() < 1024 * 1024/2);
+
+    let mut reader = SparseReader::new(&simg[..]).unwrap();
+    assert_eq!(reader.size(), 1024 * 1024);
+    let disk = DiskMemory::new(reader.size());
+    let expanded = reader.expand(&disk).unwrap();
+    assert_eq!(expanded.chunks, stats.chunks);
+
+    let fs = FileSystem::open(disk).unwrap();
+    let node = fs.find_node("test", root).unwrap();
+    let mut buf = vec![0; data.len()];
+    fs.read_node(node.0, 0, &mut buf).unwrap();
+    assert!(buf == data);
+    let fill = fs.find_node("fill", root).unwrap();
+    let mut buf = [0; 8192];
+    fs.read_node(fill.0, 0, &mut buf).unwrap();
+    assert!(buf.iter().all(|&b| b == 0xAA));
+}
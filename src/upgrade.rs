@@ -0,0 +1,276 @@
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+use syscall::error::{Error, Result, EINVAL, EIO, ENAMETOOLONG, ENOENT, EOPNOTSUPP};
+
+use disk::{Disk, io_error};
+use header::Header;
+use node::Node;
+
+const BACKUP_SIGNATURE: &'static [u8; 8] = b"RFSUPBAK";
+
+fn read_le(buf: &[u8]) -> u64 {
+    buf.iter().rev().fold(0, |value, &b| value << 8 | b as u64)
+}
+
+fn write_le(buf: &mut [u8], value: u64) {
+    for (i, b) in buf.iter_mut().enumerate() {
+        *b = (value >> (i * 8)) as u8;
+    }
+}
+
+/// A metadata block rewritten by an upgrade
+pub struct UpgradeBlock {
+    /// Sector of the disk
+    pub block: u64,
+    pub old: Vec<u8>,
+    pub new: Vec<u8>,
+}
+
+/// The changes needed to bring a filesystem to the current version
+///
+/// Nothing is written until `apply` is called, so an upgrade can be planned to see what it would
+/// change, and the old contents of every block saved with `write_backup` first.
+pub struct Upgrade {
+    /// Version found on the disk
+    pub from: u64,
+    pub to: u64,
+    /// Blocks to rewrite, the header last
+    pub blocks: Vec<UpgradeBlock>,
+}
+
+impl Upgrade {
+    pub fn apply<D: Disk>(&self, disk: &D) -> Result<()> {
+        for block in self.blocks.iter() {
+            if disk.write_at(block.block, &block.new)? != block.new.len() {
+                return Err(Error::new(EIO));
+            }
+        }
+        disk.sync()
+    }
+
+    /// Save the old contents of the blocks, to be restored with `restore_backup`
+    pub fn write_backup<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(BACKUP_SIGNATURE).map_err(io_error)?;
+        for block in self.blocks.iter() {
+            let mut number = [0; 8];
+            write_le(&mut number, block.block);
+            writer.write_all(&number).and_then(|_| writer.write_all(&block.old)).map_err(io_error)?;
+        }
+        writer.flush().map_err(io_error)
+    }
+}
+
+/// Write back the blocks saved by `Upgrade::write_backup`, returning how many there were
+pub fn restore_backup<D: Disk, R: Read>(disk: &D, reader: &mut R) -> Result<usize> {
+    let mut data = Vec::new();
+    reader.read_to_end(&mut data).map_err(io_error)?;
+    if data.len() < 8 || &data[..8] != BACKUP_SIGNATURE || (data.len() - 8) % (8 + 512) != 0 {
+        return Err(Error::new(EINVAL));
+    }
+
+    let mut count = 0;
+    for record in data[8..].chunks(8 + 512) {
+        if disk.write_at(read_le(&record[..8]), &record[8..])? != 512 {
+            return Err(Error::new(EIO));
+        }
+        count += 1;
+    }
+    disk.sync()?;
+    Ok(count)
+}
+
+/// Find the header of a filesystem of any version, returning its sector and contents
+fn find_header<D: Disk>(disk: &D) -> Result<(u64, Header)> {
+    for block in 0..65536 {
+        let mut header = Header::default();
+        if disk.read_at(block, &mut header)? < header.len() {
+            break;
+        }
+
+        if &header.signature == Header::SIGNATURE {
+            return Ok((block, header));
+        }
+    }
+
+    Err(Error::new(ENOENT))
+}
+
+/// Version of the filesystem on a disk
+pub fn detect_version<D: Disk>(disk: &D) -> Result<u64> {
+    find_header(disk).map(|(_, header)| header.version)
+}
+
+/// Version 1 nodes start with a 256 byte name and a 64-bit mode, instead of the mode, owner,
+/// times and a 222 byte name, and have no parent. The next pointer and extents are unchanged.
+struct UpgradeV1<'a, D: 'a + Disk> {
+    disk: &'a D,
+    start: u64,
+    ctime: u64,
+    ctime_nsec: u32,
+    visited: BTreeSet<u64>,
+    blocks: Vec<UpgradeBlock>,
+}
+
+impl<'a, D: Disk> UpgradeV1<'a, D> {
+    /// Convert the node at `block`, the nodes continuing it, and for directories their children
+    fn node(&mut self, block: u64, parent: u64, dir: bool) -> Result<()> {
+        if block == 0 || ! self.visited.insert(block) {
+            return Ok(());
+        }
+
+        let mut old = vec![0; 512];
+        if self.disk.read_at(self.start + block, &mut old)? != old.len() {
+            return Err(Error::new(EIO));
+        }
+
+        let name_len = old[..256].iter().position(|&b| b == 0).unwrap_or(256);
+        let mut node = Node::default();
+        if name_len > node.name.len() {
+            eprintln!("redoxfs: name of node {} is longer than {} bytes", block, node.name.len());
+            return Err(Error::new(ENAMETOOLONG));
+        }
+
+        // Continuation nodes have no name or mode, and keep zero times
+        let mode = read_le(&old[256..264]) as u16;
+        if mode != 0 {
+            node.mode = mode;
+            node.name[..name_len].copy_from_slice(&old[..name_len]);
+            node.parent = parent;
+            node.ctime = self.ctime;
+            node.ctime_nsec = self.ctime_nsec;
+            node.mtime = self.ctime;
+            node.mtime_nsec = self.ctime_nsec;
+        }
+        node[264..].copy_from_slice(&old[264..]);
+
+        let children = dir || node.is_dir();
+        let head = if mode != 0 { block } else { parent };
+        let next = node.next;
+        let extents = node.extents;
+        self.blocks.push(UpgradeBlock {
+            block: self.start + block,
+            old: old,
+            new: node.to_vec()
+        });
+
+        if children {
+            for extent in extents.iter() {
+                for (child, size) in extent.blocks(512) {
+                    if size >= 512 {
+                        self.node(child, head, false)?;
+                    }
+                }
+            }
+        }
+
+        self.node(next, head, children)
+    }
+}
+
+/// Plan the upgrade of the filesystem on a disk to the current version
+///
+/// `ctime` is used as the creation and modification time of nodes that did not record one.
+pub fn plan_upgrade<D: Disk>(disk: &D, ctime: u64, ctime_nsec: u32) -> Result<Upgrade> {
+    let (start, header) = find_header(disk)?;
+    let from = header.version;
+    let checksum = header.checksum;
+
+    let mut upgrade = Upgrade {
+        from: from,
+        to: Header::VERSION,
+        blocks: Vec::new()
+    };
+
+    if from == 1 {
+        let (root, free) = (header.root, header.free);
+        let blocks = {
+            let mut v1 = UpgradeV1 {
+                disk: disk,
+                start: start,
+                ctime: ctime,
+                ctime_nsec: ctime_nsec,
+                visited: BTreeSet::new(),
+                blocks: Vec::new()
+            };
+            v1.node(root, 0, true)?;
+            v1.node(free, 0, false)?;
+            v1.blocks
+        };
+        upgrade.blocks = blocks;
+    } else if from != Header::VERSION {
+        return Err(Error::new(EOPNOTSUPP));
+    } else if checksum != 0 {
+        // Already current
+        return Ok(upgrade);
+    }
+
+    // Older headers end in zeros, which are the defaults of the fields added since
+    let mut new = Header::default();
+    new.copy_from_slice(&header);
+    new.version = Header::VERSION;
+    new.update_checksum();
+    if ! new.valid() {
+        return Err(Error::new(EINVAL));
+    }
+
+    upgrade.blocks.push(UpgradeBlock {
+        block: start,
+        old: header.to_vec(),
+        new: new.to_vec()
+    });
+    Ok(upgrade)
+}
+
+#[test]
+fn upgrade_test() {
+    use disk::DiskMemory;
+    use extent::Extent;
+    use filesystem::FileSystem;
+
+    // Build a version 1 filesystem: a root directory with a file and a subdirectory
+    fn node_v1(name: &str, mode: u64, next: u64, extents: &[Extent]) -> Vec<u8> {
+        let mut data = vec![0; 512];
+        data[..name.len()].copy_from_slice(name.as_bytes());
+        write_le(&mut data[256..264], mode);
+        write_le(&mut data[264..272], next);
+        for (i, extent) in extents.iter().enumerate() {
+            write_le(&mut data[272 + i * 16..280 + i * 16], extent.block);
+            write_le(&mut data[280 + i * 16..288 + i * 16], extent.length);
+        }
+        data
+    }
+
+    let disk = DiskMemory::new(1024 * 1024);
+    let mut header = Header::new([1; 16], 1024 * 1024, 512, 1, 2);
+    header.version = 1;
+    disk.write_at(0, &header).unwrap();
+    disk.write_at(1, &node_v1("root", 0x41ed, 0, &[Extent::new(4, 1024)])).unwrap();
+    disk.write_at(2, &node_v1("free", 0x8000, 0, &[Extent::new(8, 1024 * 1024 - 8 * 512)])).unwrap();
+    disk.write_at(4, &node_v1("file", 0x81a4, 0, &[Extent::new(6, 5)])).unwrap();
+    disk.write_at(5, &node_v1("dir", 0x41ed, 0, &[])).unwrap();
+    disk.write_at(6, b"hello").unwrap();
+
+    assert_eq!(detect_version(&disk).unwrap(), 1);
+    assert!(FileSystem::open(&disk).is_err());
+
+    let upgrade = plan_upgrade(&disk, 1, 0).unwrap();
+    assert_eq!(upgrade.blocks.iter().map(|block| block.block).collect::<Vec<u64>>(), vec![1, 4, 5, 2, 0]);
+    let mut backup = Vec::new();
+    upgrade.write_backup(&mut backup).unwrap();
+    upgrade.apply(&disk).unwrap();
+
+    {
+        let fs = FileSystem::open(&disk).unwrap();
+        let file = fs.find_node("file", 1).unwrap();
+        assert!(file.1.parent == 1);
+        assert!(file.1.is_file());
+        let mut data = [0; 5];
+        fs.read_node(file.0, 0, &mut data).unwrap();
+        assert_eq!(&data, b"hello");
+        assert!(fs.find_node("dir", 1).unwrap().1.is_dir());
+    }
+    assert_eq!(plan_upgrade(&disk, 1, 0).unwrap().blocks.len(), 0);
+
+    assert_eq!(restore_backup(&disk, &mut &backup[..]).unwrap(), 5);
+    assert_eq!(detect_version(&disk).unwrap(), 1);
+}